@@ -0,0 +1,134 @@
+//! Holds thousands of idle keep-alive connections open against a server in
+//! `Mode::Epoll` with a four-worker pool, then sends a request down every
+//! one of them. In `Mode::Threads` each of these connections would hold a
+//! worker while it waited, so only four could be served at a time.
+//!
+//! Run with `cargo bench --bench keep_alive`. A number overrides the
+//! connection count (5000). Client and server share the process, so it needs
+//! twice that many file descriptors; the soft limit is raised to the hard one.
+
+use std::{
+    env,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use web_server::{KeepAlive, Limits, Mode, Response, Router, Server, StatusCode, ThreadPool};
+
+const WORKERS: usize = 4;
+
+#[cfg(target_os = "linux")]
+fn raise_file_limit() {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid rlimit for both calls.
+    unsafe {
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0 {
+            limit.rlim_cur = limit.rlim_max;
+            libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn raise_file_limit() {}
+
+// Sends one request and reads its response, returning the body length.
+fn round_trip(stream: &mut BufReader<TcpStream>) -> io::Result<usize> {
+    stream.get_mut().write_all(b"GET /ping HTTP/1.1\r\n\r\n")?;
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if line == "\r\n" {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length: ") {
+            length = value.trim().parse().unwrap();
+        }
+    }
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body)?;
+    Ok(length)
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let index = ((sorted.len() as f64 - 1.0) * p).round() as usize;
+    sorted[index]
+}
+
+fn main() {
+    // `cargo bench` passes `--bench`; a number overrides the connection count.
+    let connections: usize = env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(5000);
+    raise_file_limit();
+
+    let mut router = Router::new();
+    router.get("/ping", |_, _| {
+        Response::new(StatusCode::Ok).with_body("pong")
+    });
+    let server = Server::new(
+        TcpListener::bind("127.0.0.1:0").unwrap(),
+        router,
+        ThreadPool::new(WORKERS),
+    )
+    .mode(Mode::Epoll)
+    .keep_alive(KeepAlive {
+        idle_timeout: Duration::from_secs(60),
+        max_requests: usize::MAX,
+    })
+    .limits(Limits {
+        max_connections_per_ip: None,
+        ..Limits::default()
+    });
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+
+    println!("{connections} keep-alive connections, {WORKERS} workers, epoll mode");
+
+    let start = Instant::now();
+    let mut streams = Vec::with_capacity(connections);
+    for _ in 0..connections {
+        let mut stream = BufReader::new(TcpStream::connect(addr).unwrap());
+        round_trip(&mut stream).unwrap();
+        streams.push(stream);
+    }
+    println!("opened and served all in {:?}", start.elapsed());
+
+    thread::sleep(Duration::from_secs(2));
+
+    // Every connection is still open and answers after sitting idle.
+    let start = Instant::now();
+    let mut latencies = Vec::with_capacity(connections);
+    let mut served = 0;
+    for stream in &mut streams {
+        let sent = Instant::now();
+        if round_trip(stream).is_ok() {
+            served += 1;
+        }
+        latencies.push(sent.elapsed());
+    }
+    let elapsed = start.elapsed();
+    latencies.sort_unstable();
+
+    println!(
+        "after idling: {served}/{connections} served, {:>8.0} req/s   p50 {:>10?}   p99 {:>10?}   max {:>10?}",
+        connections as f64 / elapsed.as_secs_f64(),
+        percentile(&latencies, 0.50),
+        percentile(&latencies, 0.99),
+        percentile(&latencies, 1.0),
+    );
+
+    drop(streams);
+    shutdown.trigger();
+    running.join().unwrap().unwrap();
+}
@@ -0,0 +1,228 @@
+//! Compares the work-stealing `ThreadPool` with the original design, where
+//! every worker waits on one `Arc<Mutex<mpsc::Receiver<Job>>>`.
+//!
+//! Run with `cargo bench --bench pool`. Each scenario reports throughput and
+//! the latency from submitting a job until a worker starts it.
+
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use web_server::ThreadPool;
+
+// The pool from chapter 20 of the book, kept here as the baseline.
+struct LegacyPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    sender: Option<mpsc::Sender<Box<dyn FnOnce() + Send + 'static>>>,
+}
+
+impl LegacyPool {
+    fn new(size: usize) -> LegacyPool {
+        let (sender, receiver) = mpsc::channel::<Box<dyn FnOnce() + Send + 'static>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let message = receiver.lock().unwrap().recv();
+                    match message {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+
+        LegacyPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+}
+
+impl Drop for LegacyPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+trait Executor: Send + Sync + 'static {
+    fn run(&self, job: Box<dyn FnOnce() + Send + 'static>);
+}
+
+impl Executor for ThreadPool {
+    fn run(&self, job: Box<dyn FnOnce() + Send + 'static>) {
+        self.execute(job);
+    }
+}
+
+impl Executor for LegacyPool {
+    fn run(&self, job: Box<dyn FnOnce() + Send + 'static>) {
+        self.sender.as_ref().unwrap().send(job).unwrap();
+    }
+}
+
+// Lets the benchmark thread sleep until the last job is done.
+struct Latch {
+    remaining: AtomicUsize,
+    done: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl Latch {
+    fn new(count: usize) -> Latch {
+        Latch {
+            remaining: AtomicUsize::new(count),
+            done: Mutex::new(false),
+            wake: Condvar::new(),
+        }
+    }
+
+    fn count_down(&self) {
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+            *self.done.lock().unwrap() = true;
+            self.wake.notify_all();
+        }
+    }
+
+    fn wait(&self) {
+        let mut done = self.done.lock().unwrap();
+        while !*done {
+            done = self.wake.wait(done).unwrap();
+        }
+    }
+}
+
+struct Sample {
+    elapsed: Duration,
+    latencies: Vec<u64>,
+}
+
+// A tiny amount of work so scheduling overhead dominates.
+fn spin(iterations: u64) -> u64 {
+    (0..iterations).fold(0u64, |acc, i| acc.wrapping_mul(31).wrapping_add(i))
+}
+
+fn short_jobs<E: Executor>(pool: Arc<E>, jobs: usize) -> Sample {
+    let latch = Arc::new(Latch::new(jobs));
+    let latencies: Arc<Vec<AtomicU64>> = Arc::new((0..jobs).map(|_| AtomicU64::new(0)).collect());
+    let start = Instant::now();
+
+    for i in 0..jobs {
+        let latch = Arc::clone(&latch);
+        let latencies = Arc::clone(&latencies);
+        let submitted = Instant::now();
+        pool.run(Box::new(move || {
+            latencies[i].store(submitted.elapsed().as_nanos() as u64, Ordering::Relaxed);
+            std::hint::black_box(spin(100));
+            latch.count_down();
+        }));
+    }
+    latch.wait();
+
+    Sample {
+        elapsed: start.elapsed(),
+        latencies: latencies
+            .iter()
+            .map(|l| l.load(Ordering::Relaxed))
+            .collect(),
+    }
+}
+
+// Each outer job spawns its children from inside the pool, which is where
+// per-worker deques help the most.
+fn fan_out<E: Executor>(pool: Arc<E>, outer: usize, inner: usize) -> Sample {
+    let jobs = outer * inner;
+    let latch = Arc::new(Latch::new(jobs));
+    let latencies: Arc<Vec<AtomicU64>> = Arc::new((0..jobs).map(|_| AtomicU64::new(0)).collect());
+    let start = Instant::now();
+
+    for o in 0..outer {
+        let spawner = Arc::clone(&pool);
+        let latch = Arc::clone(&latch);
+        let latencies = Arc::clone(&latencies);
+        pool.run(Box::new(move || {
+            for i in 0..inner {
+                let latch = Arc::clone(&latch);
+                let latencies = Arc::clone(&latencies);
+                let submitted = Instant::now();
+                spawner.run(Box::new(move || {
+                    let slot = o * inner + i;
+                    latencies[slot].store(submitted.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                    std::hint::black_box(spin(100));
+                    latch.count_down();
+                }));
+            }
+        }));
+    }
+    latch.wait();
+
+    Sample {
+        elapsed: start.elapsed(),
+        latencies: latencies
+            .iter()
+            .map(|l| l.load(Ordering::Relaxed))
+            .collect(),
+    }
+}
+
+fn percentile(sorted: &[u64], p: f64) -> Duration {
+    let index = ((sorted.len() as f64 - 1.0) * p).round() as usize;
+    Duration::from_nanos(sorted[index])
+}
+
+fn report(name: &str, design: &str, sample: Sample) {
+    let mut latencies = sample.latencies;
+    latencies.sort_unstable();
+    let throughput = latencies.len() as f64 / sample.elapsed.as_secs_f64();
+
+    println!(
+        "{name:<12} {design:<14} {throughput:>12.0} jobs/s   p50 {:>10?}   p99 {:>10?}   p99.9 {:>10?}   max {:>10?}",
+        percentile(&latencies, 0.50),
+        percentile(&latencies, 0.99),
+        percentile(&latencies, 0.999),
+        percentile(&latencies, 1.0),
+    );
+}
+
+fn main() {
+    // `cargo bench` passes `--bench`; a number overrides the worker count.
+    let workers = env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or_else(|| thread::available_parallelism().map_or(4, |n| n.get()));
+    let jobs = 200_000;
+
+    println!("{workers} workers, {jobs} jobs per scenario");
+
+    report(
+        "short-jobs",
+        "mutex-receiver",
+        short_jobs(Arc::new(LegacyPool::new(workers)), jobs),
+    );
+    report(
+        "short-jobs",
+        "work-stealing",
+        short_jobs(Arc::new(ThreadPool::new(workers)), jobs),
+    );
+
+    report(
+        "fan-out",
+        "mutex-receiver",
+        fan_out(Arc::new(LegacyPool::new(workers)), 200, jobs / 200),
+    );
+    report(
+        "fan-out",
+        "work-stealing",
+        fan_out(Arc::new(ThreadPool::new(workers)), 200, jobs / 200),
+    );
+}
@@ -0,0 +1,533 @@
+//! Response compression negotiated from `Accept-Encoding`.
+//!
+//! `Compression` is a middleware that compresses bodies on the fly with
+//! gzip, deflate or brotli, whichever the client ranks highest. Bodies that
+//! are too small to be worth it, or whose type is not on its list, are sent
+//! as they are:
+//!
+//! ```
+//! use web_server::{Compression, Response, Router, StatusCode};
+//!
+//! let mut router = Router::new();
+//! router.middleware(Compression::default()).get("/", |_, _| {
+//!     Response::new(StatusCode::Ok)
+//!         .with_header("Content-Type", "text/plain")
+//!         .with_body("hello ".repeat(1000))
+//! });
+//! ```
+//!
+//! `StaticFiles` uses the same negotiation to serve files compressed ahead of
+//! time, which is where slow, thorough settings such as brotli's highest
+//! quality pay off.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use crate::{Body, HeaderMap, Middleware, Request, Response, StatusCode};
+
+/// Brotli quality for compressing on the fly. Higher levels compress a
+/// little better at many times the cost.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+/// How much of a file is read and compressed at a time.
+const FILE_CHUNK: usize = 64 * 1024;
+
+/// A content coding the server can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Gzip,
+    /// zlib-wrapped deflate, which is what HTTP calls `deflate`.
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    /// The name used in `Accept-Encoding` and `Content-Encoding`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    /// The file extension of a file compressed ahead of time with this
+    /// encoding, if `StaticFiles` looks for one.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Gzip => Some("gz"),
+            Encoding::Brotli => Some("br"),
+            Encoding::Deflate => None,
+        }
+    }
+
+    // Whether an `Accept-Encoding` entry names this encoding.
+    fn matches(&self, coding: &str) -> bool {
+        coding.eq_ignore_ascii_case(self.as_str())
+            || (*self == Encoding::Gzip && coding.eq_ignore_ascii_case("x-gzip"))
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Encoding, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "gzip" => Ok(Encoding::Gzip),
+            "deflate" => Ok(Encoding::Deflate),
+            "br" | "brotli" => Ok(Encoding::Brotli),
+            _ => Err(format!("expected gzip, deflate or br, got {s:?}")),
+        }
+    }
+}
+
+/// Picks the encoding in `offered` that an `Accept-Encoding` header ranks
+/// highest, breaking ties by the order of `offered`.
+///
+/// Returns `None` when the body should be sent unencoded: there is no
+/// header, the client accepts none of `offered`, or it prefers `identity`
+/// to all of them.
+pub fn negotiate(accept_encoding: Option<&str>, offered: &[Encoding]) -> Option<Encoding> {
+    let accepted = parse_accept_encoding(accept_encoding?);
+    let quality = |matches: &dyn Fn(&str) -> bool| {
+        accepted
+            .iter()
+            .find(|(coding, _)| matches(coding))
+            .or_else(|| accepted.iter().find(|(coding, _)| coding == "*"))
+            .map(|(_, q)| *q)
+    };
+
+    // A later candidate only wins with a strictly higher q.
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in offered {
+        let q = quality(&|coding| encoding.matches(coding)).unwrap_or(0.0);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((*encoding, q));
+        }
+    }
+    // Unencoded is always acceptable as a fallback, but only preferred when
+    // the client ranks it above every encoding.
+    let identity = quality(&|coding| coding.eq_ignore_ascii_case("identity")).unwrap_or(0.0);
+    best.filter(|(_, q)| *q >= identity)
+        .map(|(encoding, _)| encoding)
+}
+
+// Splits `gzip;q=0.8, br` into codings and their q-values. Entries with a
+// malformed q-value are ignored.
+fn parse_accept_encoding(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let coding = parts.next()?.trim().to_ascii_lowercase();
+            if coding.is_empty() {
+                return None;
+            }
+            let mut q = 1.0;
+            for param in parts {
+                if let Some((name, value)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        q = value.trim().parse::<f32>().ok()?;
+                    }
+                }
+            }
+            (0.0..=1.0).contains(&q).then_some((coding, q))
+        })
+        .collect()
+}
+
+/// Adds `token` to the response's `Vary` header unless it is already there.
+pub(crate) fn add_vary(headers: &mut HeaderMap, token: &str) {
+    let present = headers
+        .get_all("vary")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|listed| listed == "*" || listed.eq_ignore_ascii_case(token));
+    if !present {
+        headers.append("Vary", token);
+    }
+}
+
+/// Compresses response bodies for clients that accept it.
+///
+/// Responses that already have a `Content-Encoding`, partial content,
+/// `Cache-Control: no-transform` and bodiless statuses pass through
+/// untouched. Compressible responses get `Vary: Accept-Encoding` whether or
+/// not this client's copy was compressed, so caches keep the copies apart.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    /// Encodings to offer, most preferred first. Empty turns compression
+    /// off.
+    pub encodings: Vec<Encoding>,
+    /// Bodies shorter than this are sent as they are. Streams, whose length
+    /// is unknown, are always compressed.
+    pub min_bytes: u64,
+    /// Media types worth compressing, such as `application/json`. An entry
+    /// like `text/*` covers a whole top-level type.
+    pub mime_types: Vec<String>,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression {
+            encodings: vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+            // Below about a packet, the headers cost more than is saved.
+            min_bytes: 1024,
+            mime_types: [
+                "text/*",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "application/wasm",
+                "image/svg+xml",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+impl Compression {
+    fn compressible(&self, response: &Response) -> bool {
+        let Some(content_type) = response.header("content-type") else {
+            return false;
+        };
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        let listed = self
+            .mime_types
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(top) => essence
+                    .split_once('/')
+                    .is_some_and(|(ty, _)| ty.eq_ignore_ascii_case(top)),
+                None => allowed.eq_ignore_ascii_case(&essence),
+            });
+        let no_transform = response
+            .headers
+            .get_all("cache-control")
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+
+        listed
+            && !no_transform
+            && !response.headers.contains("content-encoding")
+            && response.status != StatusCode::PartialContent
+            && !response.status.is_bodiless()
+    }
+}
+
+impl Middleware for Compression {
+    fn after(&self, request: &Request, mut response: Response) -> Response {
+        // The 200 this revalidates may have been compressed, and a 304 has
+        // to carry the same Vary.
+        if response.status == StatusCode::NotModified && !self.encodings.is_empty() {
+            add_vary(&mut response.headers, "Accept-Encoding");
+            return response;
+        }
+        if self.encodings.is_empty() || !self.compressible(&response) {
+            return response;
+        }
+        add_vary(&mut response.headers, "Accept-Encoding");
+
+        let Some(encoding) = negotiate(request.header("accept-encoding"), &self.encodings) else {
+            return response;
+        };
+        if response.body.len().is_some_and(|len| len < self.min_bytes) {
+            return response;
+        }
+
+        let body = match std::mem::take(&mut response.body) {
+            Body::Bytes(bytes) => match encode(encoding, &bytes) {
+                Ok(compressed) if compressed.len() < bytes.len() => Body::Bytes(compressed),
+                // Already-compressed data can grow; send it as it was.
+                _ => {
+                    response.body = Body::Bytes(bytes);
+                    return response;
+                }
+            },
+            Body::File { file, len } => {
+                Body::stream(Encoded::new(encoding, file_chunks(file, len), false))
+            }
+            // A handler streaming events wants each chunk sent as it comes.
+            Body::Stream(chunks) => Body::stream(Encoded::new(encoding, chunks, true)),
+        };
+
+        response.headers.remove("content-length");
+        // Byte ranges would index the unencoded body.
+        response.headers.remove("accept-ranges");
+        // Both copies share a validator, which only a weak one allows.
+        if let Some(etag) = response.header("etag").map(str::to_string) {
+            if !etag.starts_with("W/") {
+                response.headers.insert("ETag", &format!("W/{etag}"));
+            }
+        }
+        response
+            .with_header("Content-Encoding", encoding.as_str())
+            .with_body(body)
+    }
+}
+
+/// Compresses `data` in one go.
+pub fn encode(encoding: Encoding, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = Encoder::new(encoding);
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Encoder {
+        let level = flate2::Compression::default();
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), level)),
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(Vec::new(), level)),
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW_BITS,
+            ))),
+        }
+    }
+
+    // Takes the compressed bytes produced so far.
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(match self {
+            Encoder::Gzip(encoder) => encoder.get_mut(),
+            Encoder::Deflate(encoder) => encoder.get_mut(),
+            Encoder::Brotli(encoder) => encoder.get_mut(),
+        })
+    }
+
+    // Ends the stream, returning the bytes not yet taken.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Deflate(encoder) => encoder.write(buf),
+            Encoder::Brotli(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Deflate(encoder) => encoder.flush(),
+            Encoder::Brotli(encoder) => encoder.flush(),
+        }
+    }
+}
+
+fn file_chunks(file: File, len: u64) -> impl Iterator<Item = io::Result<Vec<u8>>> + Send {
+    let mut file = file.take(len);
+    std::iter::from_fn(move || {
+        let mut chunk = vec![0; FILE_CHUNK];
+        match file.read(&mut chunk) {
+            Ok(0) => None,
+            Ok(read) => {
+                chunk.truncate(read);
+                Some(Ok(chunk))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    })
+}
+
+// Compresses a body chunk by chunk as the server writes it out.
+struct Encoded<I> {
+    chunks: I,
+    encoder: Option<Encoder>,
+    /// Whether each chunk is flushed through instead of waiting for the
+    /// encoder to fill a block.
+    flush: bool,
+}
+
+impl<I> Encoded<I> {
+    fn new(encoding: Encoding, chunks: I, flush: bool) -> Encoded<I> {
+        Encoded {
+            chunks,
+            encoder: Some(Encoder::new(encoding)),
+            flush,
+        }
+    }
+}
+
+impl<I: Iterator<Item = io::Result<Vec<u8>>>> Iterator for Encoded<I> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let encoder = self.encoder.as_mut()?;
+        loop {
+            match self.chunks.next() {
+                Some(Ok(chunk)) => {
+                    let written = encoder.write_all(&chunk).and_then(|_| {
+                        if self.flush {
+                            encoder.flush()
+                        } else {
+                            Ok(())
+                        }
+                    });
+                    if let Err(e) = written {
+                        self.encoder = None;
+                        return Some(Err(e));
+                    }
+                    let compressed = encoder.take();
+                    if !compressed.is_empty() {
+                        return Some(Ok(compressed));
+                    }
+                }
+                Some(Err(e)) => {
+                    self.encoder = None;
+                    return Some(Err(e));
+                }
+                None => return self.encoder.take().map(Encoder::finish),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Chain;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+
+    fn request(accept_encoding: Option<&str>) -> Request {
+        let header = accept_encoding.map_or(String::new(), |value| {
+            format!("Accept-Encoding: {value}\r\n")
+        });
+        let raw = format!("GET / HTTP/1.1\r\n{header}\r\n");
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn text(body: impl Into<Body>) -> Response {
+        Response::new(StatusCode::Ok)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_header("ETag", "\"v1\"")
+            .with_body(body)
+    }
+
+    fn decode(encoding: &str, data: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        match encoding {
+            "gzip" => GzDecoder::new(data).read_to_end(&mut decoded),
+            "deflate" => ZlibDecoder::new(data).read_to_end(&mut decoded),
+            "br" => brotli::Decompressor::new(data, 4096).read_to_end(&mut decoded),
+            other => panic!("unexpected encoding {other}"),
+        }
+        .unwrap();
+        decoded
+    }
+
+    #[test]
+    fn negotiates_by_q_value() {
+        let all = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+        assert_eq!(negotiate(None, &all), None);
+        assert_eq!(negotiate(Some("gzip, br"), &all), Some(Encoding::Brotli));
+        assert_eq!(
+            negotiate(Some("br;q=0.5, gzip;q=0.9"), &all),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate(Some("x-gzip"), &all), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate(Some("*;q=0.3, br;q=0"), &all),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate(Some("gzip;q=0.5, identity"), &all), None);
+        assert_eq!(negotiate(Some("gzip;q=bad"), &all), None);
+        assert_eq!(negotiate(Some("compress"), &all), None);
+        assert_eq!(negotiate(Some("gzip, deflate"), &[Encoding::Brotli]), None);
+    }
+
+    #[test]
+    fn compresses_with_each_encoding() {
+        let body = "hello world ".repeat(200);
+        for (accept, name) in [("br", "br"), ("gzip", "gzip"), ("deflate", "deflate")] {
+            let response = Chain::new()
+                .with(Compression::default())
+                .run(&request(Some(accept)), &|_| text(body.clone()));
+
+            assert_eq!(response.header("content-encoding"), Some(name));
+            assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+            assert_eq!(response.header("etag"), Some("W/\"v1\""));
+            let compressed = response.body.into_bytes().unwrap();
+            assert!(compressed.len() < body.len());
+            assert_eq!(decode(name, &compressed), body.as_bytes());
+        }
+    }
+
+    #[test]
+    fn leaves_small_and_unlisted_bodies_alone() {
+        let chain = Chain::new().with(Compression::default());
+
+        let small = chain.run(&request(Some("gzip")), &|_| text("short"));
+        assert_eq!(small.header("content-encoding"), None);
+        assert_eq!(small.header("vary"), Some("Accept-Encoding"));
+        assert_eq!(small.header("etag"), Some("\"v1\""));
+
+        let png = chain.run(&request(Some("gzip")), &|_| {
+            Response::new(StatusCode::Ok)
+                .with_header("Content-Type", "image/png")
+                .with_body(vec![0; 4096])
+        });
+        assert_eq!(png.header("content-encoding"), None);
+        assert_eq!(png.header("vary"), None);
+
+        let unasked = chain.run(&request(None), &|_| text("x".repeat(4096)));
+        assert_eq!(unasked.header("content-encoding"), None);
+        assert_eq!(unasked.header("vary"), Some("Accept-Encoding"));
+    }
+
+    #[test]
+    fn compresses_streams_chunk_by_chunk() {
+        let response =
+            Chain::new()
+                .with(Compression::default())
+                .run(&request(Some("gzip")), &|_| {
+                    let chunks = ["first ", "second ", "third"].map(|s| Ok(s.as_bytes().to_vec()));
+                    text(Body::stream(chunks))
+                });
+
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        let Body::Stream(chunks) = response.body else {
+            panic!("expected a stream");
+        };
+        let chunks: Vec<_> = chunks.map(Result::unwrap).collect();
+        // Each input chunk is flushed through, then the trailer follows.
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(decode("gzip", &chunks.concat()), b"first second third");
+    }
+}
@@ -0,0 +1,955 @@
+//! Startup settings for the `web-server` binary.
+//!
+//! Settings come from a TOML file, then `WEB_SERVER_*` environment
+//! variables, then command-line flags, each layer overriding the one before.
+//! Every setting has the same name in all three: `pool.max_workers` in the
+//! file is `WEB_SERVER_POOL_MAX_WORKERS` and `--pool-max-workers`.
+//!
+//! ```toml
+//! bind = ["127.0.0.1:7878", "[::1]:7878"]
+//! root = "front-end"
+//!
+//! [pool]
+//! max_workers = 32
+//! queue_capacity = 64
+//! overflow = "reject"
+//!
+//! [log]
+//! level = "debug"
+//!
+//! [proxy]
+//! prefixes = ["/api"]
+//! upstreams = ["127.0.0.1:9000", "127.0.0.1:9001"]
+//! health_check = "/health"
+//!
+//! # Needs the `tls` cargo feature.
+//! [tls]
+//! bind = ["0.0.0.0:8443"]
+//! cert = "cert.pem"
+//! key = "key.pem"
+//! redirect_http = true
+//! ```
+
+use std::{
+    error::Error,
+    fmt, fs, io,
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    logging::Level, Balance, Compression, Encoding, KeepAlive, Limits, LogConfig, LogFormat,
+    LogOutput, Mode, OverflowPolicy, PoolConfig,
+};
+
+const ENV_PREFIX: &str = "WEB_SERVER_";
+
+/// Size at which a log file set with `log.file` is rotated, unless
+/// `log.max_bytes` says otherwise.
+const DEFAULT_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_LOG_KEEP: usize = 5;
+
+/// Every setting, in the order they are applied within one layer.
+const SETTINGS: &[(&str, &str)] = &[
+    ("bind", "addresses to listen on, comma-separated"),
+    ("root", "directory of the front-end files"),
+    (
+        "mode",
+        "threads, or epoll to serve every connection from one thread",
+    ),
+    (
+        "drain_timeout",
+        "how long in-flight requests get at shutdown",
+    ),
+    ("pool.min_workers", "threads kept even when idle"),
+    ("pool.max_workers", "most threads the pool grows to"),
+    (
+        "pool.idle_timeout",
+        "how long an extra thread idles before exiting",
+    ),
+    (
+        "pool.queue_capacity",
+        "requests that may wait, or \"unbounded\"",
+    ),
+    (
+        "pool.overflow",
+        "block, reject, drop-oldest or caller-runs when the queue is full",
+    ),
+    (
+        "keep_alive.timeout",
+        "how long an idle connection stays open",
+    ),
+    ("keep_alive.max_requests", "requests served per connection"),
+    (
+        "limits.header_timeout",
+        "how long a client has to send its request headers",
+    ),
+    (
+        "limits.body_timeout",
+        "how long a client has to send a request body",
+    ),
+    (
+        "limits.write_timeout",
+        "how long a write to a client may block",
+    ),
+    (
+        "limits.max_header_bytes",
+        "largest request line and headers accepted",
+    ),
+    ("limits.max_body_bytes", "largest request body accepted"),
+    (
+        "limits.max_connections_per_ip",
+        "open connections allowed per client, or \"unlimited\"",
+    ),
+    (
+        "limits.max_websockets",
+        "WebSocket connections allowed at once",
+    ),
+    (
+        "compression.encodings",
+        "gzip, deflate and br in order of preference, comma-separated, or \"none\"",
+    ),
+    (
+        "compression.min_bytes",
+        "smallest response body worth compressing",
+    ),
+    (
+        "compression.types",
+        "media types to compress, comma-separated; text/* covers all text",
+    ),
+    (
+        "proxy.prefixes",
+        "route prefixes forwarded to the upstreams, comma-separated",
+    ),
+    (
+        "proxy.upstreams",
+        "addresses of the upstream servers, comma-separated",
+    ),
+    ("proxy.balance", "round-robin or least-busy"),
+    (
+        "proxy.strip_prefix",
+        "remove the prefix before forwarding: true or false",
+    ),
+    (
+        "proxy.health_check",
+        "path each upstream is checked on, or \"none\"",
+    ),
+    ("proxy.health_interval", "how often upstreams are checked"),
+    (
+        "proxy.max_response_bytes",
+        "largest response body taken from an upstream",
+    ),
+    ("log.level", "error, warn, info or debug"),
+    ("log.format", "common or json"),
+    ("log.file", "write logs to this file instead of stdout"),
+    ("log.max_bytes", "rotate the log file at this size"),
+    ("log.keep", "rotated log files to keep"),
+    ("tls.bind", "addresses to serve HTTPS on, comma-separated"),
+    ("tls.cert", "PEM certificate chain, leaf first"),
+    ("tls.key", "PEM private key"),
+    (
+        "tls.redirect_http",
+        "answer plain HTTP with a redirect to HTTPS: true or false",
+    ),
+];
+
+/// Settings whose relative paths, when read from a file, are taken relative
+/// to that file.
+const PATH_SETTINGS: &[&str] = &["root", "log.file", "tls.cert", "tls.key"];
+
+/// Everything `web-server` needs to start.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: Vec<SocketAddr>,
+    pub root: PathBuf,
+    pub mode: Mode,
+    pub pool: PoolConfig,
+    pub keep_alive: KeepAlive,
+    pub limits: Limits,
+    pub compression: Compression,
+    pub drain_timeout: Duration,
+    pub proxy: ProxySettings,
+    pub log: LogConfig,
+    pub tls: TlsSettings,
+}
+
+/// Route prefixes forwarded to a group of upstream servers.
+#[derive(Debug, Clone)]
+pub struct ProxySettings {
+    /// Paths such as `/api`, each forwarded along with everything below it.
+    pub prefixes: Vec<String>,
+    pub upstreams: Vec<SocketAddr>,
+    pub balance: Balance,
+    pub strip_prefix: bool,
+    /// The path upstreams are checked on. Without one every upstream is
+    /// always tried.
+    pub health_check: Option<String>,
+    pub health_interval: Duration,
+    pub max_response_bytes: usize,
+}
+
+impl Default for ProxySettings {
+    fn default() -> ProxySettings {
+        ProxySettings {
+            prefixes: Vec::new(),
+            upstreams: Vec::new(),
+            balance: Balance::default(),
+            strip_prefix: false,
+            health_check: None,
+            health_interval: Duration::from_secs(5),
+            max_response_bytes: Limits::default().max_body_bytes,
+        }
+    }
+}
+
+/// HTTPS listeners, which need the `tls` cargo feature.
+#[derive(Debug, Clone, Default)]
+pub struct TlsSettings {
+    pub bind: Vec<SocketAddr>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Whether the plain `bind` listeners redirect to the first HTTPS
+    /// address instead of serving the site.
+    pub redirect_http: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        // Each keep-alive connection holds a worker while it waits for the
+        // next request, so the pool may grow well past the number of cores.
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        Config {
+            bind: vec![SocketAddr::from(([127, 0, 0, 1], 7878))],
+            root: PathBuf::from("front-end"),
+            mode: Mode::Threads,
+            // A full queue is answered with 503 instead of growing without
+            // limit.
+            pool: PoolConfig {
+                min_workers: 2,
+                max_workers: cores * 8,
+                idle_timeout: Duration::from_secs(30),
+                queue_capacity: Some(64),
+                overflow: OverflowPolicy::Reject,
+            },
+            keep_alive: KeepAlive::default(),
+            // One client may not hold more than a share of the workers, and
+            // bodies are read into memory before a handler sees them.
+            limits: Limits {
+                max_body_bytes: 1024 * 1024,
+                max_connections_per_ip: Some(64),
+                ..Limits::default()
+            },
+            compression: Compression::default(),
+            drain_timeout: Duration::from_secs(10),
+            proxy: ProxySettings::default(),
+            log: LogConfig::default(),
+            tls: TlsSettings::default(),
+        }
+    }
+}
+
+/// Why the configuration could not be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read.
+    Read { path: PathBuf, error: io::Error },
+    /// The config file is not valid TOML.
+    Parse { path: PathBuf, message: String },
+    /// An unknown flag or setting, or a flag without its value.
+    Usage(String),
+    /// A setting whose value cannot be used, such as `--pool-max-workers
+    /// many`. `origin` names where it came from.
+    Value { origin: String, message: String },
+    /// Settings that are each valid but do not work together.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, error } => {
+                write!(f, "cannot read {}: {error}", path.display())
+            }
+            ConfigError::Parse { path, message } => {
+                write!(f, "{} is not valid TOML: {message}", path.display())
+            }
+            ConfigError::Usage(message) => write!(f, "{message} (see --help)"),
+            ConfigError::Value { origin, message } => write!(f, "{origin}: {message}"),
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid configuration: {}", problems.join("; "))
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Read { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// One setting from one layer, waiting to be applied.
+struct Assignment {
+    key: &'static str,
+    value: String,
+    origin: String,
+}
+
+impl Config {
+    /// Loads the config file named by `--config` or `WEB_SERVER_CONFIG`,
+    /// if any, then applies environment variables and the flags in `args`,
+    /// which should not include the program name.
+    ///
+    /// `env` looks up an environment variable, so tests need not touch the
+    /// real environment.
+    pub fn load<I, E>(args: I, env: E) -> Result<Config, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+        E: Fn(&str) -> Option<String>,
+    {
+        let (path, flags) = parse_args(args)?;
+        let path = path.or_else(|| env("WEB_SERVER_CONFIG").map(PathBuf::from));
+
+        let mut config = Config::default();
+        if let Some(path) = path {
+            config.apply(file_assignments(&path)?)?;
+        }
+        config.apply(env_assignments(&env))?;
+        config.apply(flags)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads a config file on top of the defaults. Relative paths are taken
+    /// relative to the file, not the working directory.
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        config.apply(file_assignments(path)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the settings that can only be judged together or against the
+    /// file system, reporting every problem at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if self.bind.is_empty() && self.tls.bind.is_empty() {
+            problems.push(String::from(
+                "bind or tls.bind must name at least one address",
+            ));
+        }
+        if !self.tls.bind.is_empty() {
+            if !cfg!(feature = "tls") {
+                problems.push(String::from(
+                    "tls.bind needs web-server built with `--features tls`",
+                ));
+            }
+            for (name, path) in [("tls.cert", &self.tls.cert), ("tls.key", &self.tls.key)] {
+                match path {
+                    None => problems.push(format!("tls.bind needs {name}")),
+                    Some(path) if !path.is_file() => {
+                        problems.push(format!("{name} {} is not a file", path.display()))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        if self.mode == Mode::Epoll {
+            if !cfg!(target_os = "linux") {
+                problems.push(String::from("mode epoll is only available on Linux"));
+            }
+            if !self.tls.bind.is_empty() {
+                problems.push(String::from("mode epoll cannot serve tls.bind"));
+            }
+            // The loop thread itself submits requests to the pool.
+            if self.pool.overflow == OverflowPolicy::Block {
+                problems.push(String::from(
+                    "mode epoll cannot use pool.overflow block, which would stall the loop",
+                ));
+            }
+        }
+        if self.tls.redirect_http && (self.tls.bind.is_empty() || self.bind.is_empty()) {
+            problems.push(String::from(
+                "tls.redirect_http needs both bind and tls.bind",
+            ));
+        }
+        if !self.root.is_dir() {
+            problems.push(format!("root {} is not a directory", self.root.display()));
+        }
+        if self.pool.max_workers == 0 {
+            problems.push(String::from("pool.max_workers must be at least 1"));
+        }
+        if self.pool.min_workers > self.pool.max_workers {
+            problems.push(format!(
+                "pool.min_workers ({}) is more than pool.max_workers ({})",
+                self.pool.min_workers, self.pool.max_workers
+            ));
+        }
+        if self.keep_alive.idle_timeout.is_zero() {
+            problems.push(String::from("keep_alive.timeout must be more than 0"));
+        }
+        if self.keep_alive.max_requests == 0 {
+            problems.push(String::from("keep_alive.max_requests must be at least 1"));
+        }
+        for (name, timeout) in [
+            ("limits.header_timeout", self.limits.header_timeout),
+            ("limits.body_timeout", self.limits.body_timeout),
+            ("limits.write_timeout", self.limits.write_timeout),
+        ] {
+            if timeout.is_zero() {
+                problems.push(format!("{name} must be more than 0"));
+            }
+        }
+        if self.limits.max_header_bytes == 0 {
+            problems.push(String::from("limits.max_header_bytes must be at least 1"));
+        }
+        let proxy = &self.proxy;
+        if proxy.prefixes.is_empty() != proxy.upstreams.is_empty() {
+            problems.push(String::from(
+                "proxy.prefixes and proxy.upstreams must be set together",
+            ));
+        }
+        for prefix in &proxy.prefixes {
+            if !prefix.starts_with('/') {
+                problems.push(format!("proxy prefix {prefix:?} must start with /"));
+            }
+        }
+        if proxy
+            .health_check
+            .as_ref()
+            .is_some_and(|path| !path.starts_with('/'))
+        {
+            problems.push(String::from("proxy.health_check must start with /"));
+        }
+        if proxy.health_interval.is_zero() {
+            problems.push(String::from("proxy.health_interval must be more than 0"));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// The `--help` text, listing every flag.
+    pub fn usage() -> String {
+        let mut usage = String::from(
+            "Usage: web-server [--config FILE] [--SETTING VALUE]...\n\n\
+             Settings are read from FILE, then WEB_SERVER_* environment variables,\n\
+             then flags. Durations take a unit: 500ms, 30s, 5m.\n\n",
+        );
+        usage.push_str(&format!(
+            "  {:<28}{}\n",
+            "--config FILE", "TOML file to read settings from"
+        ));
+        for (key, help) in SETTINGS {
+            usage.push_str(&format!("  {:<28}{help}\n", flag_name(key)));
+        }
+        usage
+    }
+
+    // Applies one layer in `SETTINGS` order, so `log.file` is in place
+    // before `log.max_bytes` whatever order they were given in.
+    fn apply(&mut self, mut assignments: Vec<Assignment>) -> Result<(), ConfigError> {
+        assignments.sort_by_key(|a| SETTINGS.iter().position(|(key, _)| *key == a.key));
+        for Assignment { key, value, origin } in assignments {
+            self.set(key, &value)
+                .map_err(|message| ConfigError::Value { origin, message })?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "bind" => self.bind = parse_addresses(value)?,
+            "root" => self.root = PathBuf::from(value),
+            "mode" => self.mode = value.parse::<Mode>()?,
+            "drain_timeout" => self.drain_timeout = parse_duration(value)?,
+            "pool.min_workers" => self.pool.min_workers = parse_number(value)?,
+            "pool.max_workers" => self.pool.max_workers = parse_number(value)?,
+            "pool.idle_timeout" => self.pool.idle_timeout = parse_duration(value)?,
+            "pool.queue_capacity" => {
+                self.pool.queue_capacity = match value {
+                    "unbounded" => None,
+                    _ => match parse_number(value)? {
+                        0 => return Err(String::from("must be at least 1 or \"unbounded\"")),
+                        n => Some(n),
+                    },
+                }
+            }
+            "pool.overflow" => self.pool.overflow = parse_overflow(value)?,
+            "keep_alive.timeout" => self.keep_alive.idle_timeout = parse_duration(value)?,
+            "keep_alive.max_requests" => self.keep_alive.max_requests = parse_number(value)?,
+            "limits.header_timeout" => self.limits.header_timeout = parse_duration(value)?,
+            "limits.body_timeout" => self.limits.body_timeout = parse_duration(value)?,
+            "limits.write_timeout" => self.limits.write_timeout = parse_duration(value)?,
+            "limits.max_header_bytes" => self.limits.max_header_bytes = parse_number(value)?,
+            "limits.max_body_bytes" => self.limits.max_body_bytes = parse_number(value)?,
+            "limits.max_connections_per_ip" => {
+                self.limits.max_connections_per_ip = match value {
+                    "unlimited" => None,
+                    _ => match parse_number(value)? {
+                        0 => return Err(String::from("must be at least 1 or \"unlimited\"")),
+                        n => Some(n),
+                    },
+                }
+            }
+            "limits.max_websockets" => self.limits.max_websockets = parse_number(value)?,
+            "compression.encodings" => {
+                self.compression.encodings = match value {
+                    "none" => Vec::new(),
+                    _ => value
+                        .split(',')
+                        .map(|encoding| encoding.trim().parse::<Encoding>())
+                        .collect::<Result<_, _>>()?,
+                }
+            }
+            "compression.min_bytes" => self.compression.min_bytes = parse_number(value)? as u64,
+            "compression.types" => {
+                self.compression.mime_types = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|mime_type| !mime_type.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            "proxy.prefixes" => {
+                self.proxy.prefixes = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|prefix| !prefix.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            "proxy.upstreams" => self.proxy.upstreams = parse_addresses(value)?,
+            "proxy.balance" => self.proxy.balance = value.parse::<Balance>()?,
+            "proxy.strip_prefix" => self.proxy.strip_prefix = parse_bool(value)?,
+            "proxy.health_check" => {
+                self.proxy.health_check = match value {
+                    "none" => None,
+                    _ => Some(value.to_string()),
+                }
+            }
+            "proxy.health_interval" => self.proxy.health_interval = parse_duration(value)?,
+            "proxy.max_response_bytes" => self.proxy.max_response_bytes = parse_number(value)?,
+            "log.level" => self.log.level = value.parse::<Level>()?,
+            "log.format" => self.log.format = value.parse::<LogFormat>()?,
+            "log.file" => {
+                self.log.output = LogOutput::File {
+                    path: PathBuf::from(value),
+                    max_bytes: DEFAULT_LOG_MAX_BYTES,
+                    keep: DEFAULT_LOG_KEEP,
+                }
+            }
+            "log.max_bytes" | "log.keep" => match &mut self.log.output {
+                LogOutput::File {
+                    max_bytes, keep, ..
+                } => {
+                    if key == "log.keep" {
+                        *keep = parse_number(value)?;
+                    } else {
+                        *max_bytes = parse_number(value)? as u64;
+                    }
+                }
+                LogOutput::Stdout => return Err(String::from("only applies with log.file")),
+            },
+            "tls.bind" => self.tls.bind = parse_addresses(value)?,
+            "tls.cert" => self.tls.cert = Some(PathBuf::from(value)),
+            "tls.key" => self.tls.key = Some(PathBuf::from(value)),
+            "tls.redirect_http" => self.tls.redirect_http = parse_bool(value)?,
+            _ => unreachable!("unknown setting {key}"),
+        }
+        Ok(())
+    }
+}
+
+// Splits `--config` off from the setting flags. Flags take their value as
+// the next argument or after `=`.
+fn parse_args<I>(args: I) -> Result<(Option<PathBuf>, Vec<Assignment>), ConfigError>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut path = None;
+    let mut flags = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let key = if flag == "--config" {
+            None
+        } else {
+            Some(
+                SETTINGS
+                    .iter()
+                    .map(|(key, _)| *key)
+                    .find(|key| flag_name(key) == flag)
+                    .ok_or_else(|| ConfigError::Usage(format!("unknown flag {flag}")))?,
+            )
+        };
+        let value = inline
+            .or_else(|| args.next())
+            .ok_or_else(|| ConfigError::Usage(format!("{flag} needs a value")))?;
+
+        match key {
+            None => path = Some(PathBuf::from(value)),
+            Some(key) => flags.push(Assignment {
+                key,
+                value,
+                origin: flag,
+            }),
+        }
+    }
+
+    Ok((path, flags))
+}
+
+fn env_assignments(env: &dyn Fn(&str) -> Option<String>) -> Vec<Assignment> {
+    SETTINGS
+        .iter()
+        .filter_map(|(key, _)| {
+            let name = env_name(key);
+            env(&name).map(|value| Assignment {
+                key,
+                value,
+                origin: name,
+            })
+        })
+        .collect()
+}
+
+fn file_assignments(path: &Path) -> Result<Vec<Assignment>, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|error| ConfigError::Read {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    let table: toml::Table = text
+        .parse()
+        .map_err(|e: toml::de::Error| ConfigError::Parse {
+            path: path.to_path_buf(),
+            message: e.message().to_string(),
+        })?;
+
+    let mut assignments = Vec::new();
+    flatten(path, "", &table, &mut assignments)?;
+
+    // The file's own directory anchors relative paths, so the server finds
+    // its files whatever directory it is started from.
+    let base = path.parent().unwrap_or(Path::new(""));
+    for assignment in &mut assignments {
+        if PATH_SETTINGS.contains(&assignment.key) {
+            assignment.value = base.join(&assignment.value).display().to_string();
+        }
+    }
+    Ok(assignments)
+}
+
+// Turns `[pool] max_workers = 8` into the setting `pool.max_workers`.
+fn flatten(
+    path: &Path,
+    prefix: &str,
+    table: &toml::Table,
+    assignments: &mut Vec<Assignment>,
+) -> Result<(), ConfigError> {
+    for (name, value) in table {
+        let key = format!("{prefix}{name}");
+        let origin = format!("{}: {key}", path.display());
+        if let toml::Value::Table(section) = value {
+            if prefix.is_empty() {
+                flatten(path, &format!("{key}."), section, assignments)?;
+                continue;
+            }
+        }
+
+        let key = SETTINGS
+            .iter()
+            .map(|(known, _)| *known)
+            .find(|known| *known == key)
+            .ok_or_else(|| ConfigError::Usage(format!("unknown setting {origin}")))?;
+        let value = match value {
+            toml::Value::String(s) => s.clone(),
+            toml::Value::Integer(n) => n.to_string(),
+            toml::Value::Boolean(b) => b.to_string(),
+            // Lists of addresses are joined like the flag's value.
+            toml::Value::Array(items) => items
+                .iter()
+                .map(|item| match item {
+                    toml::Value::String(s) => Ok(s.clone()),
+                    _ => Err(ConfigError::Value {
+                        origin: origin.clone(),
+                        message: String::from("expected a list of strings"),
+                    }),
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .join(","),
+            other => {
+                return Err(ConfigError::Value {
+                    origin,
+                    message: format!("unexpected {}", other.type_str()),
+                })
+            }
+        };
+        assignments.push(Assignment { key, value, origin });
+    }
+    Ok(())
+}
+
+fn flag_name(key: &str) -> String {
+    format!("--{}", key.replace(['.', '_'], "-"))
+}
+
+fn env_name(key: &str) -> String {
+    format!("{ENV_PREFIX}{}", key.replace('.', "_").to_uppercase())
+}
+
+fn parse_number(value: &str) -> Result<usize, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("expected a whole number, got {value:?}"))
+}
+
+/// Parses durations such as `250ms`, `30s`, `5m` and `1h`, up to a year.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    // Deadlines are computed by adding to the current time, which panics
+    // for durations too large to represent.
+    const MAX_SECS: u64 = 365 * 24 * 60 * 60;
+
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("expected a duration like 30s, got {value:?}"))?;
+    let duration = match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "s" => Some(Duration::from_secs(number)),
+        "m" => number.checked_mul(60).map(Duration::from_secs),
+        "h" => number.checked_mul(60 * 60).map(Duration::from_secs),
+        _ => {
+            return Err(format!(
+                "expected a duration with a unit of ms, s, m or h, got {value:?}"
+            ))
+        }
+    };
+    duration
+        .filter(|duration| duration.as_secs() <= MAX_SECS)
+        .ok_or_else(|| format!("expected a duration of at most a year, got {value:?}"))
+}
+
+fn parse_addresses(value: &str) -> Result<Vec<SocketAddr>, String> {
+    let mut addresses = Vec::new();
+    for address in value.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+        let resolved = address
+            .to_socket_addrs()
+            .map_err(|e| format!("cannot use {address:?} as an address: {e}"))?;
+        addresses.extend(resolved);
+    }
+    Ok(addresses)
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("expected true or false, got {value:?}")),
+    }
+}
+
+fn parse_overflow(value: &str) -> Result<OverflowPolicy, String> {
+    match value {
+        "block" => Ok(OverflowPolicy::Block),
+        "reject" => Ok(OverflowPolicy::Reject),
+        "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+        "caller-runs" => Ok(OverflowPolicy::CallerRuns),
+        _ => Err(format!(
+            "expected block, reject, drop-oldest or caller-runs, got {value:?}"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn write_config(name: &str, text: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("web-server-config-{name}-{}", std::process::id()));
+        fs::create_dir_all(dir.join("site")).unwrap();
+        let path = dir.join("web-server.toml");
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn flags_override_env_which_overrides_the_file() {
+        let path = write_config(
+            "layers",
+            r#"
+            bind = ["127.0.0.1:8000", "127.0.0.1:8001"]
+            root = "site"
+
+            [pool]
+            min_workers = 1
+            max_workers = 4
+            queue_capacity = "unbounded"
+
+            [limits]
+            header_timeout = "2s"
+            max_body_bytes = 2048
+
+            [compression]
+            encodings = ["gzip", "br"]
+
+            [proxy]
+            prefixes = ["/api", "/auth"]
+            upstreams = ["127.0.0.1:9000", "127.0.0.1:9001"]
+            balance = "least-busy"
+            health_check = "/health"
+            max_response_bytes = 4096
+
+            [log]
+            max_bytes = 1024
+            file = "server.log"
+            "#,
+        );
+        let env = HashMap::from([
+            ("WEB_SERVER_POOL_MAX_WORKERS", "8"),
+            ("WEB_SERVER_KEEP_ALIVE_TIMEOUT", "250ms"),
+            ("WEB_SERVER_LIMITS_MAX_CONNECTIONS_PER_IP", "unlimited"),
+            ("WEB_SERVER_MODE", "epoll"),
+            ("WEB_SERVER_PROXY_STRIP_PREFIX", "true"),
+        ]);
+        let load = |overflow: &str| {
+            Config::load(
+                args(&[
+                    "--config",
+                    path.to_str().unwrap(),
+                    "--pool-max-workers=16",
+                    "--pool-overflow",
+                    overflow,
+                ]),
+                |name| env.get(name).map(|v| v.to_string()),
+            )
+        };
+
+        let error = load("block").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("mode epoll cannot use pool.overflow block"),
+            "{error}"
+        );
+        let config = load("drop-oldest").unwrap();
+
+        assert_eq!(config.bind.len(), 2);
+        assert_eq!(config.root, path.parent().unwrap().join("site"));
+        assert_eq!(config.pool.min_workers, 1);
+        assert_eq!(config.pool.max_workers, 16);
+        assert_eq!(config.pool.queue_capacity, None);
+        assert_eq!(config.pool.overflow, OverflowPolicy::DropOldest);
+        assert_eq!(config.keep_alive.idle_timeout, Duration::from_millis(250));
+        assert_eq!(config.limits.header_timeout, Duration::from_secs(2));
+        assert_eq!(config.limits.max_body_bytes, 2048);
+        assert_eq!(config.limits.max_connections_per_ip, None);
+        assert_eq!(config.mode, Mode::Epoll);
+        assert_eq!(
+            config.compression.encodings,
+            [Encoding::Gzip, Encoding::Brotli]
+        );
+        assert_eq!(config.proxy.prefixes, ["/api", "/auth"]);
+        assert_eq!(config.proxy.upstreams.len(), 2);
+        assert_eq!(config.proxy.balance, Balance::LeastBusy);
+        assert!(config.proxy.strip_prefix);
+        assert_eq!(config.proxy.health_check.as_deref(), Some("/health"));
+        assert_eq!(config.proxy.max_response_bytes, 4096);
+        match config.log.output {
+            LogOutput::File { max_bytes, .. } => assert_eq!(max_bytes, 1024),
+            LogOutput::Stdout => panic!("expected a log file"),
+        }
+    }
+
+    #[test]
+    fn reports_where_a_bad_value_came_from() {
+        let path = write_config("bad", "[pool]\nmax_workers = \"many\"\n");
+        let error = Config::from_file(&path).unwrap_err();
+        assert!(matches!(error, ConfigError::Value { .. }));
+        assert!(error
+            .to_string()
+            .contains("pool.max_workers: expected a whole number"));
+
+        let path = write_config("unknown", "[pool]\nworkers = 4\n");
+        let error = Config::from_file(&path).unwrap_err();
+        assert!(error.to_string().contains("unknown setting"));
+
+        let error = Config::load(args(&["--drain-timeout", "10"]), |_| None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "--drain-timeout: expected a duration with a unit of ms, s, m or h, got \"10\""
+        );
+        let error = Config::load(args(&["--workers", "4"]), |_| None).unwrap_err();
+        assert!(matches!(error, ConfigError::Usage(_)));
+        let error = Config::load(args(&["--root"]), |_| None).unwrap_err();
+        assert!(error.to_string().contains("--root needs a value"));
+    }
+
+    #[test]
+    fn validation_lists_every_problem() {
+        let error = Config::load(
+            args(&[
+                "--root",
+                "/does/not/exist",
+                "--pool-min-workers",
+                "8",
+                "--pool-max-workers",
+                "2",
+                "--keep-alive-timeout",
+                "0s",
+                "--tls-bind",
+                "127.0.0.1:8443",
+                "--tls-key",
+                "/does/not/exist.pem",
+                "--proxy-prefixes",
+                "api",
+            ]),
+            |_| None,
+        )
+        .unwrap_err();
+
+        let ConfigError::Invalid(problems) = error else {
+            panic!("unexpected error: {error}");
+        };
+        assert!(problems.contains(&String::from("tls.bind needs tls.cert")));
+        assert!(problems.contains(&String::from("tls.key /does/not/exist.pem is not a file")));
+        assert!(problems.contains(&String::from("proxy prefix \"api\" must start with /")));
+        let without_feature = usize::from(!cfg!(feature = "tls"));
+        assert_eq!(problems.len(), 7 + without_feature, "{problems:?}");
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert!(parse_duration("400000000000000000h").is_err());
+        assert!(parse_duration("18446744073709551615s").is_err());
+        assert_eq!(parse_duration("8760h"), Ok(Duration::from_secs(31_536_000)));
+        assert!(parse_duration("5 minutes").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(Config::usage().contains("--keep-alive-max-requests"));
+    }
+}
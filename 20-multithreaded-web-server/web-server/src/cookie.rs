@@ -0,0 +1,315 @@
+//! Cookies, as in RFC 6265.
+//!
+//! `Request::cookie` reads the cookies a client sent and
+//! `Response::with_cookie` sets one. A `Key` signs values so a client
+//! cannot change them without the server noticing:
+//!
+//! ```
+//! use web_server::cookie::{Cookie, Key, SameSite};
+//! use web_server::{Response, StatusCode};
+//!
+//! let key = Key::generate();
+//! let cookie = Cookie::new("theme", "dark")
+//!     .max_age(std::time::Duration::from_secs(3600))
+//!     .same_site(SameSite::Lax);
+//! let response = Response::new(StatusCode::Ok).with_cookie(&key.sign(cookie));
+//!
+//! let sent = response.header("set-cookie").unwrap();
+//! let value = sent.split(';').next().unwrap().trim_start_matches("theme=");
+//! assert_eq!(key.verify("theme", value), Some("dark"));
+//! ```
+
+use std::{fmt, time::Duration};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::Request;
+
+/// The shortest secret `Key::new` accepts.
+const MIN_KEY_BYTES: usize = 32;
+
+/// Whether a browser sends a cookie on requests from other sites.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    /// Only on requests from the site itself.
+    Strict,
+    /// Also when following a link from another site.
+    Lax,
+    /// On every request. Browsers ignore the cookie unless it is `Secure`.
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        })
+    }
+}
+
+/// A cookie to set, written out as a `Set-Cookie` value by `Display`.
+///
+/// The name and value are sent as given, so they must not contain spaces,
+/// quotes, commas, semicolons or backslashes. Percent-encode anything else.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// A cookie that lasts until the browser is closed.
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie that makes the browser delete `name`. Give it the same path
+    /// and domain the cookie was set with.
+    pub fn removal(name: &str) -> Cookie {
+        Cookie::new(name, "").max_age(Duration::ZERO)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn path(mut self, path: &str) -> Cookie {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Cookie {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    /// How long the browser keeps the cookie, to the second.
+    pub fn max_age(mut self, max_age: Duration) -> Cookie {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Only sends the cookie over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Cookie {
+        self.secure = secure;
+        self
+    }
+
+    /// Hides the cookie from scripts in the page.
+    pub fn http_only(mut self, http_only: bool) -> Cookie {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={path}")?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={domain}")?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={same_site}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits a `Cookie` request header into names and values. Pairs without
+/// `=` are skipped and double quotes around a value are removed.
+pub fn parse(header: &str) -> impl Iterator<Item = (&str, &str)> {
+    header.split(';').filter_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+        Some((name.trim(), value))
+    })
+}
+
+/// A secret for signing cookie values with HMAC-SHA256.
+///
+/// A signed value is readable by the client, but any change to it, or a
+/// move to a cookie of another name, fails `verify`.
+#[derive(Clone)]
+pub struct Key(Vec<u8>);
+
+impl Key {
+    /// A key from a secret kept by the application, so signed cookies stay
+    /// valid across restarts.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `secret` is shorter than 32 bytes.
+    pub fn new(secret: &[u8]) -> Key {
+        assert!(
+            secret.len() >= MIN_KEY_BYTES,
+            "a cookie key needs at least {MIN_KEY_BYTES} bytes"
+        );
+        Key(secret.to_vec())
+    }
+
+    /// A random key. Cookies signed with it stop verifying when the process
+    /// exits.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the OS has no randomness to offer.
+    pub fn generate() -> Key {
+        let mut secret = [0; MIN_KEY_BYTES];
+        getrandom::getrandom(&mut secret).expect("Failed to read random bytes");
+        Key(secret.to_vec())
+    }
+
+    /// Appends a signature to the value of `cookie`.
+    pub fn sign(&self, mut cookie: Cookie) -> Cookie {
+        let signature = self.mac(&cookie.name, &cookie.value).finalize();
+        cookie.value = format!(
+            "{}.{}",
+            cookie.value,
+            URL_SAFE_NO_PAD.encode(signature.into_bytes())
+        );
+        cookie
+    }
+
+    /// Returns the original value if `value` was signed by this key for a
+    /// cookie named `name`.
+    pub fn verify<'a>(&self, name: &str, value: &'a str) -> Option<&'a str> {
+        let (value, signature) = value.rsplit_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.mac(name, value)
+            .verify_slice(&signature)
+            .ok()
+            .map(|()| value)
+    }
+
+    /// Returns the value of the cookie `name` if it carries a valid
+    /// signature.
+    pub fn signed_cookie<'a>(&self, request: &'a Request, name: &str) -> Option<&'a str> {
+        self.verify(name, request.cookie(name)?)
+    }
+
+    fn mac(&self, name: &str, value: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC takes keys of any size");
+        // The name is bound in too, with a separator it cannot contain.
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+        mac
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Response, StatusCode};
+
+    fn request(cookies: &[&str]) -> Request {
+        let headers: String = cookies
+            .iter()
+            .map(|header| format!("Cookie: {header}\r\n"))
+            .collect();
+        let raw = format!("GET / HTTP/1.1\r\n{headers}\r\n");
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn formats_set_cookie() {
+        assert_eq!(Cookie::new("a", "1").to_string(), "a=1");
+        let cookie = Cookie::new("id", "abc")
+            .path("/app")
+            .domain("example.com")
+            .max_age(Duration::from_millis(90_500))
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Strict);
+        assert_eq!(
+            cookie.to_string(),
+            "id=abc; Path=/app; Domain=example.com; Max-Age=90; Secure; HttpOnly; SameSite=Strict"
+        );
+        assert_eq!(Cookie::removal("id").to_string(), "id=; Max-Age=0");
+
+        let response = Response::new(StatusCode::Ok)
+            .with_cookie(&Cookie::new("a", "1"))
+            .with_cookie(&Cookie::new("b", "2"));
+        let set: Vec<_> = response.headers.get_all("set-cookie").collect();
+        assert_eq!(set, ["a=1", "b=2"]);
+    }
+
+    #[test]
+    fn parses_cookie_headers() {
+        let request = request(&["a=1; b=\"two words\";flag; c=x=y", "d=4"]);
+
+        assert_eq!(request.cookie("a"), Some("1"));
+        assert_eq!(request.cookie("b"), Some("two words"));
+        assert_eq!(request.cookie("c"), Some("x=y"));
+        assert_eq!(request.cookie("d"), Some("4"));
+        assert_eq!(request.cookie("flag"), None);
+        assert_eq!(request.cookie("A"), None);
+    }
+
+    #[test]
+    fn signed_values_resist_tampering() {
+        let key = Key::new(&[7; 32]);
+        let signed = key.sign(Cookie::new("user", "ann.admin"));
+        assert!(signed.value().starts_with("ann.admin."));
+
+        assert_eq!(key.verify("user", signed.value()), Some("ann.admin"));
+        let request = request(&[&format!("user={}", signed.value())]);
+        assert_eq!(key.signed_cookie(&request, "user"), Some("ann.admin"));
+
+        let forged = signed.value().replacen("ann", "bob", 1);
+        assert_eq!(key.verify("user", &forged), None);
+        assert_eq!(key.verify("admin", signed.value()), None);
+        assert_eq!(key.verify("user", "ann.admin"), None);
+        assert_eq!(Key::generate().verify("user", signed.value()), None);
+    }
+}
@@ -0,0 +1,930 @@
+//! Typed access to request bodies.
+//!
+//! `Form`, `Json` and `Multipart` each check the request's `Content-Type`
+//! and a size limit from `BodyConfig` before parsing. A `BodyError` turns
+//! into the response to send back: `415` for the wrong content type, `413`
+//! for a body over a limit and `400` for one that does not parse.
+//!
+//! ```no_run
+//! use web_server::extract::{BodyConfig, Form};
+//! use web_server::{Response, Router, StatusCode};
+//!
+//! let config = BodyConfig::default();
+//! let mut router = Router::new();
+//! router.post("/subscribe", move |request, _| {
+//!     let form = match Form::from_request(request, &config) {
+//!         Ok(form) => form,
+//!         Err(e) => return e.to_response(),
+//!     };
+//!     let email = form.get("email").unwrap_or("nobody");
+//!     Response::new(StatusCode::Ok).with_body(format!("Subscribed {email}"))
+//! });
+//! ```
+
+use std::{
+    env,
+    error::Error,
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tempfile::NamedTempFile;
+
+use crate::{request, Request, Response, StatusCode};
+
+/// The most bytes the headers of one multipart part may take.
+const MAX_PART_HEADER_BYTES: usize = 8 * 1024;
+
+/// How much of a multipart body is read at a time.
+const CHUNK_BYTES: usize = 16 * 1024;
+
+/// Limits for parsing request bodies, and where uploads are kept.
+///
+/// They apply on top of `Limits::max_body_bytes`, which caps what the
+/// server reads in the first place, except for a body left to a handler
+/// registered with `Router::streaming`.
+#[derive(Debug, Clone)]
+pub struct BodyConfig {
+    pub max_form_bytes: usize,
+    pub max_json_bytes: usize,
+    /// The longest value of a multipart field that is not a file.
+    pub max_field_bytes: usize,
+    /// The largest single file in a multipart body. A body the server has
+    /// read is already capped by `Limits::max_body_bytes`; this matters for
+    /// one read with `Multipart::from_body`.
+    pub max_file_bytes: u64,
+    /// How many fields and files a multipart body may have in all.
+    pub max_parts: usize,
+    /// Where uploaded files are written while the request is handled.
+    pub temp_dir: PathBuf,
+}
+
+impl Default for BodyConfig {
+    fn default() -> BodyConfig {
+        BodyConfig {
+            max_form_bytes: 64 * 1024,
+            max_json_bytes: 1024 * 1024,
+            max_field_bytes: 64 * 1024,
+            max_file_bytes: 100 * 1024 * 1024,
+            max_parts: 100,
+            temp_dir: env::temp_dir(),
+        }
+    }
+}
+
+/// Why a request body could not be extracted.
+#[derive(Debug)]
+pub enum BodyError {
+    /// The `Content-Type` is missing or not the one expected.
+    UnsupportedMediaType {
+        expected: &'static str,
+        found: Option<String>,
+    },
+    /// The body, or part of it, is over a limit in `BodyConfig`.
+    TooLarge(String),
+    /// The body does not parse as its content type.
+    Malformed(String),
+    /// An upload could not be written to disk.
+    Io(io::Error),
+}
+
+impl BodyError {
+    /// The status to answer the client with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            BodyError::UnsupportedMediaType { .. } => StatusCode::UnsupportedMediaType,
+            BodyError::TooLarge(_) => StatusCode::PayloadTooLarge,
+            BodyError::Malformed(_) => StatusCode::BadRequest,
+            BodyError::Io(_) => StatusCode::InternalServerError,
+        }
+    }
+
+    /// A plain-text response explaining the error. I/O errors are not
+    /// described, since they are the server's problem rather than the
+    /// client's.
+    pub fn to_response(&self) -> Response {
+        let status = self.status();
+        let message = match self {
+            BodyError::Io(_) => status.reason_phrase().to_string(),
+            other => other.to_string(),
+        };
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(message)
+    }
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BodyError::UnsupportedMediaType {
+                expected,
+                found: Some(found),
+            } => write!(f, "expected Content-Type {expected}, got {found}"),
+            BodyError::UnsupportedMediaType {
+                expected,
+                found: None,
+            } => write!(f, "expected Content-Type {expected}"),
+            BodyError::TooLarge(what) => write!(f, "{what}"),
+            BodyError::Malformed(what) => write!(f, "{what}"),
+            BodyError::Io(e) => write!(f, "i/o error: {e}"),
+        }
+    }
+}
+
+impl Error for BodyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BodyError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BodyError {
+    fn from(e: io::Error) -> BodyError {
+        match e.kind() {
+            // How a body read from the connection reports a client that
+            // stopped early or sent a broken chunk.
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData => {
+                BodyError::Malformed(format!("cannot read the body: {e}"))
+            }
+            _ => BodyError::Io(e),
+        }
+    }
+}
+
+/// The fields of an `application/x-www-form-urlencoded` body, in the order
+/// they were sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Form(Vec<(String, String)>);
+
+impl Form {
+    pub fn from_request(request: &Request, config: &BodyConfig) -> Result<Form, BodyError> {
+        expect_type(request, "application/x-www-form-urlencoded", |essence| {
+            essence == "application/x-www-form-urlencoded"
+        })?;
+        check_size("form", request.body.len(), config.max_form_bytes)?;
+        let body = std::str::from_utf8(&request.body)
+            .map_err(|_| BodyError::Malformed(String::from("form body is not UTF-8")))?;
+        request::parse_query(body)
+            .map(Form)
+            .map_err(|e| BodyError::Malformed(format!("form body: {e}")))
+    }
+
+    /// Returns the first value of the field `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns every value of the field `name`, as sent by checkboxes and
+    /// multiple selects.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.iter()
+            .filter(move |(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+/// A JSON body, or a value to send back as one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> Json<T> {
+    /// Accepts `application/json` and any `+json` type such as
+    /// `application/problem+json`.
+    pub fn from_request(request: &Request, config: &BodyConfig) -> Result<Json<T>, BodyError> {
+        expect_type(request, "application/json", |essence| {
+            essence == "application/json" || essence.ends_with("+json")
+        })?;
+        check_size("JSON", request.body.len(), config.max_json_bytes)?;
+        serde_json::from_slice(&request.body)
+            .map(Json)
+            .map_err(|e| BodyError::Malformed(format!("invalid JSON: {e}")))
+    }
+}
+
+impl<T: Serialize> Json<T> {
+    /// A response with the value as its `application/json` body.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value cannot be serialized, such as a map with keys
+    /// that are not strings.
+    pub fn into_response(self, status: StatusCode) -> Response {
+        let body = serde_json::to_vec(&self.0).expect("value cannot be serialized as JSON");
+        Response::new(status)
+            .with_header("Content-Type", "application/json")
+            .with_body(body)
+    }
+}
+
+/// The fields and files of a `multipart/form-data` body.
+///
+/// Files are written to `BodyConfig::temp_dir` and deleted when the
+/// `Multipart` is dropped unless moved away with `FilePart::persist`.
+#[derive(Debug, Default)]
+pub struct Multipart {
+    fields: Vec<(String, String)>,
+    files: Vec<FilePart>,
+}
+
+impl Multipart {
+    /// Parses the body of `request`, which the server has already read into
+    /// memory, so uploads are bounded by `Limits::max_body_bytes` as well
+    /// as by `config`. See `from_body` for larger ones.
+    pub fn from_request(request: &Request, config: &BodyConfig) -> Result<Multipart, BodyError> {
+        Multipart::from_body(request, &request.body[..], config)
+    }
+
+    /// Parses a body still on the connection, as a handler registered with
+    /// `Router::streaming` gets it. Files go to disk as they arrive, so only
+    /// `config` bounds them.
+    ///
+    /// ```no_run
+    /// use web_server::extract::{BodyConfig, Multipart};
+    /// use web_server::{Method, Response, Router, StatusCode};
+    ///
+    /// let config = BodyConfig::default();
+    /// let mut router = Router::new();
+    /// router.streaming(Method::Post, "/upload", move |request, _, body| {
+    ///     match Multipart::from_body(request, body, &config) {
+    ///         Ok(upload) => Response::new(StatusCode::Ok)
+    ///             .with_body(format!("{} files", upload.files().len())),
+    ///         Err(e) => e.to_response(),
+    ///     }
+    /// });
+    /// ```
+    pub fn from_body<R: Read>(
+        request: &Request,
+        body: R,
+        config: &BodyConfig,
+    ) -> Result<Multipart, BodyError> {
+        let content_type = expect_type(request, "multipart/form-data", |essence| {
+            essence == "multipart/form-data"
+        })?;
+        let (_, params) = parse_params(content_type);
+        let boundary = params
+            .iter()
+            .find(|(name, _)| name == "boundary")
+            .map(|(_, boundary)| boundary.as_str())
+            .filter(|boundary| (1..=70).contains(&boundary.len()))
+            .ok_or_else(|| BodyError::Malformed(String::from("multipart body has no boundary")))?;
+        Multipart::read_from(body, boundary, config)
+    }
+
+    /// Parses a multipart body from `reader` without holding more than a
+    /// chunk of it in memory at once.
+    pub fn read_from<R: Read>(
+        reader: R,
+        boundary: &str,
+        config: &BodyConfig,
+    ) -> Result<Multipart, BodyError> {
+        let delimiter = format!("\r\n--{boundary}").into_bytes();
+        // The first delimiter may open the body without a line break before
+        // it, so the scanner starts with one.
+        let mut scanner = Scanner::new(reader, b"\r\n");
+        scanner.copy_until(&delimiter, |_| Ok(()))?;
+
+        let mut multipart = Multipart::default();
+        loop {
+            if scanner.peek(2)? == b"--" {
+                return Ok(multipart);
+            }
+            let mut budget = MAX_PART_HEADER_BYTES;
+            // Whitespace may pad the line a delimiter ends.
+            if !scanner.read_line(&mut budget)?.trim().is_empty() {
+                return Err(BodyError::Malformed(String::from(
+                    "multipart delimiter is followed by text",
+                )));
+            }
+            if multipart.fields.len() + multipart.files.len() == config.max_parts {
+                return Err(BodyError::TooLarge(format!(
+                    "multipart body has more than {} parts",
+                    config.max_parts
+                )));
+            }
+
+            let part = PartHead::read(&mut scanner, &mut budget)?;
+            match part.filename {
+                Some(filename) => {
+                    let mut file = NamedTempFile::new_in(&config.temp_dir)?;
+                    let mut size = 0;
+                    scanner.copy_until(&delimiter, |chunk| {
+                        size += chunk.len() as u64;
+                        if size > config.max_file_bytes {
+                            return Err(BodyError::TooLarge(format!(
+                                "file {filename:?} is larger than {} bytes",
+                                config.max_file_bytes
+                            )));
+                        }
+                        file.write_all(chunk).map_err(BodyError::Io)
+                    })?;
+                    file.flush()?;
+                    multipart.files.push(FilePart {
+                        name: part.name,
+                        filename,
+                        content_type: part.content_type,
+                        size,
+                        file,
+                    });
+                }
+                None => {
+                    let mut value = Vec::new();
+                    scanner.copy_until(&delimiter, |chunk| {
+                        if value.len() + chunk.len() > config.max_field_bytes {
+                            return Err(BodyError::TooLarge(format!(
+                                "field {:?} is longer than {} bytes",
+                                part.name, config.max_field_bytes
+                            )));
+                        }
+                        value.extend_from_slice(chunk);
+                        Ok(())
+                    })?;
+                    let value = String::from_utf8(value).map_err(|_| {
+                        BodyError::Malformed(format!("field {:?} is not UTF-8", part.name))
+                    })?;
+                    multipart.fields.push((part.name, value));
+                }
+            }
+        }
+    }
+
+    /// Returns the first value of the field `name`.
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Returns the first file sent as the field `name`.
+    pub fn file(&self, name: &str) -> Option<&FilePart> {
+        self.files.iter().find(|file| file.name == name)
+    }
+
+    pub fn files(&self) -> &[FilePart] {
+        &self.files
+    }
+
+    /// Takes the files out, to keep some with `FilePart::persist`.
+    pub fn into_files(self) -> Vec<FilePart> {
+        self.files
+    }
+}
+
+/// An uploaded file, held in a temporary file until dropped or persisted.
+#[derive(Debug)]
+pub struct FilePart {
+    /// The form field the file was sent as.
+    pub name: String,
+    /// The name the client gave the file, without any directories. It is
+    /// chosen by the client, so check it before using it in a path.
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub size: u64,
+    file: NamedTempFile,
+}
+
+impl FilePart {
+    /// Where the upload is while the request is handled.
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// Opens the upload for reading from the start.
+    pub fn open(&self) -> io::Result<File> {
+        self.file.reopen()
+    }
+
+    /// Moves the upload to `path` so it outlives the request. `path` should
+    /// be on the same file system as `BodyConfig::temp_dir`.
+    pub fn persist(self, path: &Path) -> io::Result<File> {
+        self.file.persist(path).map_err(|e| e.error)
+    }
+}
+
+// The headers of one multipart part that matter.
+struct PartHead {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+}
+
+impl PartHead {
+    fn read<R: Read>(scanner: &mut Scanner<R>, budget: &mut usize) -> Result<PartHead, BodyError> {
+        let mut disposition = None;
+        let mut content_type = None;
+        loop {
+            let line = scanner.read_line(budget)?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = request::parse_header(&line)
+                .map_err(|e| BodyError::Malformed(format!("multipart part: {e}")))?;
+            match name.as_str() {
+                "content-disposition" => disposition = Some(value),
+                "content-type" => content_type = Some(value),
+                _ => {}
+            }
+        }
+
+        let disposition = disposition.ok_or_else(|| {
+            BodyError::Malformed(String::from("multipart part has no Content-Disposition"))
+        })?;
+        let (kind, params) = parse_params(&disposition);
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        };
+        let name = param("name")
+            .filter(|_| kind == "form-data")
+            .ok_or_else(|| {
+                BodyError::Malformed(format!(
+                    "multipart part is not a named field: {disposition}"
+                ))
+            })?;
+        // Some browsers send the whole path the file was picked from.
+        let filename = param("filename").map(|filename| {
+            let base = filename.rsplit(['/', '\\']).next().unwrap_or("");
+            base.to_string()
+        });
+        Ok(PartHead {
+            name,
+            filename,
+            content_type,
+        })
+    }
+}
+
+// Reads ahead of a multipart body in chunks, so a delimiter split between
+// two reads is still found.
+struct Scanner<R> {
+    reader: R,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Scanner<R> {
+    fn new(reader: R, start: &[u8]) -> Scanner<R> {
+        Scanner {
+            reader,
+            buf: start.to_vec(),
+            pos: 0,
+        }
+    }
+
+    // Reads another chunk, returning false at the end of the body.
+    fn fill(&mut self) -> Result<bool, BodyError> {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let start = self.buf.len();
+        self.buf.resize(start + CHUNK_BYTES, 0);
+        let read = loop {
+            match self.reader.read(&mut self.buf[start..]) {
+                Ok(read) => break read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        };
+        self.buf.truncate(start + read);
+        Ok(read > 0)
+    }
+
+    fn unexpected_end() -> BodyError {
+        BodyError::Malformed(String::from(
+            "multipart body ended before its closing delimiter",
+        ))
+    }
+
+    fn peek(&mut self, len: usize) -> Result<&[u8], BodyError> {
+        while self.buf.len() - self.pos < len {
+            if !self.fill()? {
+                return Err(Scanner::<R>::unexpected_end());
+            }
+        }
+        Ok(&self.buf[self.pos..self.pos + len])
+    }
+
+    // Reads a line ending in CRLF, charging it to `budget`.
+    fn read_line(&mut self, budget: &mut usize) -> Result<String, BodyError> {
+        loop {
+            let rest = &self.buf[self.pos..];
+            if let Some(end) = find(rest, b"\r\n") {
+                let line = String::from_utf8(rest[..end].to_vec()).map_err(|_| {
+                    BodyError::Malformed(String::from("multipart headers are not UTF-8"))
+                })?;
+                *budget = budget.checked_sub(end + 2).ok_or_else(too_long)?;
+                self.pos += end + 2;
+                return Ok(line);
+            }
+            if rest.len() > *budget {
+                return Err(too_long());
+            }
+            if !self.fill()? {
+                return Err(Scanner::<R>::unexpected_end());
+            }
+        }
+    }
+
+    // Passes everything up to `delimiter` to `sink` and skips the delimiter.
+    // Only the bytes that could be the start of a delimiter are held back.
+    fn copy_until<F>(&mut self, delimiter: &[u8], mut sink: F) -> Result<(), BodyError>
+    where
+        F: FnMut(&[u8]) -> Result<(), BodyError>,
+    {
+        loop {
+            let rest = &self.buf[self.pos..];
+            if let Some(end) = find(rest, delimiter) {
+                sink(&rest[..end])?;
+                self.pos += end + delimiter.len();
+                return Ok(());
+            }
+            let safe = rest.len().saturating_sub(delimiter.len() - 1);
+            if safe > 0 {
+                sink(&rest[..safe])?;
+                self.pos += safe;
+            }
+            if !self.fill()? {
+                return Err(Scanner::<R>::unexpected_end());
+            }
+        }
+    }
+}
+
+fn too_long() -> BodyError {
+    BodyError::TooLarge(format!(
+        "multipart part headers are longer than {MAX_PART_HEADER_BYTES} bytes"
+    ))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// Checks the media type of the request, returning the whole header for its
+// parameters.
+fn expect_type<'a>(
+    request: &'a Request,
+    expected: &'static str,
+    matches: impl Fn(&str) -> bool,
+) -> Result<&'a str, BodyError> {
+    let unsupported = |found: Option<&str>| BodyError::UnsupportedMediaType {
+        expected,
+        found: found.map(String::from),
+    };
+    let content_type = request
+        .header("content-type")
+        .ok_or_else(|| unsupported(None))?;
+    let (essence, _) = parse_params(content_type);
+    if matches(&essence) {
+        Ok(content_type)
+    } else {
+        Err(unsupported(Some(content_type)))
+    }
+}
+
+fn check_size(what: &str, len: usize, max: usize) -> Result<(), BodyError> {
+    if len > max {
+        return Err(BodyError::TooLarge(format!(
+            "{what} body is longer than {max} bytes"
+        )));
+    }
+    Ok(())
+}
+
+// Splits a header such as `form-data; name="a"; filename="b c.txt"` into
+// its lowercased first item and its parameters, unquoting quoted values.
+// Parameter names are lowercased and values kept as sent.
+fn parse_params(value: &str) -> (String, Vec<(String, String)>) {
+    let mut items = Vec::new();
+    let mut current = String::new();
+    let mut chars = value.chars();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            '\\' if quoted => {
+                current.push(c);
+                current.extend(chars.next());
+            }
+            ';' if !quoted => items.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    items.push(current);
+
+    let mut items = items.into_iter();
+    let first = items.next().unwrap_or_default().trim().to_ascii_lowercase();
+    let params = items
+        .filter_map(|item| {
+            let (name, value) = item.split_once('=')?;
+            let value = value.trim();
+            let value = match value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+            {
+                Some(quoted) => unescape(quoted),
+                None => value.to_string(),
+            };
+            Some((name.trim().to_ascii_lowercase(), value))
+        })
+        .collect();
+    (first, params)
+}
+
+fn unescape(quoted: &str) -> String {
+    let mut value = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => value.extend(chars.next()),
+            c => value.push(c),
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Method;
+    use serde::Deserialize;
+
+    fn request(content_type: &str, body: &[u8]) -> Request {
+        let head = format!(
+            "POST / HTTP/1.1\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        );
+        let raw = [head.as_bytes(), body].concat();
+        Request::read_from(&mut &raw[..]).unwrap()
+    }
+
+    fn upload() -> Vec<u8> {
+        [
+            "--XyZ\r\n",
+            "Content-Disposition: form-data; name=\"title\"\r\n\r\n",
+            "Holiday\r\n",
+            "--XyZ\r\n",
+            "Content-Disposition: form-data; name=\"photo\"; filename=\"C:\\\\pics\\\\beach \\\"1\\\".txt\"\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "sand\r\n--Xy and sea\r\n",
+            "--XyZ--\r\n",
+        ]
+        .concat()
+        .into_bytes()
+    }
+
+    #[test]
+    fn parses_forms() {
+        let request = request(
+            "application/x-www-form-urlencoded; charset=UTF-8",
+            b"name=J%C3%BCrgen+B&tag=a&tag=b&empty=",
+        );
+        let form = Form::from_request(&request, &BodyConfig::default()).unwrap();
+
+        assert_eq!(form.get("name"), Some("Jürgen B"));
+        assert_eq!(form.get_all("tag").collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(form.get("empty"), Some(""));
+        assert_eq!(form.get("missing"), None);
+    }
+
+    #[test]
+    fn parses_json() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Order {
+            item: String,
+            count: u32,
+        }
+
+        let valid = request("application/json", br#"{"item": "tea", "count": 2}"#);
+        let Json(order) = Json::<Order>::from_request(&valid, &BodyConfig::default()).unwrap();
+        assert_eq!(
+            order,
+            Order {
+                item: String::from("tea"),
+                count: 2
+            }
+        );
+
+        let incomplete = request("application/vnd.api+json", br#"{"item": "tea"}"#);
+        let e = Json::<Order>::from_request(&incomplete, &BodyConfig::default()).unwrap_err();
+        assert_eq!(e.status(), StatusCode::BadRequest);
+        assert!(e.to_string().contains("count"), "{e}");
+    }
+
+    #[test]
+    fn streams_uploads_past_the_body_limit() {
+        use crate::{
+            testing::{TestRequest, TestServer},
+            Limits, Router, Server, ThreadPool,
+        };
+
+        let file = "sand and sea ".repeat(20_000);
+        let mut router = Router::new();
+        router.streaming(Method::Post, "/upload", |request, _, body| {
+            // The server left the body for the handler to read.
+            assert!(request.body.is_empty());
+            let upload = match Multipart::from_body(request, body, &BodyConfig::default()) {
+                Ok(upload) => upload,
+                Err(e) => return e.to_response(),
+            };
+            let photo = upload.file("photo").unwrap();
+            let size = std::fs::metadata(photo.path()).unwrap().len();
+            Response::new(StatusCode::Ok).with_body(format!("{size} bytes"))
+        });
+        let server = TestServer::with(|listener| {
+            Server::new(listener, router, ThreadPool::new(2)).limits(Limits {
+                max_body_bytes: 1024,
+                ..Limits::default()
+            })
+        });
+        let body = [
+            "--XyZ\r\n",
+            "Content-Disposition: form-data; name=\"photo\"; filename=\"beach.txt\"\r\n\r\n",
+            &file,
+            "\r\n--XyZ--\r\n",
+        ]
+        .concat();
+        let upload = TestRequest::new(Method::Post, "/upload")
+            .header("Content-Type", "multipart/form-data; boundary=XyZ")
+            .body(body);
+
+        let mut client = server.client();
+        let response = client.send(&upload).unwrap();
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(response.text(), format!("{} bytes", file.len()));
+        assert_eq!(response.header("connection"), Some("keep-alive"));
+        // The connection carries on after a body read to the end.
+        assert_eq!(client.send(&upload).unwrap().status, StatusCode::Ok);
+        server.shutdown();
+    }
+
+    #[test]
+    fn streams_multipart_files_to_disk() {
+        let config = BodyConfig::default();
+        // Reading a byte at a time splits every delimiter across reads.
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let len = self.0.len().min(buf.len()).min(1);
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+
+        for multipart in [
+            Multipart::from_request(
+                &request("multipart/form-data; boundary=\"XyZ\"", &upload()),
+                &config,
+            )
+            .unwrap(),
+            Multipart::read_from(Trickle(&upload()), "XyZ", &config).unwrap(),
+        ] {
+            assert_eq!(multipart.field("title"), Some("Holiday"));
+            let file = multipart.file("photo").unwrap();
+            assert_eq!(file.filename, "beach \"1\".txt");
+            assert_eq!(file.content_type.as_deref(), Some("text/plain"));
+            assert_eq!(file.size, 18);
+            assert!(file.path().starts_with(&config.temp_dir));
+            let mut contents = String::new();
+            file.open().unwrap().read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, "sand\r\n--Xy and sea");
+
+            let path = file.path().to_path_buf();
+            drop(multipart);
+            assert!(!path.exists());
+        }
+    }
+
+    #[test]
+    fn persists_uploads() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BodyConfig {
+            temp_dir: dir.path().to_path_buf(),
+            ..BodyConfig::default()
+        };
+        let multipart = Multipart::read_from(&upload()[..], "XyZ", &config).unwrap();
+        let file = multipart.into_files().pop().unwrap();
+
+        let kept = dir.path().join("kept.txt");
+        file.persist(&kept).unwrap();
+        assert_eq!(std::fs::read(&kept).unwrap(), b"sand\r\n--Xy and sea");
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = BodyConfig {
+            max_form_bytes: 4,
+            max_json_bytes: 4,
+            max_field_bytes: 4,
+            max_file_bytes: 4,
+            max_parts: 1,
+            ..BodyConfig::default()
+        };
+        let status = |e: BodyError| e.status();
+
+        let form = request("application/x-www-form-urlencoded", b"a=12345");
+        assert_eq!(
+            Form::from_request(&form, &limits).map_err(status),
+            Err(StatusCode::PayloadTooLarge)
+        );
+        let json = request("application/json", b"12345");
+        assert_eq!(
+            Json::<u32>::from_request(&json, &limits).map_err(status),
+            Err(StatusCode::PayloadTooLarge)
+        );
+
+        let multipart = |config: &BodyConfig| {
+            Multipart::read_from(&upload()[..], "XyZ", config)
+                .map(|_| ())
+                .map_err(status)
+        };
+        assert_eq!(multipart(&limits), Err(StatusCode::PayloadTooLarge));
+        for limits in [
+            BodyConfig {
+                max_field_bytes: 4,
+                ..BodyConfig::default()
+            },
+            BodyConfig {
+                max_file_bytes: 17,
+                ..BodyConfig::default()
+            },
+        ] {
+            assert_eq!(multipart(&limits), Err(StatusCode::PayloadTooLarge));
+        }
+        assert_eq!(multipart(&BodyConfig::default()), Ok(()));
+    }
+
+    #[test]
+    fn rejects_the_wrong_content_type() {
+        let config = BodyConfig::default();
+
+        let json = request("text/plain", b"{}");
+        let e = Json::<serde_json::Value>::from_request(&json, &config).unwrap_err();
+        assert_eq!(e.status(), StatusCode::UnsupportedMediaType);
+        assert_eq!(
+            e.to_string(),
+            "expected Content-Type application/json, got text/plain"
+        );
+
+        let mut form = request("", b"a=1");
+        form.headers.clear();
+        let e = Form::from_request(&form, &config).unwrap_err();
+        assert_eq!(e.status(), StatusCode::UnsupportedMediaType);
+
+        let response = Multipart::from_request(&json, &config)
+            .unwrap_err()
+            .to_response();
+        assert_eq!(response.status, StatusCode::UnsupportedMediaType);
+    }
+
+    #[test]
+    fn rejects_malformed_multipart() {
+        let config = BodyConfig::default();
+        let malformed = |content_type: &str, body: &str| {
+            let request = request(content_type, body.as_bytes());
+            Multipart::from_request(&request, &config).unwrap_err()
+        };
+
+        for e in [
+            malformed("multipart/form-data", "--XyZ--\r\n"),
+            malformed(
+                "multipart/form-data; boundary=XyZ",
+                "--XyZ\r\n\r\nnameless\r\n--XyZ--",
+            ),
+            malformed(
+                "multipart/form-data; boundary=XyZ",
+                "--XyZ\r\nContent-Disposition: form-data; name=a\r\n\r\nunfinished",
+            ),
+            malformed("multipart/form-data; boundary=XyZ", "no delimiter at all"),
+        ] {
+            assert_eq!(e.status(), StatusCode::BadRequest, "{e}");
+        }
+    }
+}
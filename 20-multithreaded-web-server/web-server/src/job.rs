@@ -0,0 +1,174 @@
+use std::{
+    any::Any,
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    time::Duration,
+};
+
+/// A flag a running job can poll to find out it is no longer wanted.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Why a job did not produce a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// The job panicked; this is the panic message.
+    Panicked(String),
+    /// The job was cancelled before a worker started it.
+    Cancelled,
+    /// The pool refused the job because its queue was full or it was
+    /// shutting down.
+    Rejected,
+    /// The job was thrown away while queued, or its result was already
+    /// taken by an earlier join.
+    Dropped,
+    /// `join_timeout` gave up waiting. The job may still finish later.
+    TimedOut,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Panicked(message) => write!(f, "job panicked: {message}"),
+            JoinError::Cancelled => write!(f, "job was cancelled"),
+            JoinError::Rejected => write!(f, "job was rejected by the pool"),
+            JoinError::Dropped => write!(f, "job was dropped before it finished"),
+            JoinError::TimedOut => write!(f, "timed out waiting for job"),
+        }
+    }
+}
+
+impl Error for JoinError {}
+
+/// The caller's side of a job started with `ThreadPool::submit`.
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<Result<T, JoinError>>,
+    token: CancellationToken,
+    finished: Arc<AtomicBool>,
+}
+
+/// The worker's side of a job: where its result goes.
+pub(crate) struct JobResult<T> {
+    sender: mpsc::Sender<Result<T, JoinError>>,
+    finished: Arc<AtomicBool>,
+}
+
+impl<T> JobResult<T> {
+    // A second sender, used to report a rejection after the first one was
+    // handed to the pool inside the job.
+    pub(crate) fn duplicate(&self) -> JobResult<T> {
+        JobResult {
+            sender: self.sender.clone(),
+            finished: Arc::clone(&self.finished),
+        }
+    }
+
+    pub(crate) fn send(self, result: Result<T, JoinError>) {
+        // Marked first so a caller that just received the value never sees
+        // the job as unfinished.
+        self.finished.store(true, Ordering::SeqCst);
+        // The caller may have dropped its handle; nobody is left to tell.
+        let _ = self.sender.send(result);
+    }
+}
+
+pub(crate) fn job_pair<T>() -> (JobHandle<T>, JobResult<T>) {
+    let (sender, receiver) = mpsc::channel();
+    let finished = Arc::new(AtomicBool::new(false));
+    let handle = JobHandle {
+        receiver,
+        token: CancellationToken::new(),
+        finished: Arc::clone(&finished),
+    };
+    (handle, JobResult { sender, finished })
+}
+
+impl<T> JobHandle<T> {
+    /// Blocks until the job finishes and returns its value.
+    pub fn join(self) -> Result<T, JoinError> {
+        self.receiver.recv().unwrap_or(Err(JoinError::Dropped))
+    }
+
+    /// Waits at most `timeout` for the job to finish. The value can only be
+    /// taken once; later calls return `JoinError::Dropped`.
+    pub fn join_timeout(&self, timeout: Duration) -> Result<T, JoinError> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(JoinError::TimedOut),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(JoinError::Dropped),
+        }
+    }
+
+    /// Returns true once the job has produced a result, whether a value or
+    /// an error.
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    /// Asks the job to stop. A job that has not started yet is skipped; a
+    /// running job only stops if it checks its token.
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+}
+
+/// The caller's side of a job started with `ThreadPool::execute_after` or
+/// `ThreadPool::execute_every`. Dropping the handle does not cancel the job.
+#[derive(Debug, Clone)]
+pub struct ScheduledHandle {
+    token: CancellationToken,
+}
+
+impl ScheduledHandle {
+    pub(crate) fn new() -> ScheduledHandle {
+        ScheduledHandle {
+            token: CancellationToken::new(),
+        }
+    }
+
+    /// Stops the job from running again. A run that has already started is
+    /// not interrupted unless it checks the token.
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("Box<dyn Any>")
+    }
+}
@@ -1,102 +1,45 @@
-use std::{
-    sync::{mpsc, Arc, Mutex},
-    thread,
+pub mod compression;
+pub mod config;
+pub mod cookie;
+mod date;
+pub mod extract;
+pub mod job;
+pub mod logging;
+pub mod metrics;
+pub mod middleware;
+mod pool;
+pub mod proxy;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod server;
+pub mod session;
+pub mod static_files;
+mod status;
+pub mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod websocket;
+
+pub use compression::{Compression, Encoding};
+pub use config::{Config, ConfigError};
+pub use cookie::{Cookie, Key, SameSite};
+pub use extract::{BodyConfig, BodyError, FilePart, Form, Json, Multipart};
+pub use job::{CancellationToken, JobHandle, JoinError, ScheduledHandle};
+pub use logging::{LogConfig, LogFormat, LogOutput, Logger};
+pub use middleware::{Chain, Middleware, Next};
+pub use pool::{
+    OverflowPolicy, PoolConfig, PoolCreationError, PoolStats, ShutdownReport, ThreadPool,
+    TryExecuteError,
 };
-
-pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
-}
-
-type Job = Box<dyn FnOnce() + Send + 'static>;
-
-/*
-Note: If the operating system can’t create a thread because there aren’t enough system resources, thread::spawn
-will panic. That will cause whole server to panic, even though the creation of some threads might succeed. For
-simplicity’s sake, this behavior is fine, but in a production thread pool implementation, using
-std::thread::Builder and its spawn method that returns Result will be much safer.
-*/
-
-impl ThreadPool {
-    /// Create a new ThreadPool.
-    ///
-    /// The size is the number of threads in the pool.
-    ///
-    /// # Panics
-    ///
-    /// The `new` function will panic if the size is zero.
-    pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
-
-        let (sender, receiver) = mpsc::channel();
-
-        let receiver = Arc::new(Mutex::new(receiver));
-
-        let mut workers = Vec::with_capacity(size);
-
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-        }
-
-        ThreadPool {
-            workers,
-            sender: Some(sender),
-        }
-    }
-
-    pub fn execute<F>(&self, f: F)
-    where
-        F: FnOnce() + Send + 'static,
-    {
-        let job = Box::new(f);
-
-        self.sender.as_ref().unwrap().send(job).unwrap();
-    }
-}
-
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        drop(self.sender.take());
-
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
-
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
-            }
-        }
-    }
-}
-
-struct Worker {
-    id: usize,
-    thread: Option<thread::JoinHandle<()>>,
-}
-
-impl Worker {
-    // ! with let, any temporary values used in the expression on the right hand side of the equals sign are
-    // ! immediately dropped when the let statement ends. However, while let (and if let and match) does not
-    // ! drop temporary values until the end of the associated block.
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
-
-            match message {
-                Ok(job) => {
-                    println!("Worker {id} got a job; executing.");
-
-                    job();
-                }
-                Err(_) => {
-                    println!("Worker {id} disconnected; shutting down.");
-                    break;
-                }
-            }
-        });
-
-        Worker {
-            id,
-            thread: Some(thread),
-        }
-    }
-}
\ No newline at end of file
+pub use proxy::{Balance, Proxy};
+pub use request::{Method, ParseError, Request, Version};
+pub use response::{Body, HeaderMap, Response};
+pub use router::{Params, Router};
+pub use server::{KeepAlive, Limits, Listener, Mode, Server, Shutdown};
+pub use session::{FileStore, MemoryStore, Session, SessionStore, Sessions};
+pub use static_files::StaticFiles;
+pub use status::{StatusCode, UnknownStatus};
+#[cfg(feature = "tls")]
+pub use tls::{TlsConfig, TlsError};
+pub use websocket::{CloseCode, Message, WebSocket, WebSocketError};
@@ -0,0 +1,509 @@
+//! A small leveled logger shared by the access log and the server's own
+//! messages.
+//!
+//! There is one process-wide `Logger`. Until `set_logger` installs another,
+//! messages at `Level::Info` and above go to stdout as plain text. Use the
+//! `error!`, `warn!`, `info!` and `debug!` macros for messages and
+//! `Logger::access` for one line per request.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    hash::{BuildHasher, RandomState},
+    io::{self, Write},
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::date;
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// How important a message is. Ordered from most to least severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Level {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Level, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(format!("unknown log level: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Access entries in Common Log Format followed by the request id,
+    /// worker and duration; other messages as plain text.
+    #[default]
+    Common,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "common" | "clf" => Ok(LogFormat::Common),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {s}")),
+        }
+    }
+}
+
+/// Where log lines go.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum LogOutput {
+    #[default]
+    Stdout,
+    /// Appends to `path`. Once the file would grow past `max_bytes` it is
+    /// renamed to `path.1`, older files move up one number, and anything
+    /// past `path.{keep}` is deleted.
+    File {
+        path: PathBuf,
+        max_bytes: u64,
+        keep: usize,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LogConfig {
+    /// Messages less severe than this are skipped. Access entries are
+    /// logged at `Level::Info`.
+    pub level: Level,
+    pub format: LogFormat,
+    pub output: LogOutput,
+}
+
+/// One request, as recorded in the access log.
+#[derive(Debug, Clone)]
+pub struct AccessEntry<'a> {
+    pub remote: Option<IpAddr>,
+    pub request_id: &'a str,
+    pub method: &'a str,
+    /// The request target, path and query, as the client sent it.
+    pub path: &'a str,
+    pub version: &'a str,
+    pub status: u16,
+    /// Bytes of body sent, not counting the headers.
+    pub bytes: u64,
+    pub duration: Duration,
+    /// The pool worker that served the request, if any.
+    pub worker: Option<usize>,
+}
+
+pub struct Logger {
+    level: Level,
+    format: LogFormat,
+    output: Mutex<Output>,
+}
+
+enum Output {
+    Stdout,
+    File(RotatingFile),
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl Logger {
+    /// Creates a logger from `config`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the log file cannot be opened.
+    pub fn new(config: LogConfig) -> io::Result<Logger> {
+        let output = match config.output {
+            LogOutput::Stdout => Output::Stdout,
+            LogOutput::File {
+                path,
+                max_bytes,
+                keep,
+            } => Output::File(RotatingFile::open(path, max_bytes, keep)?),
+        };
+
+        Ok(Logger {
+            level: config.level,
+            format: config.format,
+            output: Mutex::new(output),
+        })
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        level <= self.level
+    }
+
+    /// Writes a message tagged with its level and the current thread's name.
+    pub fn log(&self, level: Level, args: fmt::Arguments) {
+        if !self.enabled(level) {
+            return;
+        }
+
+        let now = date::format_rfc3339(SystemTime::now());
+        let current = thread::current();
+        let thread = current.name().unwrap_or("-");
+        let line = match self.format {
+            LogFormat::Common => format!("{now} {level:<5} [{thread}] {args}"),
+            LogFormat::Json => format!(
+                r#"{{"time":"{now}","level":"{level}","thread":"{}","message":"{}"}}"#,
+                escape_json(thread),
+                escape_json(&args.to_string())
+            ),
+        };
+        self.write_line(&line);
+    }
+
+    /// Records a finished request.
+    pub fn access(&self, entry: &AccessEntry) {
+        if !self.enabled(Level::Info) {
+            return;
+        }
+
+        let now = SystemTime::now();
+        let remote = entry
+            .remote
+            .map_or_else(|| String::from("-"), |ip| ip.to_string());
+        let worker = entry
+            .worker
+            .map_or_else(|| String::from("-"), |id| id.to_string());
+        let millis = entry.duration.as_secs_f64() * 1000.0;
+        let line = match self.format {
+            LogFormat::Common => format!(
+                r#"{remote} - - [{}] "{} {} {}" {} {} {} worker={worker} {millis:.3}ms"#,
+                date::format_clf_date(now),
+                escape_clf(entry.method),
+                escape_clf(entry.path),
+                entry.version,
+                entry.status,
+                entry.bytes,
+                escape_clf(entry.request_id),
+            ),
+            LogFormat::Json => format!(
+                concat!(
+                    r#"{{"time":"{}","remote":"{}","request_id":"{}","method":"{}","#,
+                    r#""path":"{}","version":"{}","status":{},"bytes":{},"#,
+                    r#""duration_ms":{:.3},"worker":{}}}"#
+                ),
+                date::format_rfc3339(now),
+                remote,
+                escape_json(entry.request_id),
+                escape_json(entry.method),
+                escape_json(entry.path),
+                entry.version,
+                entry.status,
+                entry.bytes,
+                millis,
+                entry
+                    .worker
+                    .map_or_else(|| String::from("null"), |id| id.to_string()),
+            ),
+        };
+        self.write_line(&line);
+    }
+
+    fn write_line(&self, line: &str) {
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        // A logger has nowhere to report its own failures.
+        let _ = match &mut *output {
+            Output::Stdout => writeln!(io::stdout().lock(), "{line}"),
+            Output::File(file) => file.write_line(line),
+        };
+    }
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            written,
+            max_bytes,
+            keep,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.written > 0 && self.written + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{line}")?;
+        self.written += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            // The oldest file may not exist yet, which is fine.
+            let _ = fs::remove_file(numbered(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(numbered(n), numbered(n + 1));
+            }
+            fs::rename(&self.path, numbered(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.written = 0;
+        Ok(())
+    }
+}
+
+/// Installs the process-wide logger. Only the first call succeeds; later
+/// ones hand their logger back.
+pub fn set_logger(logger: Logger) -> Result<(), Logger> {
+    let mut logger = Some(logger);
+    LOGGER.get_or_init(|| logger.take().unwrap());
+    match logger {
+        None => Ok(()),
+        Some(logger) => Err(logger),
+    }
+}
+
+/// Returns the process-wide logger, installing the default one if
+/// `set_logger` was never called.
+pub fn logger() -> &'static Logger {
+    LOGGER.get_or_init(|| Logger {
+        level: Level::Info,
+        format: LogFormat::Common,
+        output: Mutex::new(Output::Stdout),
+    })
+}
+
+/// Returns a new id for a request, unique within this process and unlikely
+/// to repeat across restarts.
+pub fn next_request_id() -> String {
+    static SEED: OnceLock<RandomState> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let hashed = SEED.get_or_init(RandomState::new).hash_one(n);
+    format!("{hashed:016x}")
+}
+
+// Escapes quotes, backslashes and control characters as Apache does, so a
+// client cannot end a quoted field or start a line of its own.
+fn escape_clf(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::logging::logger().log($crate::logging::Level::Error, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::logging::logger().log($crate::logging::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::logging::logger().log($crate::logging::Level::Info, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::logging::logger().log($crate::logging::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("web-server-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn entry() -> AccessEntry<'static> {
+        AccessEntry {
+            remote: Some(IpAddr::from([127, 0, 0, 1])),
+            request_id: "abc123",
+            method: "GET",
+            path: "/say \"hi\"",
+            version: "HTTP/1.1",
+            status: 200,
+            bytes: 42,
+            duration: Duration::from_micros(1500),
+            worker: Some(3),
+        }
+    }
+
+    fn file_logger(path: &Path, format: LogFormat, max_bytes: u64) -> Logger {
+        Logger::new(LogConfig {
+            level: Level::Info,
+            format,
+            output: LogOutput::File {
+                path: path.to_path_buf(),
+                max_bytes,
+                keep: 2,
+            },
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn writes_common_and_json_access_entries() {
+        let path = temp_path("formats.log");
+        file_logger(&path, LogFormat::Common, 1 << 20).access(&entry());
+        file_logger(&path, LogFormat::Json, 1 << 20).access(&entry());
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert!(lines[0].starts_with("127.0.0.1 - - ["));
+        assert!(
+            lines[0].ends_with(r#"] "GET /say \"hi\" HTTP/1.1" 200 42 abc123 worker=3 1.500ms"#)
+        );
+        assert!(lines[1].contains(r#""request_id":"abc123","method":"GET","path":"/say \"hi\"""#));
+        assert!(lines[1].ends_with(r#""status":200,"bytes":42,"duration_ms":1.500,"worker":3}"#));
+    }
+
+    #[test]
+    fn common_entries_cannot_forge_lines() {
+        let path = temp_path("forged.log");
+        let forged = AccessEntry {
+            path: "/\n1.2.3.4 - - [x] \"GET /admin\\ HTTP/1.1\" 200",
+            ..entry()
+        };
+        file_logger(&path, LogFormat::Common, 1 << 20).access(&forged);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        assert!(contents
+            .contains(r#""GET /\x0a1.2.3.4 - - [x] \"GET /admin\\ HTTP/1.1\" 200 HTTP/1.1""#));
+    }
+
+    #[test]
+    fn skips_messages_below_the_level() {
+        let path = temp_path("levels.log");
+        let logger = file_logger(&path, LogFormat::Common, 1 << 20);
+        logger.log(Level::Debug, format_args!("hidden"));
+        logger.log(Level::Warn, format_args!("shown"));
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("hidden"));
+        assert!(contents.contains("WARN  ["));
+        assert!(contents.trim_end().ends_with("shown"));
+    }
+
+    #[test]
+    fn rotates_files_past_the_size_limit() {
+        let path = temp_path("rotate.log");
+        let logger = file_logger(&path, LogFormat::Common, 200);
+        for _ in 0..6 {
+            logger.access(&entry());
+        }
+
+        let rotated = |n| {
+            let mut name = path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        assert!(fs::metadata(&path).unwrap().len() <= 200);
+        assert!(rotated(1).exists());
+        assert!(rotated(2).exists());
+        assert!(!rotated(3).exists());
+    }
+
+    #[test]
+    fn request_ids_are_unique() {
+        let a = next_request_id();
+        let b = next_request_id();
+
+        assert_eq!(a.len(), 16);
+        assert_ne!(a, b);
+    }
+}
@@ -1,56 +1,185 @@
 use std::{
-    io::prelude::*,
-    net::{TcpListener, TcpStream}, fs, time::Duration, thread,
+    env, fs,
+    net::{SocketAddr, TcpListener},
+    path::Path,
+    process, thread,
+    time::Duration,
 };
 
-use web_server::ThreadPool;
+use web_server::{
+    error, info, logging, metrics,
+    middleware::{CatchPanic, Cors, RequestLogger},
+    Chain, Config, Listener, Logger, Proxy, Response, Router, Server, StaticFiles, StatusCode,
+    ThreadPool,
+};
+
+#[cfg(feature = "tls")]
+use web_server::TlsConfig;
+
+fn html(status: StatusCode, path: &Path) -> Response {
+    match fs::read(path) {
+        Ok(contents) => Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(contents),
+        Err(e) => {
+            error!("Failed to read {}: {e}", path.display());
+            Response::new(StatusCode::InternalServerError)
+        }
+    }
+}
 
+fn router(config: &Config, proxy: Option<&Proxy>) -> Router {
+    let root = &config.root;
+    let files = StaticFiles::new(root);
+    let hello = root.join("hello.html");
+    let slow_hello = hello.clone();
+    let not_found = root.join("not-found.html");
 
-fn handle_connection(mut stream: TcpStream) {
-    // let buf_reader = BufReader::new(&mut stream);
-    // let request_line = buf_reader.lines().next().unwrap().unwrap();
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).unwrap();
+    let mut router = Router::new();
+    router
+        .middleware(CatchPanic)
+        .middleware(RequestLogger::default())
+        .middleware(config.compression.clone())
+        .get("/", move |_, _| html(StatusCode::Ok, &hello))
+        // Assets may be loaded from other sites, so they allow any origin.
+        .get(
+            "/static/*path",
+            Chain::new()
+                .with(Cors::default())
+                .wrap(move |request, params| {
+                    files.serve(request, params.get("path").unwrap_or(""))
+                }),
+        )
+        .get("/metrics", |_, _| {
+            Response::new(StatusCode::Ok)
+                .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+                .with_body(metrics::global().encode())
+        })
+        .get("/sleep", move |_, _| {
+            thread::sleep(Duration::from_secs(5));
+            html(StatusCode::Ok, &slow_hello)
+        })
+        .not_found(move |_, _| html(StatusCode::NotFound, &not_found));
 
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
+    // Proxied prefixes come last, so the site's own routes win.
+    if let Some(proxy) = proxy {
+        for prefix in &config.proxy.prefixes {
+            let mut proxy = proxy.clone();
+            if config.proxy.strip_prefix {
+                proxy = proxy.strip_prefix(prefix);
+            }
+            info!("Forwarding {prefix} to {:?}", config.proxy.upstreams);
+            let pattern = format!("{}/*path", prefix.trim_end_matches('/'));
+            router.any(&pattern, move |request, _| proxy.forward(request));
+        }
+    }
+    router
+}
 
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK", "./front-end/hello.html")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(Duration::from_secs(5));
-        ("HTTP/1.1 200 OK", "./front-end/hello.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND", "./front-end/not-found.html")
-    };
+fn bind(addr: &SocketAddr) -> TcpListener {
+    TcpListener::bind(addr).unwrap_or_else(|e| {
+        error!("Failed to listen on {addr}: {e}");
+        process::exit(1);
+    })
+}
 
-    let contents = fs::read_to_string(filename).unwrap();
+// Plain listeners first, then HTTPS ones. With `tls.redirect_http` the plain
+// listeners only send clients on to the first HTTPS address.
+fn listeners(config: &Config) -> Vec<Listener> {
+    let https_port = config.tls.bind.first().map(SocketAddr::port);
+    let mut listeners = Vec::new();
+    for addr in &config.bind {
+        let socket = bind(addr);
+        match https_port.filter(|_| config.tls.redirect_http) {
+            Some(port) => {
+                info!("Redirecting http://{addr} to HTTPS port {port}");
+                listeners.push(Listener::redirect_to_https(socket, port));
+            }
+            None => {
+                info!("Listening on http://{addr}");
+                listeners.push(Listener::plain(socket));
+            }
+        }
+    }
 
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        status_line,
-        contents.len(),
-        contents
-    );
+    #[cfg(feature = "tls")]
+    if !config.tls.bind.is_empty() {
+        // Validation has checked both paths are set.
+        let (Some(cert), Some(key)) = (&config.tls.cert, &config.tls.key) else {
+            unreachable!("validated config without a TLS certificate");
+        };
+        let tls = TlsConfig::from_pem_files(cert, key).unwrap_or_else(|e| {
+            error!("Failed to load TLS certificate: {e}");
+            process::exit(1);
+        });
+        for addr in &config.tls.bind {
+            info!("Listening on https://{addr}");
+            listeners.push(Listener::tls(bind(addr), tls.clone()));
+        }
+    }
 
-    stream.write_all(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+    listeners
 }
 
 // The CRLF(carriage return and line feed) sequence can also be written as \r\n, where \r is a carriage return and \n is a line feed
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", Config::usage());
+        return;
+    }
+    // Nothing can be logged before the config says where logs go.
+    let config = Config::load(args, |name| env::var(name).ok()).unwrap_or_else(|e| {
+        eprintln!("web-server: {e}");
+        process::exit(2);
+    });
+
+    let logger = Logger::new(config.log.clone()).unwrap_or_else(|e| {
+        eprintln!("Failed to set up logging: {e}");
+        process::exit(1);
+    });
+    // Nothing has logged yet, so this is the first logger installed.
+    let _ = logging::set_logger(logger);
+
     // HTTP isn’t normally accepted on this port so our server is unlikely to conflict with any other web server you might have running on your machine
     // nonadministrators can listen only on ports higher than 1023
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
+    let listeners = listeners(&config);
+    let pool = ThreadPool::with_config(config.pool.clone()).unwrap_or_else(|e| {
+        error!("Failed to start thread pool: {e}");
+        process::exit(1);
+    });
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+    // Every prefix shares the upstreams, so one set of health checks covers
+    // them all.
+    let proxy = (!config.proxy.upstreams.is_empty()).then(|| {
+        Proxy::new(config.proxy.upstreams.iter().copied())
+            .balance(config.proxy.balance)
+            .max_response_bytes(config.proxy.max_response_bytes)
+    });
+    if let (Some(proxy), Some(path)) = (&proxy, &config.proxy.health_check) {
+        proxy.health_checks(&pool, path, config.proxy.health_interval);
+    }
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+    let mut listeners = listeners.into_iter();
+    let router = router(&config, proxy.as_ref());
+    let mut server = Server::new(listeners.next().unwrap(), router, pool)
+        .keep_alive(config.keep_alive.clone())
+        .limits(config.limits.clone())
+        .mode(config.mode)
+        .metrics(metrics::global())
+        .drain_timeout(config.drain_timeout);
+    for listener in listeners {
+        server = server.add_listener(listener);
     }
-    println!("Shutting down.");
 
-}
\ No newline at end of file
+    // Ctrl-C sends SIGINT and deploy scripts send SIGTERM; both stop the
+    // accept loop and let in-flight requests finish.
+    let shutdown = server.shutdown_handle();
+    ctrlc::set_handler(move || shutdown.trigger()).expect("Failed to install signal handler");
+
+    let report = server.run().unwrap();
+    info!(
+        "Completed {} jobs ({} panicked), abandoned {}.",
+        report.completed, report.panicked, report.abandoned
+    );
+}
@@ -0,0 +1,653 @@
+//! Counters, gauges and histograms, exposed in the Prometheus text format.
+//!
+//! Metrics are registered once in a `Registry` and then updated with plain
+//! atomic operations. Registration pushes onto an append-only list with a
+//! compare-and-swap, so neither recording nor scraping ever takes a lock.
+//! `global()` is the registry the `web-server` binary serves at `/metrics`;
+//! any crate can register into it:
+//!
+//! ```
+//! use web_server::metrics;
+//!
+//! let jobs = metrics::global().counter_family(
+//!     "doc_jobs_total",
+//!     "Jobs run, by outcome.",
+//!     &["outcome"],
+//! );
+//! jobs.with(&["ok"]).inc();
+//!
+//! let text = metrics::global().encode();
+//! assert!(text.contains("doc_jobs_total{outcome=\"ok\"} 1\n"));
+//! ```
+
+use std::{
+    fmt::Write,
+    ptr,
+    sync::{
+        atomic::{AtomicI64, AtomicPtr, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Latency buckets in seconds, from a millisecond to ten seconds.
+pub const DEFAULT_BUCKETS: [f64; 13] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The registry served by the `web-server` binary.
+pub fn global() -> &'static Registry {
+    static GLOBAL: Registry = Registry::new();
+    &GLOBAL
+}
+
+/// A set of named metrics.
+///
+/// Every registering method panics if `name` is not a valid Prometheus
+/// metric name or is already taken, since both are mistakes in the code
+/// rather than conditions to handle at run time.
+pub struct Registry {
+    collectors: List<Box<dyn Collect>>,
+}
+
+impl Registry {
+    pub const fn new() -> Registry {
+        Registry {
+            collectors: List::new(),
+        }
+    }
+
+    pub fn counter(&self, name: &str, help: &str) -> Arc<Counter> {
+        self.single(name, help, Counter::default())
+    }
+
+    pub fn gauge(&self, name: &str, help: &str) -> Arc<Gauge> {
+        self.single(name, help, Gauge::default())
+    }
+
+    /// Registers a histogram with the given upper bounds, in ascending
+    /// order. A `+Inf` bucket is always added.
+    pub fn histogram(&self, name: &str, help: &str, buckets: &[f64]) -> Arc<Histogram> {
+        self.single(name, help, Histogram::new(buckets))
+    }
+
+    pub fn counter_family(&self, name: &str, help: &str, labels: &[&str]) -> Arc<Family<Counter>> {
+        self.family(name, help, labels, Box::new(Counter::default))
+    }
+
+    pub fn gauge_family(&self, name: &str, help: &str, labels: &[&str]) -> Arc<Family<Gauge>> {
+        self.family(name, help, labels, Box::new(Gauge::default))
+    }
+
+    pub fn histogram_family(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+        buckets: &[f64],
+    ) -> Arc<Family<Histogram>> {
+        let buckets = buckets.to_vec();
+        self.family(
+            name,
+            help,
+            labels,
+            Box::new(move || Histogram::new(&buckets)),
+        )
+    }
+
+    /// Registers a gauge whose value is read from `read` at each scrape, for
+    /// numbers something else already keeps, such as a queue length.
+    pub fn gauge_fn<F>(&self, name: &str, help: &str, read: F)
+    where
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        self.register(Box::new(Sampled {
+            header: Header::new(name, help, "gauge"),
+            read: Box::new(read),
+        }));
+    }
+
+    /// Like `gauge_fn`, for a count that only goes up.
+    pub fn counter_fn<F>(&self, name: &str, help: &str, read: F)
+    where
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        self.register(Box::new(Sampled {
+            header: Header::new(name, help, "counter"),
+            read: Box::new(read),
+        }));
+    }
+
+    /// Renders every metric in the text exposition format, in the order
+    /// they were registered.
+    pub fn encode(&self) -> String {
+        let mut collectors: Vec<_> = self.collectors.iter().collect();
+        collectors.reverse();
+        let mut out = String::new();
+        for collector in collectors {
+            collector.encode(&mut out);
+        }
+        out
+    }
+
+    fn single<M: Metric>(&self, name: &str, help: &str, metric: M) -> Arc<M> {
+        let metric = Arc::new(metric);
+        self.register(Box::new(Single {
+            header: Header::new(name, help, M::KIND),
+            metric: Arc::clone(&metric),
+        }));
+        metric
+    }
+
+    fn family<M: Metric>(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+        new: Box<dyn Fn() -> M + Send + Sync>,
+    ) -> Arc<Family<M>> {
+        for label in labels {
+            assert!(valid_name(label, false), "invalid label name {label:?}");
+        }
+        let family = Arc::new(Family {
+            labels: labels.iter().map(|label| label.to_string()).collect(),
+            children: List::new(),
+            new,
+        });
+        self.register(Box::new(Grouped {
+            header: Header::new(name, help, M::KIND),
+            family: Arc::clone(&family),
+        }));
+        family
+    }
+
+    fn register(&self, collector: Box<dyn Collect>) {
+        let name = collector.header().name.clone();
+        let (_, inserted) = self
+            .collectors
+            .get_or_push(|existing| existing.header().name == name, || collector);
+        assert!(inserted, "metric {name} is already registered");
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new()
+    }
+}
+
+/// A value that only goes up, such as requests served.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down, such as open connections.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn dec(&self) {
+        self.add(-1);
+    }
+
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts observations into buckets by upper bound, such as request
+/// latencies.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    /// One per bound plus `+Inf`. Each counts only its own range; they are
+    /// summed when encoded.
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    /// The bits of an `f64`, updated with compare-and-swap.
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Histogram {
+        assert!(
+            bounds.windows(2).all(|pair| pair[0] < pair[1]),
+            "histogram buckets must be in ascending order"
+        );
+        Histogram {
+            bounds: bounds.to_vec(),
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+
+    /// Records a duration in seconds.
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> f64 {
+        f64::from_bits(self.sum.load(Ordering::Relaxed))
+    }
+}
+
+/// A kind of metric a `Registry` can hold.
+pub trait Metric: Send + Sync + 'static {
+    /// The `# TYPE` of the metric.
+    const KIND: &'static str;
+
+    /// Writes the sample lines for one set of labels, given already
+    /// rendered as `name="value",...`.
+    fn encode(&self, name: &str, labels: &str, out: &mut String);
+}
+
+impl Metric for Counter {
+    const KIND: &'static str = "counter";
+
+    fn encode(&self, name: &str, labels: &str, out: &mut String) {
+        let _ = writeln!(out, "{name}{} {}", braced(labels), self.get());
+    }
+}
+
+impl Metric for Gauge {
+    const KIND: &'static str = "gauge";
+
+    fn encode(&self, name: &str, labels: &str, out: &mut String) {
+        let _ = writeln!(out, "{name}{} {}", braced(labels), self.get());
+    }
+}
+
+impl Metric for Histogram {
+    const KIND: &'static str = "histogram";
+
+    fn encode(&self, name: &str, labels: &str, out: &mut String) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(bound) => format_value(*bound),
+                None => String::from("+Inf"),
+            };
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{separator}le=\"{le}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_sum{} {}",
+            braced(labels),
+            format_value(self.sum())
+        );
+        let _ = writeln!(out, "{name}_count{} {}", braced(labels), self.count());
+    }
+}
+
+/// Metrics of one kind told apart by label values, such as requests by
+/// route and status.
+pub struct Family<M> {
+    labels: Vec<String>,
+    children: List<(Vec<String>, M)>,
+    new: Box<dyn Fn() -> M + Send + Sync>,
+}
+
+impl<M: Metric> Family<M> {
+    /// Returns the metric for `values`, one per label name, creating it the
+    /// first time those values are seen.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of values does not match the label names.
+    pub fn with(&self, values: &[&str]) -> &M {
+        assert_eq!(
+            values.len(),
+            self.labels.len(),
+            "expected values for {:?}",
+            self.labels
+        );
+        let matches = |(existing, _): &(Vec<String>, M)| existing.iter().eq(values.iter());
+        let (found, _) = self.children.get_or_push(matches, || {
+            let values = values.iter().map(|value| value.to_string()).collect();
+            (values, (self.new)())
+        });
+        &found.1
+    }
+}
+
+// The `# HELP` and `# TYPE` lines every metric starts with.
+struct Header {
+    name: String,
+    help: String,
+    kind: &'static str,
+}
+
+impl Header {
+    fn new(name: &str, help: &str, kind: &'static str) -> Header {
+        assert!(valid_name(name, true), "invalid metric name {name:?}");
+        Header {
+            name: name.to_string(),
+            help: help.to_string(),
+            kind,
+        }
+    }
+
+    fn encode(&self, out: &mut String) {
+        let help = self.help.replace('\\', "\\\\").replace('\n', "\\n");
+        let _ = writeln!(out, "# HELP {} {help}", self.name);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.kind);
+    }
+}
+
+trait Collect: Send + Sync {
+    fn header(&self) -> &Header;
+    fn encode(&self, out: &mut String);
+}
+
+struct Single<M> {
+    header: Header,
+    metric: Arc<M>,
+}
+
+impl<M: Metric> Collect for Single<M> {
+    fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn encode(&self, out: &mut String) {
+        self.header.encode(out);
+        self.metric.encode(&self.header.name, "", out);
+    }
+}
+
+struct Grouped<M> {
+    header: Header,
+    family: Arc<Family<M>>,
+}
+
+impl<M: Metric> Collect for Grouped<M> {
+    fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn encode(&self, out: &mut String) {
+        self.header.encode(out);
+        let mut children: Vec<_> = self.family.children.iter().collect();
+        children.reverse();
+        for (values, metric) in children {
+            let labels = self
+                .family
+                .labels
+                .iter()
+                .zip(values)
+                .map(|(name, value)| format!("{name}=\"{}\"", escape_label(value)))
+                .collect::<Vec<_>>()
+                .join(",");
+            metric.encode(&self.header.name, &labels, out);
+        }
+    }
+}
+
+struct Sampled {
+    header: Header,
+    read: Box<dyn Fn() -> f64 + Send + Sync>,
+}
+
+impl Collect for Sampled {
+    fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn encode(&self, out: &mut String) {
+        self.header.encode(out);
+        let _ = writeln!(out, "{} {}", self.header.name, format_value((self.read)()));
+    }
+}
+
+fn braced(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{labels}}}")
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        String::from("NaN")
+    } else if value.is_infinite() {
+        String::from(if value > 0.0 { "+Inf" } else { "-Inf" })
+    } else {
+        value.to_string()
+    }
+}
+
+// Metric names may also contain colons; label names may not.
+fn valid_name(name: &str, metric: bool) -> bool {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_' || (metric && c == ':');
+    name.chars().next().is_some_and(|c| !c.is_ascii_digit()) && name.chars().all(allowed)
+}
+
+// A singly linked list that is only ever pushed onto at the head, so
+// readers can walk it while another thread pushes.
+struct List<T> {
+    head: AtomicPtr<Node<T>>,
+}
+
+struct Node<T> {
+    value: T,
+    next: *mut Node<T>,
+}
+
+// SAFETY: nodes are only freed when the list is dropped, and values are
+// shared across threads only through `&T`.
+unsafe impl<T: Send> Send for List<T> {}
+unsafe impl<T: Send + Sync> Sync for List<T> {}
+
+impl<T> List<T> {
+    const fn new() -> List<T> {
+        List {
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Iterates from the newest value to the oldest.
+    fn iter(&self) -> impl Iterator<Item = &T> {
+        Self::iter_from(self.head.load(Ordering::Acquire))
+    }
+
+    fn iter_from<'a>(mut node: *mut Node<T>) -> impl Iterator<Item = &'a T>
+    where
+        T: 'a,
+    {
+        std::iter::from_fn(move || {
+            // SAFETY: every node reachable from the head stays allocated
+            // until the list is dropped.
+            let current = unsafe { node.as_ref()? };
+            node = current.next;
+            Some(&current.value)
+        })
+    }
+
+    /// Returns the first value for which `matches` holds, or pushes the one
+    /// `make` builds. The flag says whether it was pushed.
+    fn get_or_push(&self, matches: impl Fn(&T) -> bool, make: impl FnOnce() -> T) -> (&T, bool) {
+        let mut make = Some(make);
+        let mut node: Option<Box<Node<T>>> = None;
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            if let Some(found) = Self::iter_from(head).find(|value| matches(value)) {
+                return (found, false);
+            }
+            let mut new = node.take().unwrap_or_else(|| {
+                Box::new(Node {
+                    value: (make.take().unwrap())(),
+                    next: ptr::null_mut(),
+                })
+            });
+            new.next = head;
+            let new = Box::into_raw(new);
+            match self
+                .head
+                .compare_exchange(head, new, Ordering::AcqRel, Ordering::Acquire)
+            {
+                // SAFETY: the node is now owned by the list.
+                Ok(_) => return (unsafe { &(*new).value }, true),
+                // Someone else pushed first; check whether it was the same
+                // value before trying again.
+                Err(_) => node = Some(unsafe { Box::from_raw(new) }),
+            }
+        }
+    }
+}
+
+impl<T> Drop for List<T> {
+    fn drop(&mut self) {
+        let mut node = *self.head.get_mut();
+        while !node.is_null() {
+            // SAFETY: each node was made by `Box::into_raw` and is freed once.
+            let boxed = unsafe { Box::from_raw(node) };
+            node = boxed.next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn encodes_the_text_format() {
+        let registry = Registry::new();
+        let requests = registry.counter("requests_total", "Requests served.");
+        let open = registry.gauge("open", "Open things.");
+        let latency = registry.histogram("latency_seconds", "How long.", &[0.1, 1.0]);
+        registry.gauge_fn("answer", "Line one\nline two.", || 42.0);
+
+        requests.inc_by(3);
+        open.inc();
+        open.inc();
+        open.dec();
+        latency.observe(0.05);
+        latency.observe(0.5);
+        latency.observe(5.0);
+
+        assert_eq!(
+            registry.encode(),
+            "# HELP requests_total Requests served.\n\
+             # TYPE requests_total counter\n\
+             requests_total 3\n\
+             # HELP open Open things.\n\
+             # TYPE open gauge\n\
+             open 1\n\
+             # HELP latency_seconds How long.\n\
+             # TYPE latency_seconds histogram\n\
+             latency_seconds_bucket{le=\"0.1\"} 1\n\
+             latency_seconds_bucket{le=\"1\"} 2\n\
+             latency_seconds_bucket{le=\"+Inf\"} 3\n\
+             latency_seconds_sum 5.55\n\
+             latency_seconds_count 3\n\
+             # HELP answer Line one\\nline two.\n\
+             # TYPE answer gauge\n\
+             answer 42\n"
+        );
+    }
+
+    #[test]
+    fn families_label_and_escape() {
+        let registry = Registry::new();
+        let requests = registry.counter_family("hits_total", "Hits.", &["route", "status"]);
+        let latency = registry.histogram_family("wait_seconds", "Waits.", &["route"], &[1.0]);
+
+        requests.with(&["/a", "200"]).inc();
+        requests.with(&["/a", "200"]).inc();
+        requests.with(&["/\"b\"", "404"]).inc();
+        latency.with(&["/a"]).observe(2.0);
+
+        let text = registry.encode();
+        assert!(text.contains("hits_total{route=\"/a\",status=\"200\"} 2\n"));
+        assert!(text.contains("hits_total{route=\"/\\\"b\\\"\",status=\"404\"} 1\n"));
+        assert!(text.contains("wait_seconds_bucket{route=\"/a\",le=\"1\"} 0\n"));
+        assert!(text.contains("wait_seconds_bucket{route=\"/a\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("wait_seconds_count{route=\"/a\"} 1\n"));
+    }
+
+    #[test]
+    fn counts_from_many_threads_without_losing_updates() {
+        let registry = Registry::new();
+        let family = registry.counter_family("racing_total", "Races.", &["key"]);
+
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for i in 0..1000 {
+                        family.with(&[&(i % 10).to_string()]).inc();
+                    }
+                });
+            }
+        });
+
+        // Threads racing to create the same child end up sharing one.
+        assert_eq!(family.children.iter().count(), 10);
+        for key in 0..10 {
+            assert_eq!(family.with(&[&key.to_string()]).get(), 800);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "already registered")]
+    fn refuses_duplicate_names() {
+        let registry = Registry::new();
+        registry.counter("twice", "");
+        registry.gauge("twice", "");
+    }
+}
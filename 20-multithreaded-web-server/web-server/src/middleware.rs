@@ -0,0 +1,541 @@
+//! Hooks that run around handlers.
+//!
+//! A `Chain` holds middleware in the order they were added. Each request
+//! passes through their `before` hooks in that order, then the handler,
+//! then their `after` hooks in reverse, like layers of an onion. Install a
+//! chain for every route with `Router::middleware`, or wrap a single
+//! handler with `Chain::wrap`:
+//!
+//! ```
+//! use web_server::middleware::{Chain, Cors, Timing};
+//! use web_server::{Response, Router, StatusCode};
+//!
+//! let mut router = Router::new();
+//! router.middleware(Timing).get(
+//!     "/api/hello",
+//!     Chain::new()
+//!         .with(Cors::default())
+//!         .wrap(|_, _| Response::new(StatusCode::Ok).with_body("hello")),
+//! );
+//! ```
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    compression::add_vary,
+    error, job,
+    logging::{self, Level},
+    Method, Params, Request, Response, StatusCode,
+};
+
+/// Code that runs before and after a handler.
+///
+/// Most middleware only implement `before`, `after` or both. Those that need
+/// to keep state across the call, such as a start time, or to catch what
+/// the rest of the chain does, override `handle` instead.
+pub trait Middleware: Send + Sync + 'static {
+    /// Runs before the handler. Returning a response skips the handler and
+    /// every middleware after this one; this middleware's `after` still runs.
+    fn before(&self, _request: &Request) -> Option<Response> {
+        None
+    }
+
+    /// Runs after the handler and may change its response.
+    fn after(&self, _request: &Request, response: Response) -> Response {
+        response
+    }
+
+    /// Runs this middleware around the rest of the chain.
+    fn handle(&self, request: &Request, next: Next) -> Response {
+        let response = match self.before(request) {
+            Some(response) => response,
+            None => next.run(request),
+        };
+        self.after(request, response)
+    }
+}
+
+/// The rest of the chain after the middleware currently running, ending in
+/// the handler.
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    endpoint: &'a dyn Fn(&Request) -> Response,
+}
+
+impl Next<'_> {
+    pub fn run(self, request: &Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(
+                request,
+                Next {
+                    middleware: rest,
+                    endpoint: self.endpoint,
+                },
+            ),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+/// An ordered list of middleware. Cloning a chain is cheap.
+#[derive(Clone, Default)]
+pub struct Chain {
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl Chain {
+    pub fn new() -> Chain {
+        Chain::default()
+    }
+
+    /// Adds `middleware` inside the ones already in the chain.
+    pub fn with<M: Middleware>(mut self, middleware: M) -> Chain {
+        self.push(middleware);
+        self
+    }
+
+    pub fn push<M: Middleware>(&mut self, middleware: M) {
+        self.middleware.push(Arc::new(middleware));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.middleware.is_empty()
+    }
+
+    /// Passes `request` through the chain, with `endpoint` at the end.
+    pub fn run(&self, request: &Request, endpoint: &dyn Fn(&Request) -> Response) -> Response {
+        Next {
+            middleware: &self.middleware,
+            endpoint,
+        }
+        .run(request)
+    }
+
+    /// Wraps `handler` in this chain, giving a handler that can be passed to
+    /// `Router::route` and friends.
+    pub fn wrap<F>(&self, handler: F) -> impl Fn(&Request, &Params) -> Response + Send + Sync
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        let chain = self.clone();
+        move |request: &Request, params: &Params| {
+            chain.run(request, &|request| handler(request, params))
+        }
+    }
+}
+
+/// Logs one line per request with its status and how long it took.
+///
+/// This is meant for following requests through the application while
+/// debugging; the access log is written by the server either way.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLogger {
+    level: Level,
+}
+
+impl RequestLogger {
+    pub fn new(level: Level) -> RequestLogger {
+        RequestLogger { level }
+    }
+}
+
+impl Default for RequestLogger {
+    fn default() -> RequestLogger {
+        RequestLogger::new(Level::Debug)
+    }
+}
+
+impl Middleware for RequestLogger {
+    fn handle(&self, request: &Request, next: Next) -> Response {
+        let started = Instant::now();
+        let response = next.run(request);
+        logging::logger().log(
+            self.level,
+            format_args!(
+                "[{}] {} {} -> {} in {:.3}ms",
+                request.header("x-request-id").unwrap_or("-"),
+                request.method,
+                request.target,
+                response.status,
+                started.elapsed().as_secs_f64() * 1000.0
+            ),
+        );
+        response
+    }
+}
+
+/// Adds a `Server-Timing` header with the time spent in the rest of the
+/// chain, in milliseconds.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timing;
+
+impl Middleware for Timing {
+    fn handle(&self, request: &Request, next: Next) -> Response {
+        let started = Instant::now();
+        let response = next.run(request);
+        let millis = started.elapsed().as_secs_f64() * 1000.0;
+        response.with_header("Server-Timing", &format!("app;dur={millis:.3}"))
+    }
+}
+
+/// Answers `500 Internal Server Error` when the rest of the chain panics,
+/// instead of dropping the connection. Add it first so it covers every
+/// other middleware.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CatchPanic;
+
+impl Middleware for CatchPanic {
+    fn handle(&self, request: &Request, next: Next) -> Response {
+        match panic::catch_unwind(AssertUnwindSafe(move || next.run(request))) {
+            Ok(response) => response,
+            Err(payload) => {
+                error!(
+                    "Handler for {} {} panicked: {}",
+                    request.method,
+                    request.path,
+                    job::panic_message(&*payload)
+                );
+                Response::new(StatusCode::InternalServerError).with_body("Internal Server Error")
+            }
+        }
+    }
+}
+
+/// Rejects requests whose body is larger than `max_bytes` with
+/// `413 Payload Too Large`.
+///
+/// The request has been read by the time middleware runs, so this guards
+/// the handlers rather than the server's memory.
+#[derive(Debug, Clone, Copy)]
+pub struct SizeLimit {
+    pub max_bytes: usize,
+}
+
+impl SizeLimit {
+    pub fn new(max_bytes: usize) -> SizeLimit {
+        SizeLimit { max_bytes }
+    }
+}
+
+impl Middleware for SizeLimit {
+    fn before(&self, request: &Request) -> Option<Response> {
+        let declared = request
+            .header("content-length")
+            .and_then(|length| length.parse::<usize>().ok())
+            .unwrap_or(0);
+        if declared.max(request.body.len()) <= self.max_bytes {
+            return None;
+        }
+        Some(
+            Response::new(StatusCode::PayloadTooLarge)
+                .with_header("Connection", "close")
+                .with_body("Payload Too Large"),
+        )
+    }
+}
+
+/// Cross-origin resource sharing.
+///
+/// Answers preflight `OPTIONS` requests itself and adds the
+/// `Access-Control-*` headers to responses for allowed origins. Requests
+/// from other origins are passed through untouched, which browsers treat
+/// as a refusal.
+///
+/// Preflights only reach a `Cors` wrapped around a single route if that
+/// route is also registered for `OPTIONS`.
+#[derive(Debug, Clone)]
+pub struct Cors {
+    /// Origins such as `https://example.com`. Empty allows every origin.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<Method>,
+    /// Request headers a preflight may ask for. Empty allows whatever the
+    /// browser asks for.
+    pub allowed_headers: Vec<String>,
+    /// Response headers scripts may read beyond the CORS-safelisted ones.
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight answer.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Cors {
+        Cors {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec![Method::Get, Method::Head, Method::Post],
+            allowed_headers: Vec::new(),
+            exposed_headers: vec![String::from("X-Request-Id")],
+            allow_credentials: false,
+            max_age: Some(Duration::from_secs(600)),
+        }
+    }
+}
+
+impl Cors {
+    fn allowed_origin<'a>(&self, request: &'a Request) -> Option<&'a str> {
+        let origin = request.header("origin")?;
+        let allowed = self.allowed_origins.is_empty()
+            || self.allowed_origins.iter().any(|allowed| allowed == origin);
+        allowed.then_some(origin)
+    }
+
+    // Whether the answer depends on the origin. Then every response, even
+    // one without CORS headers, must say so, or a cache could hand it to
+    // an origin that would have been allowed.
+    fn varies(&self) -> bool {
+        !self.allowed_origins.is_empty() || self.allow_credentials
+    }
+
+    // Credentials cannot be combined with the `*` wildcard, so the origin
+    // is echoed back instead.
+    fn with_origin(&self, response: Response, origin: &str) -> Response {
+        let response = if self.varies() {
+            response.with_header("Access-Control-Allow-Origin", origin)
+        } else {
+            response.with_header("Access-Control-Allow-Origin", "*")
+        };
+        if self.allow_credentials {
+            response.with_header("Access-Control-Allow-Credentials", "true")
+        } else {
+            response
+        }
+    }
+}
+
+fn is_preflight(request: &Request) -> bool {
+    request.method == Method::Options
+        && request.header("origin").is_some()
+        && request.header("access-control-request-method").is_some()
+}
+
+impl Middleware for Cors {
+    fn before(&self, request: &Request) -> Option<Response> {
+        if !is_preflight(request) {
+            return None;
+        }
+        let Some(origin) = self.allowed_origin(request) else {
+            return Some(Response::new(StatusCode::NoContent));
+        };
+
+        let methods = self
+            .allowed_methods
+            .iter()
+            .map(|method| method.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let headers = if self.allowed_headers.is_empty() {
+            request
+                .header("access-control-request-headers")
+                .unwrap_or("")
+                .to_string()
+        } else {
+            self.allowed_headers.join(", ")
+        };
+
+        let mut response = Response::new(StatusCode::NoContent)
+            .with_header("Access-Control-Allow-Methods", &methods);
+        if !headers.is_empty() {
+            response = response.with_header("Access-Control-Allow-Headers", &headers);
+        }
+        if let Some(max_age) = self.max_age {
+            response =
+                response.with_header("Access-Control-Max-Age", &max_age.as_secs().to_string());
+        }
+        Some(self.with_origin(response, origin))
+    }
+
+    fn after(&self, request: &Request, mut response: Response) -> Response {
+        if self.varies() {
+            add_vary(&mut response.headers, "Origin");
+        }
+        // Preflight answers already carry their headers.
+        if is_preflight(request) {
+            return response;
+        }
+        let Some(origin) = self.allowed_origin(request) else {
+            return response;
+        };
+
+        let response = self.with_origin(response, origin);
+        if self.exposed_headers.is_empty() {
+            response
+        } else {
+            let exposed = self.exposed_headers.join(", ");
+            response.with_header("Access-Control-Expose-Headers", &exposed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn request(method: Method, headers: &[(&str, &str)]) -> Request {
+        let headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}: {value}\r\n"))
+            .collect();
+        let raw = format!("{method} / HTTP/1.1\r\n{headers}\r\n");
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn ok(_: &Request) -> Response {
+        Response::new(StatusCode::Ok).with_body("ok")
+    }
+
+    struct Record(&'static str, Arc<Mutex<Vec<String>>>);
+
+    impl Middleware for Record {
+        fn before(&self, _: &Request) -> Option<Response> {
+            self.1.lock().unwrap().push(format!("before {}", self.0));
+            None
+        }
+
+        fn after(&self, _: &Request, response: Response) -> Response {
+            self.1.lock().unwrap().push(format!("after {}", self.0));
+            response
+        }
+    }
+
+    #[test]
+    fn runs_like_an_onion() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let chain = Chain::new()
+            .with(Record("outer", Arc::clone(&events)))
+            .with(Record("inner", Arc::clone(&events)));
+
+        chain.run(&request(Method::Get, &[]), &|_| {
+            events.lock().unwrap().push(String::from("handler"));
+            Response::new(StatusCode::Ok)
+        });
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "before outer",
+                "before inner",
+                "handler",
+                "after inner",
+                "after outer"
+            ]
+        );
+    }
+
+    #[test]
+    fn before_can_answer_without_the_handler() {
+        let chain = Chain::new().with(SizeLimit::new(4));
+        let mut big = request(Method::Post, &[]);
+        big.body = b"too long".to_vec();
+
+        let response = chain.run(&big, &|_| panic!("handler should not run"));
+
+        assert_eq!(response.status, 413);
+    }
+
+    #[test]
+    fn catch_panic_answers_500() {
+        let chain = Chain::new().with(CatchPanic).with(Timing);
+
+        let response = chain.run(&request(Method::Get, &[]), &|_| panic!("boom"));
+
+        assert_eq!(response.status, 500);
+    }
+
+    #[test]
+    fn timing_adds_server_timing() {
+        let response = Chain::new()
+            .with(Timing)
+            .run(&request(Method::Get, &[]), &ok);
+
+        assert!(response
+            .header("server-timing")
+            .unwrap()
+            .starts_with("app;dur="));
+    }
+
+    #[test]
+    fn cors_answers_preflights_for_allowed_origins() {
+        let cors = Cors {
+            allowed_origins: vec![String::from("https://app.example")],
+            ..Cors::default()
+        };
+        let chain = Chain::new().with(cors);
+        let preflight = |origin| {
+            request(
+                Method::Options,
+                &[
+                    ("origin", origin),
+                    ("access-control-request-method", "POST"),
+                    ("access-control-request-headers", "content-type"),
+                ],
+            )
+        };
+
+        let allowed = chain.run(&preflight("https://app.example"), &ok);
+        assert_eq!(allowed.status, 204);
+        assert_eq!(
+            allowed.header("access-control-allow-origin"),
+            Some("https://app.example")
+        );
+        assert_eq!(
+            allowed.header("access-control-allow-methods"),
+            Some("GET, HEAD, POST")
+        );
+        assert_eq!(
+            allowed.header("access-control-allow-headers"),
+            Some("content-type")
+        );
+        assert_eq!(allowed.header("vary"), Some("Origin"));
+
+        let refused = chain.run(&preflight("https://evil.example"), &ok);
+        assert_eq!(refused.status, 204);
+        assert_eq!(refused.header("access-control-allow-origin"), None);
+        assert_eq!(refused.header("vary"), Some("Origin"));
+    }
+
+    #[test]
+    fn cors_varies_on_origin_for_every_answer() {
+        let chain = Chain::new().with(Cors {
+            allowed_origins: vec![String::from("https://app.example")],
+            ..Cors::default()
+        });
+        let compressed =
+            |_: &Request| Response::new(StatusCode::Ok).with_header("Vary", "Accept-Encoding");
+        let origins: [&[(&str, &str)]; 3] = [
+            &[("origin", "https://app.example")],
+            &[("origin", "https://evil.example")],
+            &[],
+        ];
+
+        for origin in origins {
+            let response = chain.run(&request(Method::Get, origin), &compressed);
+            let vary: Vec<_> = response.headers.get_all("vary").collect();
+            assert_eq!(vary, ["Accept-Encoding", "Origin"]);
+        }
+    }
+
+    #[test]
+    fn cors_tags_simple_requests() {
+        let chain = Chain::new().with(Cors::default());
+
+        let response = chain.run(
+            &request(Method::Get, &[("origin", "https://any.example")]),
+            &ok,
+        );
+        assert_eq!(response.header("access-control-allow-origin"), Some("*"));
+        assert_eq!(
+            response.header("access-control-expose-headers"),
+            Some("X-Request-Id")
+        );
+
+        assert_eq!(response.header("vary"), None);
+
+        let same_origin = chain.run(&request(Method::Get, &[]), &ok);
+        assert_eq!(same_origin.header("access-control-allow-origin"), None);
+    }
+}
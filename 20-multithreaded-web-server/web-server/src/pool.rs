@@ -0,0 +1,1183 @@
+use std::{
+    error::Error,
+    fmt, io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    debug, error,
+    job::{self, CancellationToken, JobHandle, JoinError, ScheduledHandle},
+    metrics::Registry,
+    warn,
+};
+
+mod scheduler;
+mod timer;
+
+pub(crate) use scheduler::current_worker;
+use scheduler::{Pushed, Scheduler};
+use timer::Timer;
+
+pub struct ThreadPool {
+    inner: Arc<Inner>,
+    timer: Timer,
+}
+
+// The part of the pool the timer thread shares, so that due jobs are
+// submitted the same way as any other.
+struct Inner {
+    workers: Mutex<Vec<Worker>>,
+    scheduler: Arc<Scheduler>,
+    counters: Arc<Counters>,
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Default)]
+struct Counters {
+    submitted: AtomicUsize,
+    completed: AtomicUsize,
+    panicked: AtomicUsize,
+    dropped: AtomicUsize,
+    // Workers whose thread died and has not been replaced yet.
+    dead: AtomicUsize,
+}
+
+/// A snapshot of what the pool's workers are doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Workers running a job or looking for one.
+    pub active: usize,
+    /// Workers parked waiting for a job.
+    pub idle: usize,
+    /// Jobs waiting for a worker.
+    pub queued: usize,
+}
+
+/// What happened to the submitted jobs when the pool was shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Jobs that ran to the end, including the ones that panicked.
+    pub completed: usize,
+    pub panicked: usize,
+    /// Jobs pushed out of a full queue by `OverflowPolicy::DropOldest`.
+    pub dropped: usize,
+    /// Jobs that were still queued or running when the deadline passed.
+    pub abandoned: usize,
+}
+
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// A pool needs at least one worker.
+    ZeroSize,
+    /// `min_workers` is larger than `max_workers`.
+    MinExceedsMax,
+    /// A bounded queue needs room for at least one job.
+    ZeroCapacity,
+    /// The operating system refused to start a worker thread.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroSize => write!(f, "thread pool size must be greater than zero"),
+            PoolCreationError::MinExceedsMax => {
+                write!(f, "minimum worker count exceeds the maximum")
+            }
+            PoolCreationError::ZeroCapacity => {
+                write!(f, "queue capacity must be greater than zero")
+            }
+            PoolCreationError::Spawn(e) => write!(f, "failed to spawn worker thread: {e}"),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::Spawn(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// What `execute` does when the job queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Wait until a worker takes a job off the queue.
+    #[default]
+    Block,
+    /// Hand the job back to the caller.
+    Reject,
+    /// Throw away the job that has waited longest to make room.
+    DropOldest,
+    /// Run the job on the caller's thread, which also slows the caller down.
+    CallerRuns,
+}
+
+/// How a `ThreadPool` is sized and queued.
+///
+/// The pool starts `min_workers` threads. While jobs are waiting and no
+/// worker is free it starts more, up to `max_workers`, and a worker that
+/// finds nothing to do for `idle_timeout` exits again as long as at least
+/// `min_workers` remain.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub min_workers: usize,
+    pub max_workers: usize,
+    pub idle_timeout: Duration,
+    /// How many jobs may wait for a worker. `None` means no limit.
+    pub queue_capacity: Option<usize>,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            min_workers: 4,
+            max_workers: 4,
+            idle_timeout: Duration::from_secs(60),
+            queue_capacity: None,
+            overflow: OverflowPolicy::Block,
+        }
+    }
+}
+
+/// Why `try_execute` did not accept a job. The job is handed back so the
+/// caller can deal with it, for example by answering `503`.
+pub enum TryExecuteError<F> {
+    /// The queue is full and the policy is `OverflowPolicy::Reject`.
+    Full(F),
+    /// The pool is shutting down.
+    ShutDown(F),
+}
+
+impl<F> TryExecuteError<F> {
+    pub fn into_inner(self) -> F {
+        match self {
+            TryExecuteError::Full(f) | TryExecuteError::ShutDown(f) => f,
+        }
+    }
+}
+
+impl<F> fmt::Debug for TryExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryExecuteError::Full(_) => f.write_str("Full(..)"),
+            TryExecuteError::ShutDown(_) => f.write_str("ShutDown(..)"),
+        }
+    }
+}
+
+impl<F> fmt::Display for TryExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryExecuteError::Full(_) => write!(f, "job queue is full"),
+            TryExecuteError::ShutDown(_) => write!(f, "thread pool is shutting down"),
+        }
+    }
+}
+
+impl<F> Error for TryExecuteError<F> {}
+
+impl ThreadPool {
+    /// Create a new ThreadPool.
+    ///
+    /// The size is the number of threads in the pool.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the size is zero or a worker thread
+    /// cannot be spawned. Use `build` to handle those cases instead.
+    pub fn new(size: usize) -> ThreadPool {
+        match ThreadPool::build(size) {
+            Ok(pool) => pool,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Create a new ThreadPool with an unbounded queue, reporting failures
+    /// instead of panicking.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PoolCreationError::ZeroSize` if `size` is zero and
+    /// `PoolCreationError::Spawn` if the operating system cannot start one of
+    /// the threads. Workers that did start are shut down again.
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::with_config(PoolConfig {
+            min_workers: size,
+            max_workers: size,
+            ..PoolConfig::default()
+        })
+    }
+
+    /// Create a new ThreadPool from `config`.
+    ///
+    /// # Errors
+    ///
+    /// The same as `build`, plus `PoolCreationError::ZeroCapacity` for a
+    /// bounded queue that cannot hold a single job and
+    /// `PoolCreationError::MinExceedsMax`. `max_workers` must not be zero,
+    /// but `min_workers` may be.
+    pub fn with_config(config: PoolConfig) -> Result<ThreadPool, PoolCreationError> {
+        if config.max_workers == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+        if config.min_workers > config.max_workers {
+            return Err(PoolCreationError::MinExceedsMax);
+        }
+        if config.queue_capacity == Some(0) {
+            return Err(PoolCreationError::ZeroCapacity);
+        }
+
+        // One slot per possible worker; empty slots have no thread.
+        let workers = (0..config.max_workers)
+            .map(|id| Worker { id, thread: None })
+            .collect();
+        let mut pool = ThreadPool {
+            inner: Arc::new(Inner {
+                workers: Mutex::new(workers),
+                scheduler: Arc::new(Scheduler::new(&config)),
+                counters: Arc::new(Counters::default()),
+            }),
+            timer: Timer::new(),
+        };
+
+        for _ in 0..config.min_workers {
+            // Dropping the half-built pool joins the workers that started.
+            let spawned = pool
+                .inner
+                .spawn_worker(&mut pool.inner.workers.lock().unwrap());
+            if let Some(Err(e)) = spawned {
+                return Err(PoolCreationError::Spawn(e));
+            }
+        }
+
+        let inner = Arc::clone(&pool.inner);
+        pool.timer
+            .start(move |job| inner.try_execute(job).map_err(TryExecuteError::into_inner))
+            .map_err(PoolCreationError::Spawn)?;
+
+        Ok(pool)
+    }
+
+    /// Queues `f` to run on a worker, following the overflow policy when
+    /// the queue is full.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the queue rejects the job under `OverflowPolicy::Reject`.
+    /// Use `try_execute` to get the job back instead.
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(e) = self.try_execute(f) {
+            panic!("{e}");
+        }
+    }
+
+    /// Like `execute`, but hands the job back instead of panicking when the
+    /// queue rejects it.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), TryExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.inner.try_execute(f)
+    }
+
+    /// Runs `f` on a worker once `delay` has passed.
+    ///
+    /// Until then the job waits in the pool's timer, not in the queue, so it
+    /// does not count against `queue_capacity`. If the queue refuses it when
+    /// it is due, it is dropped.
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> ScheduledHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let handle = ScheduledHandle::new();
+        self.timer.after(delay, handle.token().clone(), Box::new(f));
+        handle
+    }
+
+    /// Runs `f` on a worker every `interval`, starting one interval from now,
+    /// until the handle is cancelled or the pool shuts down.
+    ///
+    /// The interval is measured from the end of one run to the start of the
+    /// next, so runs never overlap. A run that panics is counted like any
+    /// other job and does not stop the ones after it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn execute_every<F>(&self, interval: Duration, f: F) -> ScheduledHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        assert!(!interval.is_zero(), "interval must be greater than zero");
+
+        let handle = ScheduledHandle::new();
+        self.timer
+            .every(interval, handle.token().clone(), Arc::new(f));
+        handle
+    }
+
+    /// Runs `f` on a worker and returns a handle to wait for its value.
+    ///
+    /// The job gets a `CancellationToken` it can check to stop early. A panic
+    /// inside the job comes back from `join` as `JoinError::Panicked`, and a
+    /// job the queue refuses comes back as `JoinError::Rejected`.
+    pub fn submit<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce(&CancellationToken) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (handle, result) = job::job_pair();
+        let rejected = result.duplicate();
+        let token = handle.token().clone();
+        let counters = Arc::clone(&self.inner.counters);
+
+        let job = move || {
+            if token.is_cancelled() {
+                result.send(Err(JoinError::Cancelled));
+                return;
+            }
+            match panic::catch_unwind(AssertUnwindSafe(|| f(&token))) {
+                Ok(value) => result.send(Ok(value)),
+                Err(payload) => {
+                    counters.panicked.fetch_add(1, Ordering::SeqCst);
+                    result.send(Err(JoinError::Panicked(job::panic_message(&*payload))));
+                }
+            }
+        };
+
+        if self.try_execute(job).is_err() {
+            rejected.send(Err(JoinError::Rejected));
+        }
+        handle
+    }
+
+    /// Returns how many jobs are waiting for a worker.
+    pub fn queued_jobs(&self) -> usize {
+        self.inner.scheduler.len()
+    }
+
+    /// Returns how many workers are busy, how many are idle, and how many
+    /// jobs are waiting. The numbers are read separately, so they may be
+    /// slightly out of step while the pool is busy.
+    pub fn stats(&self) -> PoolStats {
+        let scheduler = &self.inner.scheduler;
+        let live = scheduler.live_workers();
+        let idle = scheduler.idle_workers().min(live);
+        PoolStats {
+            active: live - idle,
+            idle,
+            queued: scheduler.len(),
+        }
+    }
+
+    /// Returns how many jobs have panicked since the pool was created.
+    pub fn panicked_jobs(&self) -> usize {
+        self.inner.counters.panicked.load(Ordering::SeqCst)
+    }
+
+    /// Exposes the pool's workers, queue and job counts through `registry`,
+    /// read afresh at every scrape. The names start with `prefix`, so more
+    /// than one pool can share a registry.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a pool with the same prefix is already registered.
+    pub fn register_metrics(&self, registry: &Registry, prefix: &str) {
+        let live_and_idle = |scheduler: &Scheduler| {
+            let live = scheduler.live_workers();
+            (live, scheduler.idle_workers().min(live))
+        };
+        let scheduler = Arc::clone(&self.inner.scheduler);
+        registry.gauge_fn(
+            &format!("{prefix}_busy_workers"),
+            "Workers running a job or looking for one.",
+            move || {
+                let (live, idle) = live_and_idle(&scheduler);
+                (live - idle) as f64
+            },
+        );
+        let scheduler = Arc::clone(&self.inner.scheduler);
+        registry.gauge_fn(
+            &format!("{prefix}_idle_workers"),
+            "Workers parked waiting for a job.",
+            move || live_and_idle(&scheduler).1 as f64,
+        );
+        let scheduler = Arc::clone(&self.inner.scheduler);
+        registry.gauge_fn(
+            &format!("{prefix}_queued_jobs"),
+            "Jobs waiting for a worker.",
+            move || scheduler.len() as f64,
+        );
+        let counters = Arc::clone(&self.inner.counters);
+        registry.counter_fn(
+            &format!("{prefix}_completed_jobs_total"),
+            "Jobs that ran to the end, including the ones that panicked.",
+            move || counters.completed.load(Ordering::Relaxed) as f64,
+        );
+        let counters = Arc::clone(&self.inner.counters);
+        registry.counter_fn(
+            &format!("{prefix}_panicked_jobs_total"),
+            "Jobs that panicked.",
+            move || counters.panicked.load(Ordering::Relaxed) as f64,
+        );
+    }
+
+    /// Stops accepting jobs and waits up to `timeout` for the queued and
+    /// running ones to finish.
+    ///
+    /// Workers still busy at the deadline are detached rather than joined,
+    /// and anything they had not started yet is dropped, as are delayed and
+    /// recurring jobs that were not due yet.
+    pub fn shutdown(mut self, timeout: Duration) -> ShutdownReport {
+        let inner = Arc::clone(&self.inner);
+        inner.scheduler.close();
+        self.timer.stop();
+
+        let mut workers = inner.workers.lock().unwrap();
+        let deadline = Instant::now() + timeout;
+        while !workers.iter().all(Worker::is_finished) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        inner.scheduler.clear();
+
+        for worker in workers.iter_mut() {
+            if worker.is_finished() {
+                if let Some(thread) = worker.thread.take() {
+                    // A dead worker already reported its panic.
+                    let _ = thread.join();
+                }
+            } else {
+                warn!("Abandoning worker {}", worker.id);
+                worker.thread.take();
+            }
+        }
+
+        let counters = &inner.counters;
+        let completed = counters.completed.load(Ordering::SeqCst);
+        let dropped = counters.dropped.load(Ordering::SeqCst);
+        ShutdownReport {
+            completed,
+            panicked: counters.panicked.load(Ordering::SeqCst),
+            dropped,
+            abandoned: counters.submitted.load(Ordering::SeqCst) - completed - dropped,
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Closing first frees a timer thread blocked on a full queue.
+        self.inner.scheduler.close();
+        self.timer.stop();
+
+        for worker in self.inner.workers.lock().unwrap().iter_mut() {
+            if let Some(thread) = worker.thread.take() {
+                debug!("Shutting down worker {}", worker.id);
+
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn try_execute<F>(&self, f: F) -> Result<(), TryExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.supervise();
+
+        let pushed = self.scheduler.push(f)?;
+        self.grow();
+
+        match pushed {
+            Pushed::Queued => {}
+            Pushed::DroppedOldest => {
+                self.counters.dropped.fetch_add(1, Ordering::SeqCst);
+            }
+            Pushed::Refused(f) => {
+                // CallerRuns: the caller does the work itself.
+                self.counters.submitted.fetch_add(1, Ordering::SeqCst);
+                f();
+                self.counters.completed.fetch_add(1, Ordering::SeqCst);
+                return Ok(());
+            }
+        }
+        self.counters.submitted.fetch_add(1, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    // Job panics are caught inside the worker, but a worker can still die,
+    // for example if printing to a closed stdout panics. Replace any dead
+    // worker so the pool keeps its size.
+    // The check is a single atomic load, so execute stays cheap.
+    fn supervise(&self) {
+        if self.counters.dead.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+
+        let mut workers = self.workers.lock().unwrap();
+        while self.counters.dead.load(Ordering::SeqCst) > 0 {
+            match self.spawn_worker(&mut workers) {
+                Some(Ok(id)) => {
+                    warn!("A worker died; respawned as worker {id}.");
+                    self.counters.dead.fetch_sub(1, Ordering::SeqCst);
+                }
+                Some(Err(e)) => {
+                    error!("Failed to respawn a dead worker: {e}");
+                    break;
+                }
+                // The dead thread has not finished unwinding; try again on
+                // the next job.
+                None => break,
+            }
+        }
+    }
+
+    // Starts another worker while jobs are waiting and no worker is free to
+    // take them. A pool already at its maximum size only pays for an
+    // atomic load.
+    fn grow(&self) {
+        if self.scheduler.live_workers() >= self.scheduler.max_workers()
+            || self.scheduler.len() <= self.scheduler.idle_workers()
+        {
+            return;
+        }
+
+        let mut workers = self.workers.lock().unwrap();
+        if let Some(Err(e)) = self.spawn_worker(&mut workers) {
+            error!("Failed to spawn an extra worker: {e}");
+        }
+    }
+
+    // Starts a worker in the first free slot and returns its id, or None if
+    // the pool is at its maximum size or no slot has been vacated yet.
+    fn spawn_worker(&self, workers: &mut [Worker]) -> Option<io::Result<usize>> {
+        if !self.scheduler.reserve_worker() {
+            return None;
+        }
+        // The retired flag is cleared first, so a finished slot cannot keep
+        // one that would wrongly mark its next worker as retired.
+        let Some(slot) = workers
+            .iter_mut()
+            .find(|worker| self.scheduler.take_retired(worker.id) || worker.is_finished())
+        else {
+            self.scheduler.release_worker();
+            return None;
+        };
+        if let Some(thread) = slot.thread.take() {
+            // A dead worker already reported its panic, and a retired one
+            // is only returning from its thread.
+            let _ = thread.join();
+        }
+
+        let scheduler = Arc::clone(&self.scheduler);
+        let counters = Arc::clone(&self.counters);
+        match Worker::new(slot.id, scheduler, counters) {
+            Ok(worker) => {
+                *slot = worker;
+                Some(Ok(slot.id))
+            }
+            Err(e) => {
+                self.scheduler.release_worker();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+struct Worker {
+    id: usize,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, scheduler: Arc<Scheduler>, counters: Arc<Counters>) -> io::Result<Worker> {
+        let builder = thread::Builder::new().name(format!("web-server-worker-{id}"));
+        let thread = builder.spawn(move || {
+            let _guard = DeathGuard(&scheduler, &counters);
+            scheduler.enter(id);
+            Worker::run(id, &scheduler, &counters);
+        })?;
+
+        Ok(Worker {
+            id,
+            thread: Some(thread),
+        })
+    }
+
+    fn run(id: usize, scheduler: &Scheduler, counters: &Counters) {
+        loop {
+            match scheduler.pop(id) {
+                Some(job) => {
+                    debug!("Worker {id} got a job; executing.");
+
+                    // A panicking job must not take the worker down with it.
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        warn!("Worker {id} caught a panicking job.");
+                        counters.panicked.fetch_add(1, Ordering::SeqCst);
+                    }
+                    counters.completed.fetch_add(1, Ordering::SeqCst);
+                }
+                None if scheduler.is_closed() => {
+                    debug!("Worker {id} disconnected; shutting down.");
+                    break;
+                }
+                None => {
+                    debug!("Worker {id} was idle; retiring.");
+                    break;
+                }
+            }
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_none_or(|thread| thread.is_finished())
+    }
+}
+
+// Counts a worker thread as dead if it unwinds, so the pool knows to
+// replace it.
+struct DeathGuard<'a>(&'a Scheduler, &'a Counters);
+
+impl Drop for DeathGuard<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.release_worker();
+            self.1.dead.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Barrier};
+
+    #[test]
+    fn shutdown_drains_queued_jobs() {
+        let pool = ThreadPool::new(2);
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..8 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(10));
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        let report = pool.shutdown(Duration::from_secs(5));
+
+        assert_eq!(
+            report,
+            ShutdownReport {
+                completed: 8,
+                panicked: 0,
+                dropped: 0,
+                abandoned: 0
+            }
+        );
+        assert_eq!(ran.load(Ordering::SeqCst), 8);
+    }
+
+    #[test]
+    fn build_rejects_zero_size() {
+        assert!(matches!(
+            ThreadPool::build(0),
+            Err(PoolCreationError::ZeroSize)
+        ));
+    }
+
+    #[test]
+    fn workers_are_named() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || {
+            let name = thread::current().name().map(String::from);
+            sender.send(name).unwrap();
+        });
+
+        assert_eq!(
+            receiver.recv().unwrap().as_deref(),
+            Some("web-server-worker-0")
+        );
+    }
+
+    #[test]
+    fn panicking_jobs_do_not_shrink_the_pool() {
+        let pool = ThreadPool::build(1).unwrap();
+        pool.execute(|| panic!("job failed"));
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(pool.panicked_jobs(), 1);
+    }
+
+    #[test]
+    fn dead_workers_are_respawned() {
+        let pool = ThreadPool::build(1).unwrap();
+        // Simulate a worker that died outside of a job.
+        pool.inner.scheduler.release_worker();
+        pool.inner.counters.dead.fetch_add(1, Ordering::SeqCst);
+        pool.inner.workers.lock().unwrap()[0] = Worker {
+            id: 0,
+            thread: None,
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn shutdown_abandons_jobs_past_the_deadline() {
+        let pool = ThreadPool::new(1);
+        for _ in 0..3 {
+            pool.execute(|| thread::sleep(Duration::from_millis(300)));
+        }
+
+        let report = pool.shutdown(Duration::from_millis(50));
+
+        assert_eq!(
+            report,
+            ShutdownReport {
+                completed: 0,
+                panicked: 0,
+                dropped: 0,
+                abandoned: 3
+            }
+        );
+    }
+
+    // Occupies the only worker until the returned sender is dropped.
+    fn block_worker(pool: &ThreadPool) -> mpsc::Sender<()> {
+        let (release, wait) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = wait.recv();
+        });
+        running.recv().unwrap();
+        release
+    }
+
+    fn bounded(overflow: OverflowPolicy) -> ThreadPool {
+        ThreadPool::with_config(PoolConfig {
+            min_workers: 1,
+            max_workers: 1,
+            queue_capacity: Some(1),
+            overflow,
+            ..PoolConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn reject_hands_the_job_back() {
+        let pool = bounded(OverflowPolicy::Reject);
+        let release = block_worker(&pool);
+        pool.try_execute(|| {}).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let rejected = pool.try_execute(move || sender.send(42).unwrap());
+
+        assert!(matches!(rejected, Err(TryExecuteError::Full(_))));
+        rejected.unwrap_err().into_inner()();
+        assert_eq!(receiver.recv().unwrap(), 42);
+        drop(release);
+    }
+
+    #[test]
+    fn drop_oldest_makes_room() {
+        let pool = bounded(OverflowPolicy::DropOldest);
+        let release = block_worker(&pool);
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..3 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap());
+        }
+        drop(release);
+
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(2));
+        let report = pool.shutdown(Duration::from_secs(5));
+        assert_eq!(report.dropped, 2);
+        assert_eq!(report.abandoned, 0);
+    }
+
+    #[test]
+    fn caller_runs_when_full() {
+        let pool = bounded(OverflowPolicy::CallerRuns);
+        let release = block_worker(&pool);
+        pool.execute(|| {});
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(thread::current().id()).unwrap());
+
+        assert_eq!(receiver.recv().unwrap(), thread::current().id());
+        drop(release);
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let pool = Arc::new(bounded(OverflowPolicy::Block));
+        let release = block_worker(&pool);
+        pool.execute(|| {});
+
+        let (sender, receiver) = mpsc::channel();
+        let blocked_pool = Arc::clone(&pool);
+        let caller = thread::spawn(move || {
+            blocked_pool.execute(|| {});
+            sender.send(()).unwrap();
+        });
+
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(release);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        caller.join().unwrap();
+    }
+
+    #[test]
+    fn try_execute_fails_after_shutdown_starts() {
+        let pool = ThreadPool::new(1);
+        pool.inner.scheduler.close();
+
+        assert!(matches!(
+            pool.try_execute(|| {}),
+            Err(TryExecuteError::ShutDown(_))
+        ));
+    }
+
+    #[test]
+    fn submit_returns_the_value() {
+        let pool = ThreadPool::new(2);
+        let handle = pool.submit(|_| 6 * 7);
+
+        assert_eq!(handle.join(), Ok(42));
+    }
+
+    #[test]
+    fn submit_reports_panics() {
+        let pool = ThreadPool::new(1);
+        let handle = pool.submit(|_| -> u32 { panic!("boom") });
+
+        assert_eq!(
+            handle.join(),
+            Err(JoinError::Panicked(String::from("boom")))
+        );
+        assert_eq!(pool.panicked_jobs(), 1);
+    }
+
+    #[test]
+    fn join_timeout_and_is_finished() {
+        let pool = ThreadPool::new(1);
+        let (release, wait) = mpsc::channel::<()>();
+        let handle = pool.submit(move |_| {
+            let _ = wait.recv();
+            "done"
+        });
+
+        assert_eq!(
+            handle.join_timeout(Duration::from_millis(20)),
+            Err(JoinError::TimedOut)
+        );
+        assert!(!handle.is_finished());
+        drop(release);
+        assert_eq!(handle.join_timeout(Duration::from_secs(5)), Ok("done"));
+        assert!(handle.is_finished());
+    }
+
+    #[test]
+    fn cancellation_is_cooperative() {
+        let pool = ThreadPool::new(1);
+        let release = block_worker(&pool);
+        let queued = pool.submit(|_| ());
+        queued.cancel();
+        let running = pool.submit(|token| {
+            while !token.is_cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            "stopped"
+        });
+        drop(release);
+
+        assert_eq!(queued.join(), Err(JoinError::Cancelled));
+        thread::sleep(Duration::from_millis(20));
+        running.cancel();
+        assert_eq!(running.join(), Ok("stopped"));
+    }
+
+    #[test]
+    fn rejected_submissions_fail_to_join() {
+        let pool = bounded(OverflowPolicy::Reject);
+        let release = block_worker(&pool);
+        pool.execute(|| {});
+
+        assert_eq!(pool.submit(|_| ()).join(), Err(JoinError::Rejected));
+        drop(release);
+    }
+
+    #[test]
+    fn jobs_spawned_by_workers_run_on_the_pool() {
+        let pool = Arc::new(ThreadPool::new(4));
+        let (sender, receiver) = mpsc::channel();
+
+        for _ in 0..4 {
+            let inner_pool = Arc::clone(&pool);
+            let sender = sender.clone();
+            pool.execute(move || {
+                for _ in 0..25 {
+                    let sender = sender.clone();
+                    inner_pool.execute(move || sender.send(()).unwrap());
+                }
+            });
+        }
+        drop(sender);
+
+        for _ in 0..100 {
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+    }
+
+    #[test]
+    fn idle_workers_steal_from_a_busy_one() {
+        let pool = Arc::new(ThreadPool::new(2));
+        let (sender, receiver) = mpsc::channel();
+        let inner_pool = Arc::clone(&pool);
+
+        // The spawning job keeps its worker busy, so the other worker has to
+        // steal the child job from its deque.
+        pool.execute(move || {
+            inner_pool.execute(move || sender.send(thread::current().id()).unwrap());
+            thread::sleep(Duration::from_millis(200));
+        });
+        let child = receiver.recv_timeout(Duration::from_millis(150)).unwrap();
+
+        assert_ne!(child, thread::current().id());
+    }
+
+    fn elastic(min_workers: usize, max_workers: usize) -> ThreadPool {
+        ThreadPool::with_config(PoolConfig {
+            min_workers,
+            max_workers,
+            idle_timeout: Duration::from_millis(50),
+            ..PoolConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn with_config_rejects_min_above_max() {
+        let config = PoolConfig {
+            min_workers: 3,
+            max_workers: 2,
+            ..PoolConfig::default()
+        };
+
+        assert!(matches!(
+            ThreadPool::with_config(config),
+            Err(PoolCreationError::MinExceedsMax)
+        ));
+    }
+
+    #[test]
+    fn stats_counts_active_idle_and_queued() {
+        let pool = ThreadPool::new(2);
+        let release = block_worker(&pool);
+        pool.execute(|| {});
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                active: 1,
+                idle: 1,
+                queued: 0
+            }
+        );
+        drop(release);
+
+        let pool = ThreadPool::new(1);
+        let release = block_worker(&pool);
+        pool.execute(|| {});
+        pool.execute(|| {});
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                active: 1,
+                idle: 0,
+                queued: 2
+            }
+        );
+        drop(release);
+    }
+
+    #[test]
+    fn grows_while_jobs_are_waiting() {
+        let pool = elastic(1, 3);
+        // Only completes if three jobs run at the same time.
+        let barrier = Arc::new(Barrier::new(3));
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..3 {
+            let barrier = Arc::clone(&barrier);
+            let sender = sender.clone();
+            pool.execute(move || {
+                barrier.wait();
+                sender.send(()).unwrap();
+            });
+        }
+
+        for _ in 0..3 {
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        let stats = pool.stats();
+        assert_eq!(stats.active + stats.idle, 3);
+    }
+
+    #[test]
+    fn idle_workers_retire_down_to_the_minimum() {
+        let pool = elastic(1, 3);
+        let barrier = Arc::new(Barrier::new(4));
+        for _ in 0..3 {
+            let barrier = Arc::clone(&barrier);
+            pool.execute(move || {
+                barrier.wait();
+            });
+        }
+        barrier.wait();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.inner.scheduler.live_workers() > 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(pool.inner.scheduler.live_workers(), 1);
+
+        // A retired worker's slot can be filled again.
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn a_job_pushed_as_the_last_worker_retires_still_runs() {
+        let pool = elastic(0, 1);
+        assert_eq!(pool.submit(|_| ()).join(), Ok(()));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !pool.inner.workers.lock().unwrap()[0].is_finished() {
+            assert!(Instant::now() < deadline, "worker never retired");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Stands in for the retired worker's thread while it is still
+        // returning, which is when a new job used to find no free slot.
+        pool.inner.workers.lock().unwrap()[0].thread =
+            Some(thread::spawn(|| thread::sleep(Duration::from_millis(50))));
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn a_pool_with_no_minimum_starts_a_worker_on_demand() {
+        let pool = elastic(0, 1);
+        assert_eq!(pool.inner.scheduler.live_workers(), 0);
+
+        assert_eq!(pool.submit(|_| "ran").join(), Ok("ran"));
+    }
+
+    #[test]
+    fn execute_after_waits_for_the_delay() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+        let scheduled = Instant::now();
+        pool.execute_after(Duration::from_millis(100), move || sender.send(()).unwrap());
+
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(scheduled.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn delayed_jobs_run_in_deadline_order() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+        for (delay, label) in [(60, "c"), (20, "a"), (40, "b")] {
+            let sender = sender.clone();
+            pool.execute_after(Duration::from_millis(delay), move || {
+                sender.send(label).unwrap();
+            });
+        }
+
+        let order: Vec<_> = (0..3)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        assert_eq!(order, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn cancelled_delayed_jobs_never_run() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+        let handle =
+            pool.execute_after(Duration::from_millis(50), move || sender.send(()).unwrap());
+        handle.cancel();
+
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn execute_every_repeats_until_cancelled() {
+        let pool = ThreadPool::new(2);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&runs);
+        let handle = pool.execute_every(Duration::from_millis(10), move || {
+            if counted.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("first run fails");
+            }
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while runs.load(Ordering::SeqCst) < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        handle.cancel();
+        thread::sleep(Duration::from_millis(30));
+        let after_cancel = runs.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+
+        assert!(after_cancel >= 3);
+        assert_eq!(runs.load(Ordering::SeqCst), after_cancel);
+        assert_eq!(pool.panicked_jobs(), 1);
+    }
+
+    #[test]
+    fn shutdown_discards_jobs_that_are_not_due() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+        let later = sender.clone();
+        pool.execute_after(Duration::from_secs(60), move || later.send(()).unwrap());
+        pool.execute_every(Duration::from_secs(60), move || sender.send(()).unwrap());
+
+        let report = pool.shutdown(Duration::from_secs(5));
+
+        assert_eq!(report.abandoned, 0);
+        assert!(receiver.recv().is_err());
+    }
+}
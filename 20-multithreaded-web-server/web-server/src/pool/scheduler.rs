@@ -0,0 +1,323 @@
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    mem,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use super::{Job, OverflowPolicy, PoolConfig, TryExecuteError};
+
+// Sleeping workers wake up this often even without a notification, so a
+// missed wakeup can only ever cost this much latency.
+const PARK_TIMEOUT: Duration = Duration::from_millis(100);
+
+thread_local! {
+    // The scheduler (by address) and deque index of the worker running on
+    // this thread, if any.
+    static CURRENT: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+/// Returns the index of the pool worker running on this thread, if any.
+pub(crate) fn current_worker() -> Option<usize> {
+    CURRENT.with(|current| current.get().map(|(_, index)| index))
+}
+
+pub(crate) enum Pushed<F> {
+    Queued,
+    DroppedOldest,
+    /// The queue was full and the policy is `CallerRuns`.
+    Refused(F),
+}
+
+/// Hands jobs to workers.
+///
+/// Jobs submitted from outside the pool go into a shared injector queue,
+/// which is the one that is bounded by the overflow policy. Jobs submitted
+/// by a job that is already running on a worker go into that worker's own
+/// deque instead. A worker pops its own deque newest-first, then takes from
+/// the injector, and when both are empty steals the oldest job from another
+/// worker's deque. Contention is spread over many small locks instead of
+/// every worker fighting over one receiver.
+///
+/// There is one deque per worker slot up to the pool's maximum size. The
+/// scheduler also keeps count of the live workers, so a worker that has
+/// been idle for too long can retire without taking the pool below its
+/// minimum.
+pub(crate) struct Scheduler {
+    injector: Mutex<Injector>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: Option<usize>,
+    overflow: OverflowPolicy,
+    locals: Vec<Mutex<VecDeque<Job>>>,
+    // Jobs in all local deques; may briefly run ahead of the deques.
+    local_count: AtomicUsize,
+    sleeping: AtomicUsize,
+    live: AtomicUsize,
+    // Set for a slot whose worker has retired. It is set under the injector
+    // lock, so a job pushed after the worker left always finds the slot
+    // free, even while the worker's thread is still winding down.
+    retired: Vec<AtomicBool>,
+    min_workers: usize,
+    idle_timeout: Duration,
+    // Mirrors Injector::closed so local pushes need not take its lock.
+    closed: AtomicBool,
+}
+
+struct Injector {
+    jobs: VecDeque<Job>,
+    closed: bool,
+}
+
+impl Scheduler {
+    pub(crate) fn new(config: &PoolConfig) -> Scheduler {
+        Scheduler {
+            injector: Mutex::new(Injector {
+                jobs: VecDeque::new(),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity: config.queue_capacity,
+            overflow: config.overflow,
+            locals: (0..config.max_workers)
+                .map(|_| Mutex::new(VecDeque::new()))
+                .collect(),
+            local_count: AtomicUsize::new(0),
+            sleeping: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+            retired: (0..config.max_workers)
+                .map(|_| AtomicBool::new(false))
+                .collect(),
+            min_workers: config.min_workers,
+            idle_timeout: config.idle_timeout,
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Marks the current thread as the worker that owns deque `index`.
+    pub(crate) fn enter(self: &Arc<Scheduler>, index: usize) {
+        let id = Arc::as_ptr(self) as usize;
+        CURRENT.with(|current| current.set(Some((id, index))));
+    }
+
+    fn current_index(&self) -> Option<usize> {
+        let id = self as *const Scheduler as usize;
+        CURRENT.with(|current| match current.get() {
+            Some((scheduler, index)) if scheduler == id => Some(index),
+            _ => None,
+        })
+    }
+
+    pub(crate) fn push<F>(&self, f: F) -> Result<Pushed<F>, TryExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.current_index() {
+            Some(index) => self.push_local(index, f),
+            None => self.push_injector(f),
+        }
+    }
+
+    fn push_local<F>(&self, index: usize, f: F) -> Result<Pushed<F>, TryExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(TryExecuteError::ShutDown(f));
+        }
+
+        self.local_count.fetch_add(1, Ordering::SeqCst);
+        self.locals[index].lock().unwrap().push_back(Box::new(f));
+
+        // A sleeper checks local_count while holding the injector lock, so
+        // notifying under that lock cannot be missed.
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            let _injector = self.injector.lock().unwrap();
+            self.not_empty.notify_one();
+        }
+        Ok(Pushed::Queued)
+    }
+
+    fn push_injector<F>(&self, f: F) -> Result<Pushed<F>, TryExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut injector = self.injector.lock().unwrap();
+        let mut pushed = Pushed::Queued;
+
+        loop {
+            if injector.closed {
+                return Err(TryExecuteError::ShutDown(f));
+            }
+            if self
+                .capacity
+                .is_none_or(|capacity| injector.jobs.len() < capacity)
+            {
+                break;
+            }
+            match self.overflow {
+                OverflowPolicy::Block => injector = self.not_full.wait(injector).unwrap(),
+                OverflowPolicy::Reject => return Err(TryExecuteError::Full(f)),
+                OverflowPolicy::CallerRuns => return Ok(Pushed::Refused(f)),
+                OverflowPolicy::DropOldest => {
+                    injector.jobs.pop_front();
+                    pushed = Pushed::DroppedOldest;
+                }
+            }
+        }
+
+        injector.jobs.push_back(Box::new(f));
+        // Sleepers register while holding this lock, so the check is exact.
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            self.not_empty.notify_one();
+        }
+        Ok(pushed)
+    }
+
+    /// Blocks until there is a job for the worker owning deque `index`.
+    /// Returns None once the scheduler is closed and every queue is empty,
+    /// or when the worker has been idle long enough to retire.
+    pub(crate) fn pop(&self, index: usize) -> Option<Job> {
+        let mut idle_since = None;
+        loop {
+            if let Some(job) = self.locals[index].lock().unwrap().pop_back() {
+                self.local_count.fetch_sub(1, Ordering::SeqCst);
+                return Some(job);
+            }
+
+            {
+                let mut injector = self.injector.lock().unwrap();
+                if let Some(job) = injector.jobs.pop_front() {
+                    if self.capacity.is_some() {
+                        self.not_full.notify_one();
+                    }
+                    return Some(job);
+                }
+            }
+
+            if let Some(job) = self.steal(index) {
+                return Some(job);
+            }
+
+            let injector = self.injector.lock().unwrap();
+            if !injector.jobs.is_empty() {
+                continue;
+            }
+            self.sleeping.fetch_add(1, Ordering::SeqCst);
+            if self.local_count.load(Ordering::SeqCst) > 0 {
+                self.sleeping.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+            if injector.closed {
+                self.sleeping.fetch_sub(1, Ordering::SeqCst);
+                return None;
+            }
+
+            let idle_since = *idle_since.get_or_insert_with(Instant::now);
+            let remaining = self.idle_timeout.saturating_sub(idle_since.elapsed());
+            if remaining.is_zero() && self.try_retire() {
+                self.retired[index].store(true, Ordering::SeqCst);
+                self.sleeping.fetch_sub(1, Ordering::SeqCst);
+                return None;
+            }
+            // A worker held at the minimum keeps parking as usual.
+            let park = if remaining.is_zero() {
+                PARK_TIMEOUT
+            } else {
+                remaining.min(PARK_TIMEOUT)
+            };
+            let (injector, _) = self.not_empty.wait_timeout(injector, park).unwrap();
+            self.sleeping.fetch_sub(1, Ordering::SeqCst);
+            drop(injector);
+        }
+    }
+
+    fn steal(&self, index: usize) -> Option<Job> {
+        let count = self.locals.len();
+        for offset in 1..count {
+            let victim = (index + offset) % count;
+            if let Some(job) = self.locals[victim].lock().unwrap().pop_front() {
+                self.local_count.fetch_sub(1, Ordering::SeqCst);
+                return Some(job);
+            }
+        }
+        None
+    }
+
+    /// Counts a new worker towards the pool's size, unless the pool is
+    /// already at its maximum.
+    pub(crate) fn reserve_worker(&self) -> bool {
+        let max = self.locals.len();
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live < max).then_some(live + 1)
+            })
+            .is_ok()
+    }
+
+    /// Gives back a slot taken by `reserve_worker`, for a worker that failed
+    /// to start or has died.
+    pub(crate) fn release_worker(&self) {
+        self.live.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Clears and returns whether the worker in slot `index` has retired.
+    /// Its thread takes no more jobs, so the slot may be filled again
+    /// without waiting for the thread to finish.
+    pub(crate) fn take_retired(&self, index: usize) -> bool {
+        self.retired[index].swap(false, Ordering::SeqCst)
+    }
+
+    fn try_retire(&self) -> bool {
+        let min = self.min_workers;
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live > min).then(|| live - 1)
+            })
+            .is_ok()
+    }
+
+    pub(crate) fn max_workers(&self) -> usize {
+        self.locals.len()
+    }
+
+    pub(crate) fn live_workers(&self) -> usize {
+        self.live.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn idle_workers(&self) -> usize {
+        self.sleeping.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.injector.lock().unwrap().jobs.len() + self.local_count.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn close(&self) {
+        self.injector.lock().unwrap().closed = true;
+        self.closed.store(true, Ordering::SeqCst);
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    pub(crate) fn clear(&self) {
+        // Jobs may own resources such as sockets; drop them outside the locks.
+        let mut jobs = mem::take(&mut self.injector.lock().unwrap().jobs);
+        for local in &self.locals {
+            let mut local = local.lock().unwrap();
+            self.local_count.fetch_sub(local.len(), Ordering::SeqCst);
+            jobs.extend(local.drain(..));
+        }
+        drop(jobs);
+        self.not_full.notify_all();
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    io, mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::Job;
+use crate::{job::CancellationToken, warn};
+
+/// Holds jobs that should run later and hands each one to the pool when it
+/// is due.
+///
+/// Entries sit in a heap ordered by deadline, watched by one timer thread
+/// that sleeps until the earliest of them. Cancelled entries are not
+/// searched for; they are thrown away when they reach the top of the heap.
+pub(crate) struct Timer {
+    shared: Arc<Shared>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+struct State {
+    entries: BinaryHeap<Entry>,
+    // Entries due at the same instant run in the order they were added.
+    next_seq: u64,
+    stopped: bool,
+}
+
+struct Entry {
+    deadline: Instant,
+    seq: u64,
+    token: CancellationToken,
+    task: Task,
+}
+
+enum Task {
+    Once(Job),
+    Every {
+        interval: Duration,
+        f: Arc<dyn Fn() + Send + Sync>,
+    },
+}
+
+// BinaryHeap is a max-heap, so the earliest deadline has to compare greatest.
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        other
+            .deadline
+            .cmp(&self.deadline)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl Timer {
+    pub(crate) fn new() -> Timer {
+        Timer {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    entries: BinaryHeap::new(),
+                    next_seq: 0,
+                    stopped: false,
+                }),
+                wake: Condvar::new(),
+            }),
+            thread: None,
+        }
+    }
+
+    /// Starts the timer thread. `dispatch` hands a due job to the pool and
+    /// gives it back if the pool refuses it.
+    pub(crate) fn start<D>(&mut self, dispatch: D) -> io::Result<()>
+    where
+        D: Fn(Job) -> Result<(), Job> + Send + 'static,
+    {
+        let shared = Arc::clone(&self.shared);
+        let builder = thread::Builder::new().name(String::from("web-server-timer"));
+        self.thread = Some(builder.spawn(move || shared.run(&dispatch))?);
+        Ok(())
+    }
+
+    pub(crate) fn after(&self, delay: Duration, token: CancellationToken, job: Job) {
+        self.shared
+            .push(Instant::now() + delay, token, Task::Once(job));
+    }
+
+    pub(crate) fn every(
+        &self,
+        interval: Duration,
+        token: CancellationToken,
+        f: Arc<dyn Fn() + Send + Sync>,
+    ) {
+        self.shared.push(
+            Instant::now() + interval,
+            token,
+            Task::Every { interval, f },
+        );
+    }
+
+    /// Stops the timer thread and drops every job that was not due yet.
+    pub(crate) fn stop(&mut self) {
+        let entries = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.stopped = true;
+            mem::take(&mut state.entries)
+        };
+        self.shared.wake.notify_all();
+        // Jobs may own resources such as sockets; drop them outside the lock.
+        drop(entries);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Shared {
+    fn push(&self, deadline: Instant, token: CancellationToken, task: Task) {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped {
+            return;
+        }
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        // Only a new earliest entry changes how long the thread should sleep.
+        let earliest = state
+            .entries
+            .peek()
+            .is_none_or(|first| deadline < first.deadline);
+        state.entries.push(Entry {
+            deadline,
+            seq,
+            token,
+            task,
+        });
+        if earliest {
+            self.wake.notify_one();
+        }
+    }
+
+    fn run(self: &Arc<Shared>, dispatch: &dyn Fn(Job) -> Result<(), Job>) {
+        let mut state = self.state.lock().unwrap();
+        while !state.stopped {
+            let now = Instant::now();
+            match state.entries.peek() {
+                None => state = self.wake.wait(state).unwrap(),
+                Some(first) if first.deadline > now => {
+                    let timeout = first.deadline - now;
+                    state = self.wake.wait_timeout(state, timeout).unwrap().0;
+                }
+                Some(_) => {
+                    let entry = state.entries.pop().unwrap();
+                    drop(state);
+                    self.fire(entry, dispatch);
+                    state = self.state.lock().unwrap();
+                }
+            }
+        }
+    }
+
+    fn fire(self: &Arc<Shared>, entry: Entry, dispatch: &dyn Fn(Job) -> Result<(), Job>) {
+        if entry.token.is_cancelled() {
+            return;
+        }
+
+        match entry.task {
+            Task::Once(job) => {
+                if dispatch(job).is_err() {
+                    warn!("Thread pool refused a delayed job; dropping it.");
+                }
+            }
+            Task::Every { interval, f } => {
+                let job = self.recurring_job(interval, Arc::clone(&f), entry.token.clone());
+                if dispatch(job).is_err() {
+                    warn!("Thread pool refused a recurring job; skipping this run.");
+                    let task = Task::Every { interval, f };
+                    self.push(Instant::now() + interval, entry.token, task);
+                }
+            }
+        }
+    }
+
+    // One run of a recurring job. The next run is scheduled when this one
+    // ends, so runs never overlap however long they take.
+    fn recurring_job(
+        self: &Arc<Shared>,
+        interval: Duration,
+        f: Arc<dyn Fn() + Send + Sync>,
+        token: CancellationToken,
+    ) -> Job {
+        let shared = Arc::clone(self);
+        Box::new(move || {
+            if token.is_cancelled() {
+                return;
+            }
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f()));
+            shared.push(
+                Instant::now() + interval,
+                token,
+                Task::Every { interval, f },
+            );
+            // Let the worker count the panic like any other job's.
+            if let Err(payload) = result {
+                panic::resume_unwind(payload);
+            }
+        })
+    }
+}
@@ -0,0 +1,834 @@
+use std::{
+    fmt,
+    io::{self, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    info,
+    request::{self, percent_decode, percent_encode, ParseError},
+    response::{connection_tokens, read_response_head},
+    warn, Limits, Method, Request, Response, ScheduledHandle, StatusCode, ThreadPool,
+};
+
+/// Headers that describe one connection rather than the message, so they
+/// are never passed from one side of the proxy to the other.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// How a proxy picks the upstream for each request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Balance {
+    /// Each upstream in turn.
+    #[default]
+    RoundRobin,
+    /// The upstream with the fewest requests in flight, taking turns among
+    /// those tied.
+    LeastBusy,
+}
+
+impl FromStr for Balance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Balance, String> {
+        match s {
+            "round-robin" => Ok(Balance::RoundRobin),
+            "least-busy" => Ok(Balance::LeastBusy),
+            _ => Err(format!("expected round-robin or least-busy, got {s:?}")),
+        }
+    }
+}
+
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Balance::RoundRobin => "round-robin",
+            Balance::LeastBusy => "least-busy",
+        })
+    }
+}
+
+/// Forwards requests to a group of upstream HTTP servers.
+///
+/// Mount it on a wildcard route for every method:
+///
+/// ```no_run
+/// use web_server::{Proxy, Router};
+///
+/// let api = Proxy::new(["127.0.0.1:9000".parse().unwrap()]).strip_prefix("/api");
+/// let mut router = Router::new();
+/// router.any("/api/*path", move |request, _| api.forward(request));
+/// ```
+///
+/// The upstream sees its own address in `Host`, the original one in
+/// `X-Forwarded-Host`, and the client appended to `X-Forwarded-For`.
+/// Connections to upstreams are kept open and reused between requests.
+///
+/// Clones share their upstreams, so one set of health checks covers every
+/// route a proxy is mounted on.
+#[derive(Clone)]
+pub struct Proxy {
+    upstreams: Arc<[Upstream]>,
+    next: Arc<AtomicUsize>,
+    balance: Balance,
+    strip_prefix: Option<String>,
+    timeout: Duration,
+    max_idle: usize,
+    eject_after: u32,
+    max_response_bytes: usize,
+}
+
+impl Proxy {
+    /// Creates a proxy that balances round-robin across `upstreams`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `upstreams` is empty.
+    pub fn new<I>(upstreams: I) -> Proxy
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        let upstreams: Arc<[Upstream]> = upstreams.into_iter().map(Upstream::new).collect();
+        assert!(!upstreams.is_empty(), "a proxy needs at least one upstream");
+        Proxy {
+            upstreams,
+            next: Arc::new(AtomicUsize::new(0)),
+            balance: Balance::default(),
+            strip_prefix: None,
+            timeout: Duration::from_secs(30),
+            max_idle: 8,
+            eject_after: 2,
+            max_response_bytes: Limits::default().max_body_bytes,
+        }
+    }
+
+    pub fn balance(mut self, balance: Balance) -> Proxy {
+        self.balance = balance;
+        self
+    }
+
+    /// Removes `prefix` from the start of the path before forwarding, so
+    /// `/api/users` reaches the upstream as `/users`.
+    pub fn strip_prefix(mut self, prefix: &str) -> Proxy {
+        self.strip_prefix = Some(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Sets how long connecting to an upstream, or any one read or write,
+    /// may take before the client gets `504 Gateway Timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Proxy {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many idle connections are kept open to each upstream.
+    pub fn max_idle(mut self, max_idle: usize) -> Proxy {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Sets how many health checks in a row an upstream may fail before it
+    /// stops getting requests.
+    pub fn eject_after(mut self, failures: u32) -> Proxy {
+        self.eject_after = failures.max(1);
+        self
+    }
+
+    /// Sets the largest response body taken from an upstream. The client
+    /// gets `502 Bad Gateway` for a larger one.
+    pub fn max_response_bytes(mut self, max_bytes: usize) -> Proxy {
+        self.max_response_bytes = max_bytes;
+        self
+    }
+
+    /// Checks every upstream with a `GET` for `path` each `interval`, on
+    /// the pool's workers.
+    ///
+    /// An upstream that answers with anything but a `2xx` or `3xx`, or not
+    /// at all, `eject_after` times in a row gets no more requests until a
+    /// check passes again. Without health checks every upstream is always
+    /// tried.
+    pub fn health_checks(
+        &self,
+        pool: &ThreadPool,
+        path: &str,
+        interval: Duration,
+    ) -> ScheduledHandle {
+        let proxy = self.clone();
+        let path = path.to_string();
+        pool.execute_every(interval, move || proxy.check_health(&path))
+    }
+
+    /// Sends `request` to an upstream and returns its response.
+    ///
+    /// An upstream that refuses the connection is skipped for the next one.
+    /// The client gets `503 Service Unavailable` when every upstream is
+    /// ejected, `504 Gateway Timeout` when one is too slow and
+    /// `502 Bad Gateway` for anything else that goes wrong.
+    pub fn forward(&self, request: &Request) -> Response {
+        let head_only = request.method == Method::Head;
+        let mut refused = None;
+        for upstream in self.candidates() {
+            let exchange = Exchange::new(request, upstream, &self.target(request));
+            match self.send(upstream, &exchange, head_only) {
+                Ok(response) => return response,
+                Err(UpstreamError::Connect(e)) => {
+                    warn!("Upstream {} refused a connection: {e}", upstream.addr);
+                    refused = Some(e);
+                }
+                Err(e) => {
+                    warn!("Upstream {} failed: {e}", upstream.addr);
+                    return e.response();
+                }
+            }
+        }
+        match refused {
+            Some(e) => UpstreamError::Connect(e).response(),
+            None => Response::new(StatusCode::ServiceUnavailable).with_body("No healthy upstream"),
+        }
+    }
+
+    // The healthy upstreams, the one the balancing strategy picks first
+    // and the rest in the order to fall back on.
+    fn candidates(&self) -> Vec<&Upstream> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.upstreams.len();
+        let mut candidates: Vec<&Upstream> = (0..count)
+            .map(|i| &self.upstreams[(start + i) % count])
+            .filter(|upstream| upstream.is_healthy(self.eject_after))
+            .collect();
+        if self.balance == Balance::LeastBusy {
+            // The sort is stable, so ties keep their round-robin order.
+            candidates.sort_by_key(|upstream| upstream.busy.load(Ordering::Relaxed));
+        }
+        candidates
+    }
+
+    // The target to request from the upstream: the client's own, as sent,
+    // less any prefix.
+    fn target(&self, request: &Request) -> String {
+        let raw_path = request.raw_path();
+        let query = &request.target[raw_path.len()..];
+        let path = match &self.strip_prefix {
+            Some(prefix) => match strip_segments(raw_path, prefix) {
+                Some("") => "/",
+                Some(rest) => rest,
+                None => raw_path,
+            },
+            None => raw_path,
+        };
+        format!("{path}{query}")
+    }
+
+    // Tries an idle connection first. One the upstream closed while it sat
+    // in the pool is replaced with a fresh one if the request can safely be
+    // sent twice.
+    fn send(
+        &self,
+        upstream: &Upstream,
+        exchange: &Exchange,
+        head_only: bool,
+    ) -> Result<Response, UpstreamError> {
+        let _busy = Busy::start(&upstream.busy);
+        if let Some(stream) = upstream.checkout() {
+            match self.exchange(upstream, stream, exchange, head_only) {
+                Err(e) if e.is_stale() && exchange.idempotent => {}
+                result => return result,
+            }
+        }
+        let stream = TcpStream::connect_timeout(&upstream.addr, self.timeout)
+            .map_err(UpstreamError::Connect)?;
+        stream.set_nodelay(true).map_err(UpstreamError::Io)?;
+        self.exchange(upstream, stream, exchange, head_only)
+    }
+
+    fn exchange(
+        &self,
+        upstream: &Upstream,
+        mut stream: TcpStream,
+        exchange: &Exchange,
+        head_only: bool,
+    ) -> Result<Response, UpstreamError> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.write_all(&exchange.head)?;
+        stream.write_all(exchange.body)?;
+        stream.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let mut head = read_response_head(&mut reader)?;
+        // Interim responses such as `100 Continue` come before the real one.
+        while (100..200).contains(&head.status) {
+            head = read_response_head(&mut reader)?;
+        }
+        let status =
+            StatusCode::try_from(head.status).map_err(|_| UpstreamError::Status(head.status))?;
+
+        let framed =
+            head.header("content-length").is_some() || head.header("transfer-encoding").is_some();
+        let body = if head_only || status.is_bodiless() {
+            Vec::new()
+        } else if framed {
+            match request::read_body(&head.headers, &mut reader, self.max_response_bytes) {
+                Err(ParseError::BodyTooLarge) => {
+                    return Err(UpstreamError::TooLarge(self.max_response_bytes))
+                }
+                result => result?,
+            }
+        } else {
+            // Without framing the body runs to the end of the connection.
+            let mut body = Vec::new();
+            let limit = self.max_response_bytes as u64 + 1;
+            (&mut reader).take(limit).read_to_end(&mut body)?;
+            if body.len() > self.max_response_bytes {
+                return Err(UpstreamError::TooLarge(self.max_response_bytes));
+            }
+            body
+        };
+
+        let reusable = head.keeps_alive()
+            && (framed || head_only || status.is_bodiless())
+            && reader.buffer().is_empty();
+        if reusable {
+            upstream.checkin(reader.into_inner(), self.max_idle);
+        }
+
+        let mut response = Response::new(status).with_body(body);
+        let dropped = connection_tokens(head.header("connection"));
+        for (name, value) in &head.headers {
+            // `write_to` frames the body again, except for `HEAD` where the
+            // upstream's length describes the body that was not sent.
+            let framing = name == "transfer-encoding" || (name == "content-length" && !head_only);
+            if !framing && !is_hop_by_hop(name, &dropped) {
+                response.headers.append(name, value);
+            }
+        }
+        Ok(response)
+    }
+
+    fn check_health(&self, path: &str) {
+        for upstream in self.upstreams.iter() {
+            match self.probe(upstream, path) {
+                Ok(()) => {
+                    if upstream.failures.swap(0, Ordering::SeqCst) >= self.eject_after {
+                        info!(
+                            "Upstream {} passed a health check, restoring it",
+                            upstream.addr
+                        );
+                    }
+                }
+                Err(e) => {
+                    let failures = upstream.failures.fetch_add(1, Ordering::SeqCst) + 1;
+                    if failures == self.eject_after {
+                        warn!(
+                            "Upstream {} failed {failures} health checks, ejecting it: {e}",
+                            upstream.addr
+                        );
+                        upstream.idle.lock().unwrap().clear();
+                    }
+                }
+            }
+        }
+    }
+
+    // Health checks use a connection of their own so a pooled one cannot
+    // hide an upstream that has stopped accepting new ones.
+    fn probe(&self, upstream: &Upstream, path: &str) -> Result<(), UpstreamError> {
+        let mut stream = TcpStream::connect_timeout(&upstream.addr, self.timeout)
+            .map_err(UpstreamError::Connect)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            percent_encode(path),
+            upstream.addr
+        );
+        stream.write_all(request.as_bytes())?;
+        let head = read_response_head(&mut BufReader::new(stream))?;
+        match head.status {
+            200..=399 => Ok(()),
+            status => Err(UpstreamError::Status(status)),
+        }
+    }
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Proxy")
+            .field(
+                "upstreams",
+                &self.upstreams.iter().map(|u| u.addr).collect::<Vec<_>>(),
+            )
+            .field("balance", &self.balance)
+            .field("strip_prefix", &self.strip_prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+// One upstream server and the connections kept open to it.
+struct Upstream {
+    addr: SocketAddr,
+    idle: Mutex<Vec<TcpStream>>,
+    /// Requests in flight, for `Balance::LeastBusy`.
+    busy: AtomicUsize,
+    /// Health checks failed in a row.
+    failures: AtomicU32,
+}
+
+impl Upstream {
+    fn new(addr: SocketAddr) -> Upstream {
+        Upstream {
+            addr,
+            idle: Mutex::new(Vec::new()),
+            busy: AtomicUsize::new(0),
+            failures: AtomicU32::new(0),
+        }
+    }
+
+    fn is_healthy(&self, eject_after: u32) -> bool {
+        self.failures.load(Ordering::SeqCst) < eject_after
+    }
+
+    // Takes the most recently used idle connection that the upstream has
+    // not closed in the meantime.
+    fn checkout(&self) -> Option<TcpStream> {
+        loop {
+            let stream = self.idle.lock().unwrap().pop()?;
+            if is_open(&stream) {
+                return Some(stream);
+            }
+        }
+    }
+
+    fn checkin(&self, stream: TcpStream, max_idle: usize) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < max_idle {
+            idle.push(stream);
+        }
+    }
+}
+
+// An idle connection has nothing to read, so data or an end of stream both
+// mean it cannot carry another request.
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let open = matches!(
+        stream.peek(&mut [0]),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock
+    );
+    open && stream.set_nonblocking(false).is_ok()
+}
+
+// Counts a request in flight for as long as it lives.
+struct Busy<'a>(&'a AtomicUsize);
+
+impl Busy<'_> {
+    fn start(count: &AtomicUsize) -> Busy<'_> {
+        count.fetch_add(1, Ordering::Relaxed);
+        Busy(count)
+    }
+}
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// A request rewritten for one upstream, ready to send.
+struct Exchange<'a> {
+    head: Vec<u8>,
+    body: &'a [u8],
+    /// Whether sending the request twice does no more than sending it once.
+    idempotent: bool,
+}
+
+impl<'a> Exchange<'a> {
+    fn new(request: &'a Request, upstream: &Upstream, target: &str) -> Exchange<'a> {
+        let mut head = format!("{} {target} HTTP/1.1\r\n", request.method);
+        head.push_str(&format!("host: {}\r\n", upstream.addr));
+
+        let dropped = connection_tokens(request.header("connection"));
+        let mut forwarded_for = None;
+        for (name, value) in &request.headers {
+            match name.as_str() {
+                "host" => head.push_str(&format!("x-forwarded-host: {value}\r\n")),
+                "x-forwarded-for" => forwarded_for = Some(value.as_str()),
+                // The body has been read whole, so it is sent with a length.
+                "content-length" | "transfer-encoding" | "x-forwarded-host" => {}
+                name if is_hop_by_hop(name, &dropped) => {}
+                name => head.push_str(&format!("{name}: {value}\r\n")),
+            }
+        }
+        if let Some(client) = request.remote {
+            let forwarded_for = match forwarded_for {
+                Some(earlier) => format!("{earlier}, {client}"),
+                None => client.to_string(),
+            };
+            head.push_str(&format!("x-forwarded-for: {forwarded_for}\r\n"));
+        } else if let Some(earlier) = forwarded_for {
+            head.push_str(&format!("x-forwarded-for: {earlier}\r\n"));
+        }
+        let may_have_body = matches!(request.method, Method::Post | Method::Put | Method::Patch);
+        if may_have_body || !request.body.is_empty() {
+            head.push_str(&format!("content-length: {}\r\n", request.body.len()));
+        }
+        head.push_str("\r\n");
+
+        Exchange {
+            head: head.into_bytes(),
+            body: &request.body,
+            idempotent: !matches!(
+                request.method,
+                Method::Post | Method::Patch | Method::Connect
+            ),
+        }
+    }
+}
+
+// Removes the segments of `prefix` from the start of a raw path, comparing
+// them decoded as the router does. Returns the rest, which is empty or
+// starts with `/`, or None if the path is not under `prefix`.
+fn strip_segments<'a>(raw_path: &'a str, prefix: &str) -> Option<&'a str> {
+    let mut rest = raw_path;
+    for segment in prefix.split('/').filter(|segment| !segment.is_empty()) {
+        let after = rest.strip_prefix('/')?;
+        let end = after.find('/').unwrap_or(after.len());
+        if percent_decode(&after[..end]).ok()? != segment {
+            return None;
+        }
+        rest = &after[end..];
+    }
+    Some(rest)
+}
+
+fn is_hop_by_hop(name: &str, listed: &[String]) -> bool {
+    HOP_BY_HOP.contains(&name) || listed.iter().any(|token| token == name)
+}
+
+// Why an upstream gave no usable response.
+#[derive(Debug)]
+enum UpstreamError {
+    /// Nothing was sent, so another upstream may be tried.
+    Connect(io::Error),
+    Io(io::Error),
+    Parse(ParseError),
+    /// A response body over `max_response_bytes`, which is given.
+    TooLarge(usize),
+    /// A status this server has no `StatusCode` for, or a failed health
+    /// check.
+    Status(u16),
+}
+
+impl UpstreamError {
+    // A pooled connection the upstream closed before reading the request.
+    fn is_stale(&self) -> bool {
+        match self {
+            UpstreamError::Parse(ParseError::ConnectionClosed) => true,
+            UpstreamError::Io(e) | UpstreamError::Parse(ParseError::Io(e)) => matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+            ),
+            _ => false,
+        }
+    }
+
+    fn response(&self) -> Response {
+        let timed_out = |e: &io::Error| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            )
+        };
+        match self {
+            UpstreamError::Connect(e)
+            | UpstreamError::Io(e)
+            | UpstreamError::Parse(ParseError::Io(e))
+                if timed_out(e) =>
+            {
+                Response::new(StatusCode::GatewayTimeout).with_body("Gateway Timeout")
+            }
+            _ => Response::new(StatusCode::BadGateway).with_body("Bad Gateway"),
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpstreamError::Connect(e) => write!(f, "cannot connect: {e}"),
+            UpstreamError::Io(e) => write!(f, "i/o error: {e}"),
+            UpstreamError::Parse(e) => write!(f, "bad response: {e}"),
+            UpstreamError::TooLarge(max) => {
+                write!(f, "response body larger than {max} bytes")
+            }
+            UpstreamError::Status(status) => write!(f, "answered with status {status}"),
+        }
+    }
+}
+
+impl From<io::Error> for UpstreamError {
+    fn from(e: io::Error) -> UpstreamError {
+        UpstreamError::Io(e)
+    }
+}
+
+impl From<ParseError> for UpstreamError {
+    fn from(e: ParseError) -> UpstreamError {
+        UpstreamError::Parse(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Router, Server};
+    use std::{
+        net::{IpAddr, Ipv4Addr, TcpListener},
+        sync::atomic::AtomicBool,
+        thread,
+    };
+
+    // An upstream that answers every request with its name, and `/health`
+    // with 500 once marked down.
+    struct StandIn {
+        addr: SocketAddr,
+        connections: Arc<AtomicUsize>,
+        requests: Arc<Mutex<Vec<Request>>>,
+        down: Arc<AtomicBool>,
+    }
+
+    fn stand_in(name: &'static str) -> StandIn {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stand_in = StandIn {
+            addr: listener.local_addr().unwrap(),
+            connections: Arc::new(AtomicUsize::new(0)),
+            requests: Arc::new(Mutex::new(Vec::new())),
+            down: Arc::new(AtomicBool::new(false)),
+        };
+        let (connections, requests, down) = (
+            Arc::clone(&stand_in.connections),
+            Arc::clone(&stand_in.requests),
+            Arc::clone(&stand_in.down),
+        );
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                connections.fetch_add(1, Ordering::SeqCst);
+                let (requests, down) = (Arc::clone(&requests), Arc::clone(&down));
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    while let Ok(request) = Request::read_from(&mut reader) {
+                        let status = match request.path.as_str() {
+                            "/health" if down.load(Ordering::SeqCst) => "500 Internal Server Error",
+                            _ => "200 OK",
+                        };
+                        requests.lock().unwrap().push(request);
+                        let response = format!(
+                            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nKeep-Alive: timeout=5\r\nX-Upstream: {name}\r\n\r\n{name}",
+                            name.len()
+                        );
+                        if stream.write_all(response.as_bytes()).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        stand_in
+    }
+
+    fn request(raw: &str) -> Request {
+        Request {
+            remote: Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7))),
+            ..Request::read_from(&mut raw.as_bytes()).unwrap()
+        }
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn rewrites_the_request_for_the_upstream() {
+        let upstream = stand_in("a");
+        let proxy = Proxy::new([upstream.addr]).strip_prefix("/api");
+
+        let response = proxy.forward(&request(
+            "POST /api/users%20list?q=a+b HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 10.0.0.1\r\nConnection: keep-alive, x-secret\r\nX-Secret: 1\r\nContent-Length: 2\r\n\r\nhi",
+        ));
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("x-upstream"), Some("a"));
+        assert_eq!(response.header("keep-alive"), None);
+        assert_eq!(body(response), "a");
+        let seen = upstream.requests.lock().unwrap().remove(0);
+        assert_eq!(seen.method, Method::Post);
+        assert_eq!(seen.target, "/users%20list?q=a+b");
+        assert_eq!(seen.path, "/users list");
+        assert_eq!(seen.query_param("q"), Some("a b"));
+        assert_eq!(
+            seen.header("host"),
+            Some(upstream.addr.to_string().as_str())
+        );
+        assert_eq!(seen.header("x-forwarded-host"), Some("example.com"));
+        assert_eq!(seen.header("x-forwarded-for"), Some("10.0.0.1, 192.0.2.7"));
+        assert_eq!(seen.header("x-secret"), None);
+        assert_eq!(seen.header("connection"), None);
+        assert_eq!(seen.body, b"hi");
+    }
+
+    #[test]
+    fn forwards_the_raw_target() {
+        let upstream = stand_in("a");
+        let proxy = Proxy::new([upstream.addr]).strip_prefix("/api");
+        let forwarded = |target: &str| {
+            proxy.forward(&request(&format!("GET {target} HTTP/1.1\r\n\r\n")));
+            upstream.requests.lock().unwrap().pop().unwrap().target
+        };
+
+        assert_eq!(forwarded("/api/a%2Fb?flag&x=%26"), "/a%2Fb?flag&x=%26");
+        assert_eq!(forwarded("/%61pi/users"), "/users");
+        assert_eq!(forwarded("/api?page=2"), "/?page=2");
+        assert_eq!(forwarded("/apiary/a%2Fb"), "/apiary/a%2Fb");
+    }
+
+    #[test]
+    fn refuses_responses_over_the_limit() {
+        let upstream = stand_in("abc");
+        let get = || request("GET / HTTP/1.1\r\n\r\n");
+
+        let proxy = Proxy::new([upstream.addr]).max_response_bytes(2);
+        assert_eq!(proxy.forward(&get()).status, 502);
+        let request = get();
+        let exchange = Exchange::new(&request, &proxy.upstreams[0], "/");
+        let e = proxy
+            .send(&proxy.upstreams[0], &exchange, false)
+            .unwrap_err();
+        assert!(matches!(e, UpstreamError::TooLarge(2)));
+        assert_eq!(e.to_string(), "response body larger than 2 bytes");
+        let response = Proxy::new([upstream.addr])
+            .max_response_bytes(3)
+            .forward(&get());
+        assert_eq!(body(response), "abc");
+    }
+
+    #[test]
+    fn reuses_upstream_connections() {
+        let upstream = stand_in("a");
+        let proxy = Proxy::new([upstream.addr]);
+
+        for _ in 0..3 {
+            assert_eq!(body(proxy.forward(&request("GET / HTTP/1.1\r\n\r\n"))), "a");
+        }
+
+        assert_eq!(upstream.connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn balances_across_upstreams() {
+        let (a, b) = (stand_in("a"), stand_in("b"));
+        let get = || request("GET / HTTP/1.1\r\n\r\n");
+
+        let round_robin = Proxy::new([a.addr, b.addr]);
+        let picked: Vec<String> = (0..4).map(|_| body(round_robin.forward(&get()))).collect();
+        assert_eq!(picked, ["a", "b", "a", "b"]);
+
+        let least_busy = Proxy::new([a.addr, b.addr]).balance(Balance::LeastBusy);
+        let _busy = Busy::start(&least_busy.upstreams[0].busy);
+        for _ in 0..3 {
+            assert_eq!(body(least_busy.forward(&get())), "b");
+        }
+    }
+
+    #[test]
+    fn health_checks_eject_and_restore_upstreams() {
+        let (a, b) = (stand_in("a"), stand_in("b"));
+        let proxy = Proxy::new([a.addr, b.addr]).eject_after(2);
+        let get = || request("GET / HTTP/1.1\r\n\r\n");
+
+        a.down.store(true, Ordering::SeqCst);
+        proxy.check_health("/health");
+        assert!(proxy.upstreams[0].is_healthy(2));
+        proxy.check_health("/health");
+        for _ in 0..3 {
+            assert_eq!(body(proxy.forward(&get())), "b");
+        }
+
+        b.down.store(true, Ordering::SeqCst);
+        proxy.check_health("/health");
+        proxy.check_health("/health");
+        assert_eq!(proxy.forward(&get()).status, 503);
+
+        a.down.store(false, Ordering::SeqCst);
+        proxy.check_health("/health");
+        assert_eq!(body(proxy.forward(&get())), "a");
+    }
+
+    #[test]
+    fn refused_connections_fall_back_then_fail() {
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let b = stand_in("b");
+        let get = || request("GET / HTTP/1.1\r\n\r\n");
+
+        let proxy = Proxy::new([closed, b.addr]);
+        for _ in 0..2 {
+            assert_eq!(body(proxy.forward(&get())), "b");
+        }
+
+        assert_eq!(Proxy::new([closed]).forward(&get()).status, 502);
+    }
+
+    #[test]
+    fn forwards_through_a_running_server() {
+        let upstream = stand_in("a");
+        let pool = ThreadPool::new(2);
+        let proxy = Proxy::new([upstream.addr]).strip_prefix("/api");
+        let checks = proxy.health_checks(&pool, "/health", Duration::from_millis(10));
+        let mut router = Router::new();
+        router.any("/api/*path", move |request, _| proxy.forward(request));
+        let server = Server::new(TcpListener::bind("127.0.0.1:0").unwrap(), router, pool);
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run().unwrap());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"DELETE /api/items/1 HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        checks.cancel();
+        shutdown.trigger();
+        running.join().unwrap();
+
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("\r\n\r\na"));
+        let requests = upstream.requests.lock().unwrap();
+        let seen = requests.iter().find(|r| r.path != "/health").unwrap();
+        assert_eq!(seen.method, Method::Delete);
+        assert_eq!(seen.path, "/items/1");
+        assert_eq!(seen.header("x-forwarded-for"), Some("127.0.0.1"));
+    }
+}
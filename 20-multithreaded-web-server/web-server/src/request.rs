@@ -0,0 +1,975 @@
+use std::{
+    error::Error,
+    fmt,
+    io::{self, BufRead, Read},
+    net::IpAddr,
+    str::FromStr,
+};
+
+use crate::{cookie, Limits};
+
+/// The longest chunk-size line, extensions included, in a chunked body.
+const MAX_CHUNK_LINE_BYTES: usize = 4 * 1024;
+
+/// The most bytes the trailer fields after a chunked body may take.
+const MAX_TRAILER_BYTES: usize = 16 * 1024;
+
+/// The request methods defined by HTTP/1.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Trace,
+    Connect,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = ParseError;
+
+    // Methods are case-sensitive, so "get" is not the same as "GET".
+    fn from_str(s: &str) -> Result<Method, ParseError> {
+        match s {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "PATCH" => Ok(Method::Patch),
+            "OPTIONS" => Ok(Method::Options),
+            "TRACE" => Ok(Method::Trace),
+            "CONNECT" => Ok(Method::Connect),
+            _ => Err(ParseError::InvalidMethod(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Version, ParseError> {
+        match s {
+            "HTTP/1.0" => Ok(Version::Http10),
+            "HTTP/1.1" => Ok(Version::Http11),
+            _ => Err(ParseError::InvalidVersion(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Everything that can go wrong while reading a request off the wire.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The peer closed the connection before sending a request line.
+    ConnectionClosed,
+    /// The stream ended in the middle of a request.
+    UnexpectedEof,
+    Io(io::Error),
+    MalformedRequestLine(String),
+    InvalidMethod(String),
+    InvalidVersion(String),
+    InvalidPercentEncoding(String),
+    MalformedHeader(String),
+    InvalidContentLength(String),
+    UnsupportedTransferEncoding(String),
+    /// Both `Content-Length` and `Transfer-Encoding` were sent.
+    ConflictingBodyLength,
+    InvalidChunk(String),
+    /// The request line and headers together are longer than allowed.
+    HeadersTooLarge,
+    /// The body is longer than allowed.
+    BodyTooLarge,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::ConnectionClosed => write!(f, "connection closed before request"),
+            ParseError::UnexpectedEof => write!(f, "unexpected end of request"),
+            ParseError::Io(e) => write!(f, "i/o error: {e}"),
+            ParseError::MalformedRequestLine(line) => write!(f, "malformed request line: {line:?}"),
+            ParseError::InvalidMethod(method) => write!(f, "invalid method: {method:?}"),
+            ParseError::InvalidVersion(version) => write!(f, "invalid HTTP version: {version:?}"),
+            ParseError::InvalidPercentEncoding(s) => write!(f, "invalid percent-encoding: {s:?}"),
+            ParseError::MalformedHeader(line) => write!(f, "malformed header: {line:?}"),
+            ParseError::InvalidContentLength(value) => {
+                write!(f, "invalid Content-Length: {value:?}")
+            }
+            ParseError::UnsupportedTransferEncoding(value) => {
+                write!(f, "unsupported Transfer-Encoding: {value:?}")
+            }
+            ParseError::ConflictingBodyLength => {
+                write!(f, "both Content-Length and Transfer-Encoding present")
+            }
+            ParseError::InvalidChunk(line) => write!(f, "invalid chunk: {line:?}"),
+            ParseError::HeadersTooLarge => write!(f, "request headers too large"),
+            ParseError::BodyTooLarge => write!(f, "request body too large"),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> ParseError {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => ParseError::UnexpectedEof,
+            _ => ParseError::Io(e),
+        }
+    }
+}
+
+/// A parsed HTTP/1.x request.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    /// The percent-decoded path, without the query string.
+    pub path: String,
+    /// The request target as sent, still percent-encoded and with any
+    /// query string.
+    pub target: String,
+    /// The decoded query parameters in the order they were sent.
+    pub query: Vec<(String, String)>,
+    pub version: Version,
+    /// Header names are stored lowercased, so lookups are case-insensitive.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// The address of the client, filled in by the server. Requests parsed
+    /// any other way have none.
+    pub remote: Option<IpAddr>,
+}
+
+impl Request {
+    /// Reads one request, including its body, from `reader`, within the
+    /// header and body sizes of `Limits::default()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ParseError::ConnectionClosed` if the stream ends before any
+    /// bytes arrive, `ParseError::HeadersTooLarge` or
+    /// `ParseError::BodyTooLarge` past a limit, and another `ParseError`
+    /// variant for anything malformed.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Request, ParseError> {
+        let limits = Limits::default();
+        let mut request = Request::read_head(reader, limits.max_header_bytes)?;
+        request.body = request.read_body(reader, limits.max_body_bytes)?;
+        Ok(request)
+    }
+
+    /// Reads the request line and headers, leaving the body in `reader` for
+    /// `read_body`.
+    ///
+    /// # Errors
+    ///
+    /// As for `read_from`, plus `ParseError::HeadersTooLarge` once more than
+    /// `max_bytes` have been read without reaching the end of the headers.
+    pub fn read_head<R: BufRead>(reader: &mut R, max_bytes: usize) -> Result<Request, ParseError> {
+        let mut budget = max_bytes;
+        // Robust servers ignore empty lines received before the request line.
+        let request_line = loop {
+            match read_line(reader, &mut budget)? {
+                None => return Err(ParseError::ConnectionClosed),
+                Some(line) if line.is_empty() => continue,
+                Some(line) => break line,
+            }
+        };
+
+        let mut parts = request_line.split(' ');
+        let (method, target, version) =
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(method), Some(target), Some(version), None) if !target.is_empty() => {
+                    (method, target, version)
+                }
+                _ => return Err(ParseError::MalformedRequestLine(request_line)),
+            };
+
+        let method = method.parse()?;
+        let version = version.parse()?;
+        let (path, query) = parse_target(target)?;
+
+        let mut headers = Vec::new();
+        loop {
+            let line = read_line(reader, &mut budget)?.ok_or(ParseError::UnexpectedEof)?;
+            if line.is_empty() {
+                break;
+            }
+            headers.push(parse_header(&line)?);
+        }
+
+        Ok(Request {
+            method,
+            path,
+            target: target.to_string(),
+            query,
+            version,
+            headers,
+            body: Vec::new(),
+            remote: None,
+        })
+    }
+
+    /// Returns the first value of the header `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the value of the cookie `name`, looking through every
+    /// `Cookie` header.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("cookie"))
+            .flat_map(|(_, header)| cookie::parse(header))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// The path as sent, still percent-encoded, without the query string.
+    pub fn raw_path(&self) -> &str {
+        self.target
+            .split_once('?')
+            .map_or(self.target.as_str(), |(path, _)| path)
+    }
+
+    /// Returns the first value of the query parameter `name`.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Reads the body announced by the headers of a request from
+    /// `read_head`.
+    ///
+    /// # Errors
+    ///
+    /// As for `read_from`, plus `ParseError::BodyTooLarge` for a body longer
+    /// than `max_bytes`. A `Content-Length` over the limit fails before any
+    /// of the body is read.
+    pub fn read_body<R: BufRead>(
+        &self,
+        reader: &mut R,
+        max_bytes: usize,
+    ) -> Result<Vec<u8>, ParseError> {
+        read_body(&self.headers, reader, max_bytes)
+    }
+}
+
+// How a message body is delimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Framing {
+    Length(usize),
+    Chunked,
+    None,
+}
+
+// Works out the framing from `Content-Length` or chunked
+// `Transfer-Encoding` in `headers`, which must have lowercase names.
+// Neither header means no body, as for a request.
+pub(crate) fn framing(headers: &[(String, String)]) -> Result<Framing, ParseError> {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    match (header("content-length"), header("transfer-encoding")) {
+        (Some(_), Some(_)) => Err(ParseError::ConflictingBodyLength),
+        (None, Some(encoding)) if encoding.eq_ignore_ascii_case("chunked") => Ok(Framing::Chunked),
+        (None, Some(encoding)) => Err(ParseError::UnsupportedTransferEncoding(
+            encoding.to_string(),
+        )),
+        (Some(_), None) => content_length(headers).map(Framing::Length),
+        (None, None) => Ok(Framing::None),
+    }
+}
+
+// Reads a body framed as `framing` says for `headers`.
+pub(crate) fn read_body<R: BufRead>(
+    headers: &[(String, String)],
+    reader: &mut R,
+    max_bytes: usize,
+) -> Result<Vec<u8>, ParseError> {
+    match framing(headers)? {
+        Framing::Chunked => read_chunked(reader, max_bytes),
+        Framing::Length(length) => {
+            if length > max_bytes {
+                return Err(ParseError::BodyTooLarge);
+            }
+            // Grown as the bytes arrive, so a client that announces a large
+            // body holds no more memory than it has actually sent.
+            let mut body = Vec::new();
+            reader.take(length as u64).read_to_end(&mut body)?;
+            if body.len() < length {
+                return Err(ParseError::UnexpectedEof);
+            }
+            Ok(body)
+        }
+        Framing::None => Ok(Vec::new()),
+    }
+}
+
+/// A request body still on the connection, read through its framing.
+///
+/// Reads end where the body does, so whatever follows it on the connection
+/// is left for the next request. A malformed chunk or a connection that
+/// closes early is an error with kind `InvalidData` or `UnexpectedEof`.
+pub(crate) struct BodyReader<'a> {
+    reader: &'a mut dyn BufRead,
+    state: BodyState,
+}
+
+enum BodyState {
+    Length(usize),
+    Chunked {
+        decoder: ChunkedDecoder,
+        /// Decoded bytes not yet handed out, from `read`.
+        decoded: Vec<u8>,
+        read: usize,
+    },
+}
+
+impl<'a> BodyReader<'a> {
+    pub(crate) fn new(reader: &'a mut dyn BufRead, framing: Framing) -> BodyReader<'a> {
+        let state = match framing {
+            Framing::Length(length) => BodyState::Length(length),
+            Framing::Chunked => BodyState::Chunked {
+                decoder: ChunkedDecoder::new(usize::MAX),
+                decoded: Vec::new(),
+                read: 0,
+            },
+            Framing::None => BodyState::Length(0),
+        };
+        BodyReader { reader, state }
+    }
+
+    /// Whether the whole body has been read, so the connection can carry
+    /// another request.
+    pub(crate) fn is_finished(&self) -> bool {
+        match &self.state {
+            BodyState::Length(remaining) => *remaining == 0,
+            BodyState::Chunked {
+                decoder,
+                decoded,
+                read,
+            } => decoder.is_done() && *read == decoded.len(),
+        }
+    }
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.state {
+            BodyState::Length(remaining) => {
+                if *remaining == 0 || buf.is_empty() {
+                    return Ok(0);
+                }
+                let wanted = buf.len().min(*remaining);
+                let read = self.reader.read(&mut buf[..wanted])?;
+                if read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                *remaining -= read;
+                Ok(read)
+            }
+            BodyState::Chunked {
+                decoder,
+                decoded,
+                read,
+            } => loop {
+                if *read < decoded.len() {
+                    let taken = buf.len().min(decoded.len() - *read);
+                    buf[..taken].copy_from_slice(&decoded[*read..*read + taken]);
+                    *read += taken;
+                    return Ok(taken);
+                }
+                if decoder.is_done() {
+                    return Ok(0);
+                }
+                let available = self.reader.fill_buf()?;
+                if available.is_empty() {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                let used = decoder.decode(available).map_err(|e| match e {
+                    ParseError::Io(e) => e,
+                    e => io::Error::new(io::ErrorKind::InvalidData, e),
+                })?;
+                self.reader.consume(used);
+                *decoded = decoder.take_body();
+                *read = 0;
+            },
+        }
+    }
+}
+
+// Repeated Content-Length headers are only allowed when they all agree.
+fn content_length(headers: &[(String, String)]) -> Result<usize, ParseError> {
+    let mut length = None;
+    for (_, value) in headers.iter().filter(|(key, _)| key == "content-length") {
+        let digits = value.trim();
+        // `parse` would also take a leading `+`.
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseError::InvalidContentLength(value.clone()));
+        }
+        let parsed = digits
+            .parse::<usize>()
+            .map_err(|_| ParseError::InvalidContentLength(value.clone()))?;
+        if length.is_some_and(|length| length != parsed) {
+            return Err(ParseError::InvalidContentLength(value.clone()));
+        }
+        length = Some(parsed);
+    }
+    Ok(length.unwrap_or(0))
+}
+
+// Reads a line terminated by LF, stripping the line ending. Returns None on a
+// clean end of stream. At most `budget` bytes are read, and the budget is
+// reduced by what the line used.
+pub(crate) fn read_line<R: BufRead>(
+    reader: &mut R,
+    budget: &mut usize,
+) -> Result<Option<String>, ParseError> {
+    let mut buf = Vec::new();
+    let read = Read::take(reader, *budget as u64).read_until(b'\n', &mut buf)?;
+    if read == 0 {
+        return match *budget {
+            0 => Err(ParseError::HeadersTooLarge),
+            _ => Ok(None),
+        };
+    }
+    *budget -= read;
+    if buf.pop() != Some(b'\n') {
+        return match *budget {
+            0 => Err(ParseError::HeadersTooLarge),
+            _ => Err(ParseError::UnexpectedEof),
+        };
+    }
+    if buf.last() == Some(&b'\r') {
+        buf.pop();
+    }
+    String::from_utf8(buf).map(Some).map_err(|e| {
+        ParseError::MalformedHeader(String::from_utf8_lossy(e.as_bytes()).into_owned())
+    })
+}
+
+pub(crate) fn parse_header(line: &str) -> Result<(String, String), ParseError> {
+    let (name, value) = line
+        .split_once(':')
+        .ok_or_else(|| ParseError::MalformedHeader(line.to_string()))?;
+
+    // No whitespace is allowed between the field name and the colon, and
+    // obsolete line folding starts with whitespace.
+    if name.is_empty()
+        || name
+            .bytes()
+            .any(|b| b.is_ascii_whitespace() || b.is_ascii_control())
+    {
+        return Err(ParseError::MalformedHeader(line.to_string()));
+    }
+
+    Ok((name.to_ascii_lowercase(), value.trim().to_string()))
+}
+
+fn parse_target(target: &str) -> Result<(String, Vec<(String, String)>), ParseError> {
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (target, ""),
+    };
+
+    if !path.starts_with('/') && path != "*" {
+        return Err(ParseError::MalformedRequestLine(target.to_string()));
+    }
+
+    Ok((percent_decode(path)?, parse_query(query)?))
+}
+
+// Decodes `key=value` pairs joined by `&`, as in a query string or an
+// `application/x-www-form-urlencoded` body.
+pub(crate) fn parse_query(query: &str) -> Result<Vec<(String, String)>, ParseError> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((decode_query_component(key)?, decode_query_component(value)?))
+        })
+        .collect()
+}
+
+fn decode_query_component(s: &str) -> Result<String, ParseError> {
+    percent_decode(&s.replace('+', " "))
+}
+
+/// Decodes `%XX` escapes in `s`.
+///
+/// # Errors
+///
+/// Fails if an escape is truncated, is not hex, or the result is not UTF-8.
+pub fn percent_decode(s: &str) -> Result<String, ParseError> {
+    let invalid = || ParseError::InvalidPercentEncoding(s.to_string());
+
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).ok_or_else(invalid)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
+
+/// Escapes every byte of `s` other than unreserved characters and `/` as
+/// `%XX`, so a decoded path or query component can go back into a URL.
+pub fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~/".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    encoded
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R, max_bytes: usize) -> Result<Vec<u8>, ParseError> {
+    let mut decoder = ChunkedDecoder::new(max_bytes);
+    while !decoder.is_done() {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if available.is_empty() {
+            return Err(ParseError::UnexpectedEof);
+        }
+        let used = decoder.decode(available)?;
+        reader.consume(used);
+    }
+    Ok(decoder.into_body())
+}
+
+/// Decodes a chunked body from pieces as they arrive, so a caller that
+/// cannot block on a reader need not start over with each new piece.
+pub(crate) struct ChunkedDecoder {
+    state: ChunkState,
+    /// The part of the current line taken so far.
+    line: Vec<u8>,
+    /// What the current line may still take, its line ending included.
+    budget: usize,
+    body: Vec<u8>,
+    /// Everything decoded so far, including what `take_body` handed out.
+    total: usize,
+    max_bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkState {
+    Size,
+    /// Within a chunk, with this many bytes of it still to come.
+    Data(usize),
+    /// The line ending after a chunk's data.
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+impl ChunkedDecoder {
+    pub(crate) fn new(max_bytes: usize) -> ChunkedDecoder {
+        ChunkedDecoder {
+            state: ChunkState::Size,
+            line: Vec::new(),
+            budget: MAX_CHUNK_LINE_BYTES,
+            body: Vec::new(),
+            total: 0,
+            max_bytes,
+        }
+    }
+
+    /// Whether the final chunk and the trailers have all been decoded.
+    pub(crate) fn is_done(&self) -> bool {
+        self.state == ChunkState::Done
+    }
+
+    pub(crate) fn into_body(self) -> Vec<u8> {
+        self.body
+    }
+
+    /// Takes what has been decoded since the last call, for a caller that
+    /// passes the body on rather than keeping it.
+    pub(crate) fn take_body(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.body)
+    }
+
+    /// Decodes what it can of `input` and returns how many bytes it used.
+    /// Once the body is done the rest of `input` is left alone, since it
+    /// belongs to whatever comes next.
+    pub(crate) fn decode(&mut self, input: &[u8]) -> Result<usize, ParseError> {
+        let mut used = 0;
+        while used < input.len() && !self.is_done() {
+            let rest = &input[used..];
+            if let ChunkState::Data(remaining) = self.state {
+                let taken = remaining.min(rest.len());
+                self.body.extend_from_slice(&rest[..taken]);
+                self.total += taken;
+                used += taken;
+                self.state = match remaining - taken {
+                    0 => self.line_state(ChunkState::DataEnd),
+                    remaining => ChunkState::Data(remaining),
+                };
+                continue;
+            }
+
+            let (taken, line) = self.take_line(rest)?;
+            used += taken;
+            let Some(line) = line else { continue };
+            match self.state {
+                ChunkState::Size => {
+                    // Chunk extensions after ';' carry no meaning for us.
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        return Err(ParseError::InvalidChunk(line));
+                    }
+                    let size = usize::from_str_radix(size, 16)
+                        .map_err(|_| ParseError::InvalidChunk(line.clone()))?;
+                    if size == 0 {
+                        // Trailer fields share one budget, unlike chunk lines.
+                        self.state = ChunkState::Trailers;
+                        self.budget = MAX_TRAILER_BYTES;
+                    } else if size > self.max_bytes - self.total {
+                        return Err(ParseError::BodyTooLarge);
+                    } else {
+                        self.state = ChunkState::Data(size);
+                    }
+                }
+                ChunkState::DataEnd if line.is_empty() => {
+                    self.state = self.line_state(ChunkState::Size);
+                }
+                ChunkState::DataEnd => return Err(ParseError::InvalidChunk(line)),
+                ChunkState::Trailers if line.is_empty() => self.state = ChunkState::Done,
+                _ => {}
+            }
+        }
+        Ok(used)
+    }
+
+    // Each chunk line gets a budget of its own, so an endless one fails
+    // instead of being buffered.
+    fn line_state(&mut self, state: ChunkState) -> ChunkState {
+        self.budget = MAX_CHUNK_LINE_BYTES;
+        state
+    }
+
+    // Moves `input` up to and including the next LF into the current line.
+    // Returns how many bytes that took, and the line without its ending
+    // once it is complete.
+    fn take_line(&mut self, input: &[u8]) -> Result<(usize, Option<String>), ParseError> {
+        let window = &input[..input.len().min(self.budget)];
+        let (taken, complete) = match window.iter().position(|&b| b == b'\n') {
+            Some(end) => (end + 1, true),
+            None => (window.len(), false),
+        };
+        self.line.extend_from_slice(&window[..taken]);
+        self.budget -= taken;
+        if !complete {
+            return match self.budget {
+                0 if self.state == ChunkState::Trailers => Err(ParseError::HeadersTooLarge),
+                0 => Err(ParseError::InvalidChunk(String::from(
+                    "chunk line too long",
+                ))),
+                _ => Ok((taken, None)),
+            };
+        }
+
+        let mut line = std::mem::take(&mut self.line);
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        let line = String::from_utf8(line).map_err(|e| {
+            ParseError::MalformedHeader(String::from_utf8_lossy(e.as_bytes()).into_owned())
+        })?;
+        Ok((taken, Some(line)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Request, ParseError> {
+        Request::read_from(&mut raw.as_bytes())
+    }
+
+    #[test]
+    fn parses_request_line_and_query() {
+        let request = parse("GET /search%20me?q=rust+lang&page=2&flag HTTP/1.1\r\n\r\n").unwrap();
+
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.path, "/search me");
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.query_param("q"), Some("rust lang"));
+        assert_eq!(request.query_param("page"), Some("2"));
+        assert_eq!(request.query_param("flag"), Some(""));
+    }
+
+    #[test]
+    fn headers_are_case_insensitive() {
+        let request = parse("GET / HTTP/1.1\r\nHost: localhost\r\nX-Thing:  yes \r\n\r\n").unwrap();
+
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert_eq!(request.header("x-THING"), Some("yes"));
+    }
+
+    #[test]
+    fn reads_body_by_content_length() {
+        let request = parse("POST /form HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloEXTRA").unwrap();
+
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn reads_chunked_body() {
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nTrailer: x\r\n\r\n";
+        let request = parse(raw).unwrap();
+
+        assert_eq!(request.body, b"Wikipedia");
+    }
+
+    #[test]
+    fn refuses_bodies_over_the_limit() {
+        let read = |raw: &str| {
+            let mut reader = raw.as_bytes();
+            let request = Request::read_head(&mut reader, 1024).unwrap();
+            request.read_body(&mut reader, 8)
+        };
+
+        assert!(read("POST / HTTP/1.1\r\nContent-Length: 8\r\n\r\n12345678").is_ok());
+        assert!(matches!(
+            read("POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n123456789"),
+            Err(ParseError::BodyTooLarge)
+        ));
+        assert!(matches!(
+            read("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n12345\r\n5\r\n67890\r\n0\r\n\r\n"),
+            Err(ParseError::BodyTooLarge)
+        ));
+        assert!(matches!(
+            read("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n123"),
+            Err(ParseError::UnexpectedEof)
+        ));
+
+        // `read_from` keeps to the default limits.
+        let huge = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+        assert!(matches!(parse(&huge), Err(ParseError::BodyTooLarge)));
+        let long = format!(
+            "GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n",
+            "a".repeat(64 * 1024)
+        );
+        assert!(matches!(parse(&long), Err(ParseError::HeadersTooLarge)));
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert!(matches!(parse(""), Err(ParseError::ConnectionClosed)));
+        assert!(matches!(
+            parse("GET /\r\n\r\n"),
+            Err(ParseError::MalformedRequestLine(_))
+        ));
+        assert!(matches!(
+            parse("FETCH / HTTP/1.1\r\n\r\n"),
+            Err(ParseError::InvalidMethod(_))
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/2.0\r\n\r\n"),
+            Err(ParseError::InvalidVersion(_))
+        ));
+        assert!(matches!(
+            parse("GET /%zz HTTP/1.1\r\n\r\n"),
+            Err(ParseError::InvalidPercentEncoding(_))
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nHost localhost\r\n\r\n"),
+            Err(ParseError::MalformedHeader(_))
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhi"),
+            Err(ParseError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 2\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            Err(ParseError::ConflictingBodyLength)
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"),
+            Err(ParseError::InvalidChunk(_))
+        ));
+    }
+
+    #[test]
+    fn bounds_chunk_lines_and_trailers() {
+        let chunked = |rest: &str| {
+            parse(&format!(
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{rest}"
+            ))
+        };
+        let endless = "1".repeat(MAX_CHUNK_LINE_BYTES + 1);
+
+        assert!(matches!(
+            chunked(&endless),
+            Err(ParseError::InvalidChunk(_))
+        ));
+        assert!(matches!(
+            chunked(&format!("2;{endless}\r\nhi\r\n0\r\n\r\n")),
+            Err(ParseError::InvalidChunk(_))
+        ));
+        assert!(matches!(
+            chunked("+2\r\nhi\r\n0\r\n\r\n"),
+            Err(ParseError::InvalidChunk(_))
+        ));
+        let trailers = "X-Trailer: padding\r\n".repeat(MAX_TRAILER_BYTES / 10);
+        assert!(matches!(
+            chunked(&format!("0\r\n{trailers}\r\n")),
+            Err(ParseError::HeadersTooLarge)
+        ));
+        assert!(chunked("2\r\nhi\r\n0\r\nX-Trailer: 1\r\n\r\n").is_ok());
+
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: +2\r\n\r\nhi"),
+            Err(ParseError::InvalidContentLength(_))
+        ));
+    }
+
+    #[test]
+    fn body_reader_stops_where_the_body_does() {
+        let read = |input: &[u8], framing| {
+            let mut input = input;
+            let mut reader = BodyReader::new(&mut input, framing);
+            let mut body = Vec::new();
+            let result = reader.read_to_end(&mut body).map(|_| body);
+            let finished = reader.is_finished();
+            (result, finished, input.to_vec())
+        };
+
+        let (body, finished, rest) = read(
+            b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\nGET /next",
+            Framing::Chunked,
+        );
+        assert_eq!(body.unwrap(), b"hello world");
+        assert!(finished);
+        assert_eq!(rest, b"GET /next");
+
+        let (body, finished, rest) = read(b"helloGET /next", Framing::Length(5));
+        assert_eq!(body.unwrap(), b"hello");
+        assert!(finished);
+        assert_eq!(rest, b"GET /next");
+
+        let (body, finished, _) = read(b"hel", Framing::Length(5));
+        assert_eq!(body.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(!finished);
+        let (body, _, _) = read(b"zz\r\n", Framing::Chunked);
+        assert_eq!(body.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decodes_chunks_a_byte_at_a_time() {
+        let input = b"5\r\nhello\r\n6;name=value\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\nnext";
+        let mut decoder = ChunkedDecoder::new(64);
+        let mut used = 0;
+        while !decoder.is_done() {
+            used += decoder.decode(&input[used..used + 1]).unwrap();
+        }
+
+        assert_eq!(&input[used..], b"next");
+        assert_eq!(decoder.into_body(), b"hello world");
+
+        let mut decoder = ChunkedDecoder::new(8);
+        assert!(matches!(
+            decoder.decode(b"5\r\nhello\r\n5\r\n"),
+            Err(ParseError::BodyTooLarge)
+        ));
+    }
+
+    #[test]
+    fn limits_the_size_of_the_head() {
+        let raw = "GET / HTTP/1.1\r\nX-Padding: aaaaaaaaaaaaaaaa\r\n\r\nbody";
+        assert!(matches!(
+            Request::read_head(&mut raw.as_bytes(), 24),
+            Err(ParseError::HeadersTooLarge)
+        ));
+        assert!(matches!(
+            Request::read_head(&mut raw.as_bytes(), 8),
+            Err(ParseError::HeadersTooLarge)
+        ));
+
+        let mut reader = raw.as_bytes();
+        let request = Request::read_head(&mut reader, 64).unwrap();
+        assert_eq!(request.header("x-padding"), Some("aaaaaaaaaaaaaaaa"));
+        assert_eq!(reader, b"body");
+    }
+}
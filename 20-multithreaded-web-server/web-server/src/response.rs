@@ -0,0 +1,569 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, Read, Write},
+    time::SystemTime,
+};
+
+use crate::{
+    cookie::Cookie,
+    date::format_http_date,
+    request::{self, ParseError},
+    websocket::Upgrade,
+    StatusCode, Version,
+};
+
+/// An HTTP response that handlers build and the server writes back.
+///
+/// Handlers only choose the status, headers and body. Framing headers such
+/// as `Content-Length`, `Transfer-Encoding`, `Connection` and `Date` are
+/// filled in by `write_to`.
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Body,
+    /// Takes over the connection once a `101 Switching Protocols` response
+    /// has been sent.
+    pub(crate) upgrade: Option<Upgrade>,
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: HeaderMap::new(),
+            body: Body::default(),
+            upgrade: None,
+        }
+    }
+
+    /// Adds a header, keeping any earlier values with the same name.
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Adds a `Set-Cookie` header for `cookie`.
+    pub fn with_cookie(self, cookie: &Cookie) -> Response {
+        self.with_header("Set-Cookie", &cookie.to_string())
+    }
+
+    pub fn with_status(mut self, status: StatusCode) -> Response {
+        self.status = status;
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Body>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Returns the first value of the header `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Drops the body for a `HEAD` request while keeping the framing
+    /// headers it would have had.
+    pub fn into_head(mut self) -> Response {
+        let framed =
+            self.headers.contains("content-length") || self.headers.contains("transfer-encoding");
+        if !framed {
+            match self.body.len() {
+                Some(len) => self.headers.insert("Content-Length", &len.to_string()),
+                None => self.headers.insert("Transfer-Encoding", "chunked"),
+            }
+        }
+        self.body = Body::default();
+        self
+    }
+
+    /// Writes the status line, headers and body, returning how many body
+    /// bytes were sent.
+    ///
+    /// Sized bodies get a `Content-Length` unless a handler set one, as for
+    /// `HEAD` responses. Streams are sent chunked to HTTP/1.1 clients; an
+    /// HTTP/1.0 client gets the raw stream and the connection must close
+    /// after it, so callers pass `keep_alive: false` in that case.
+    pub fn write_to<W: Write>(
+        mut self,
+        writer: &mut W,
+        version: Version,
+        keep_alive: bool,
+    ) -> io::Result<u64> {
+        let chunked = self.body.is_stream() && version == Version::Http11;
+        if self.status.is_bodiless() {
+            self.body = Body::default();
+            self.headers.remove("content-length");
+            self.headers.remove("transfer-encoding");
+        } else if self.body.is_stream() {
+            self.headers.remove("content-length");
+            self.headers.remove("transfer-encoding");
+            if chunked {
+                self.headers.insert("Transfer-Encoding", "chunked");
+            }
+        } else if !self.headers.contains("content-length")
+            && !self.headers.contains("transfer-encoding")
+        {
+            let len = self.body.len().unwrap_or(0);
+            self.headers.insert("Content-Length", &len.to_string());
+        }
+        if !self.headers.contains("date") {
+            self.headers
+                .insert("Date", &format_http_date(SystemTime::now()));
+        }
+        // A protocol switch names the new protocol in `Connection` instead.
+        if self.status != StatusCode::SwitchingProtocols {
+            let connection = if keep_alive { "keep-alive" } else { "close" };
+            self.headers.insert("Connection", connection);
+        }
+
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        for (name, value) in self.headers.iter() {
+            if !valid_header(name, value) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid response header {name:?}"),
+                ));
+            }
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+
+        let sent = match self.body {
+            Body::Bytes(bytes) => {
+                writer.write_all(&bytes)?;
+                bytes.len() as u64
+            }
+            Body::File { file, len } => {
+                let copied = io::copy(&mut file.take(len), writer)?;
+                if copied < len {
+                    // The file shrank after Content-Length was sent, so the
+                    // response cannot be completed.
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file ended before its Content-Length",
+                    ));
+                }
+                copied
+            }
+            Body::Stream(chunks) => {
+                let mut sent = 0;
+                for chunk in chunks {
+                    let chunk = chunk?;
+                    // An empty chunk would end a chunked body early.
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    if chunked {
+                        writer.write_all(format!("{:x}\r\n", chunk.len()).as_bytes())?;
+                        writer.write_all(&chunk)?;
+                        writer.write_all(b"\r\n")?;
+                    } else {
+                        writer.write_all(&chunk)?;
+                    }
+                    sent += chunk.len() as u64;
+                }
+                if chunked {
+                    writer.write_all(b"0\r\n\r\n")?;
+                }
+                sent
+            }
+        };
+        writer.flush()?;
+        Ok(sent)
+    }
+}
+
+/// Largest status line and headers accepted by `read_response_head`.
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+
+// The status line and headers of a response read off the wire, as from an
+// upstream.
+pub(crate) struct ResponseHead {
+    pub(crate) status: u16,
+    pub(crate) http10: bool,
+    /// Lowercase names, as `Request::headers`.
+    pub(crate) headers: Vec<(String, String)>,
+}
+
+impl ResponseHead {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub(crate) fn keeps_alive(&self) -> bool {
+        let tokens = connection_tokens(self.header("connection"));
+        if self.http10 {
+            tokens.iter().any(|token| token == "keep-alive")
+        } else {
+            !tokens.iter().any(|token| token == "close")
+        }
+    }
+}
+
+pub(crate) fn read_response_head<R: BufRead>(reader: &mut R) -> Result<ResponseHead, ParseError> {
+    let mut budget = MAX_HEAD_BYTES;
+    let status_line =
+        request::read_line(reader, &mut budget)?.ok_or(ParseError::ConnectionClosed)?;
+    let mut parts = status_line.splitn(3, ' ');
+    let (version, status) = match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) => (version, status),
+        _ => return Err(ParseError::MalformedRequestLine(status_line)),
+    };
+    let http10 = match version {
+        "HTTP/1.0" => true,
+        "HTTP/1.1" => false,
+        _ => return Err(ParseError::InvalidVersion(version.to_string())),
+    };
+    let status = match status.parse::<u16>() {
+        Ok(status) if (100..600).contains(&status) => status,
+        _ => return Err(ParseError::MalformedRequestLine(status_line)),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let line = request::read_line(reader, &mut budget)?.ok_or(ParseError::UnexpectedEof)?;
+        if line.is_empty() {
+            break;
+        }
+        headers.push(request::parse_header(&line)?);
+    }
+    Ok(ResponseHead {
+        status,
+        http10,
+        headers,
+    })
+}
+
+// The header names a `Connection` header lists, lowercased.
+pub(crate) fn connection_tokens(connection: Option<&str>) -> Vec<String> {
+    connection
+        .unwrap_or("")
+        .split(',')
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+fn valid_header(name: &str, value: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_graphic() && b != b':')
+        && !value.bytes().any(|b| b == b'\r' || b == b'\n')
+}
+
+/// Response headers that keep the order they were added in and are looked
+/// up without regard to case.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap::default()
+    }
+
+    /// Returns the first value of `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns every value of `name` in the order they were added.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Sets `name` to `value`, replacing any values it had. The header keeps
+    /// its place if it was already present.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        match self
+            .entries
+            .iter()
+            .position(|(key, _)| key.eq_ignore_ascii_case(name))
+        {
+            Some(index) => {
+                self.entries[index] = (name.to_string(), value.to_string());
+                let mut seen = 0;
+                self.entries.retain(|(key, _)| {
+                    if key.eq_ignore_ascii_case(name) {
+                        seen += 1;
+                        seen == 1
+                    } else {
+                        true
+                    }
+                });
+            }
+            None => self.append(name, value),
+        }
+    }
+
+    /// Adds a value for `name` after any it already has.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    /// Removes every value of `name`, returning the first.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let mut first = None;
+        self.entries.retain(|(key, value)| {
+            if key.eq_ignore_ascii_case(name) {
+                first.get_or_insert_with(|| value.clone());
+                false
+            } else {
+                true
+            }
+        });
+        first
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// The body of a response.
+pub enum Body {
+    /// Bytes already in memory.
+    Bytes(Vec<u8>),
+    /// The next `len` bytes of a file, read from its current position.
+    File { file: File, len: u64 },
+    /// Chunks of unknown total length, sent with chunked encoding.
+    Stream(Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>),
+}
+
+impl Body {
+    /// The rest of `file` from its current position.
+    pub fn file(mut file: File) -> io::Result<Body> {
+        let len = file.metadata()?.len();
+        let position = io::Seek::stream_position(&mut file)?;
+        Ok(Body::File {
+            file,
+            len: len.saturating_sub(position),
+        })
+    }
+
+    pub fn stream<I>(chunks: I) -> Body
+    where
+        I: IntoIterator<Item = io::Result<Vec<u8>>>,
+        I::IntoIter: Send + 'static,
+    {
+        Body::Stream(Box::new(chunks.into_iter()))
+    }
+
+    /// Returns the length, or `None` for a stream.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File { len, .. } => Some(*len),
+            Body::Stream(_) => None,
+        }
+    }
+
+    /// Returns true for a body known to be empty. Streams are never
+    /// considered empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    pub fn is_stream(&self) -> bool {
+        matches!(self, Body::Stream(_))
+    }
+
+    /// Returns the bytes of an in-memory body.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Reads the whole body into memory.
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self {
+            Body::Bytes(bytes) => Ok(bytes),
+            Body::File { file, len } => {
+                let mut bytes = Vec::with_capacity(len as usize);
+                file.take(len).read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
+            Body::Stream(chunks) => {
+                let mut bytes = Vec::new();
+                for chunk in chunks {
+                    bytes.extend_from_slice(&chunk?);
+                }
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Body {
+        Body::Bytes(Vec::new())
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(&bytes.len()).finish(),
+            Body::File { len, .. } => f.debug_struct("File").field("len", len).finish(),
+            Body::Stream(_) => f.write_str("Stream"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Body {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for Body {
+    fn from(bytes: &[u8; N]) -> Body {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Body {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Body {
+        Body::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+impl From<&String> for Body {
+    fn from(text: &String) -> Body {
+        Body::from(text.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(response: Response, version: Version, keep_alive: bool) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out, version, keep_alive).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn headers_ignore_case_and_keep_order() {
+        let mut headers = HeaderMap::new();
+        headers.append("Vary", "Origin");
+        headers.append("Content-Type", "text/plain");
+        headers.append("vary", "Accept-Encoding");
+        assert_eq!(headers.get("VARY"), Some("Origin"));
+        assert_eq!(
+            headers.get_all("vary").collect::<Vec<_>>(),
+            ["Origin", "Accept-Encoding"]
+        );
+
+        headers.insert("VARY", "*");
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [("VARY", "*"), ("Content-Type", "text/plain")]
+        );
+        assert_eq!(
+            headers.remove("content-type").as_deref(),
+            Some("text/plain")
+        );
+        assert_eq!(headers.len(), 1);
+    }
+
+    #[test]
+    fn serializer_adds_framing_headers() {
+        let response = Response::new(StatusCode::Ok)
+            .with_header("Connection", "upgrade")
+            .with_body("hello");
+        let text = written(response, Version::Http11, true);
+        assert!(text.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(text.contains("\r\nContent-Length: 5\r\n"));
+        assert!(text.contains("\r\nDate: "));
+        assert!(text.contains("\r\nConnection: keep-alive\r\n"));
+        assert!(!text.contains("upgrade"));
+        assert!(text.ends_with("\r\n\r\nhello"));
+
+        let text = written(
+            Response::new(StatusCode::NotModified).with_body("stale"),
+            Version::Http11,
+            false,
+        );
+        assert!(!text.contains("Content-Length"));
+        assert!(text.ends_with("Connection: close\r\n\r\n"));
+    }
+
+    #[test]
+    fn streams_are_chunked_for_http_11_only() {
+        let stream = || {
+            Body::stream(vec![
+                Ok(b"Wiki".to_vec()),
+                Ok(Vec::new()),
+                Ok(b"pedia".to_vec()),
+            ])
+        };
+        let text = written(
+            Response::new(StatusCode::Ok).with_body(stream()),
+            Version::Http11,
+            true,
+        );
+        assert!(text.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(!text.contains("Content-Length"));
+        assert!(text.ends_with("\r\n\r\n4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n"));
+
+        let text = written(
+            Response::new(StatusCode::Ok).with_body(stream()),
+            Version::Http10,
+            false,
+        );
+        assert!(!text.contains("Transfer-Encoding"));
+        assert!(text.ends_with("\r\n\r\nWikipedia"));
+    }
+
+    #[test]
+    fn rejects_headers_that_would_split_the_response() {
+        let response = Response::new(StatusCode::Ok).with_header("X-Evil", "a\r\nSet-Cookie: b");
+        let error = response
+            .write_to(&mut Vec::new(), Version::Http11, true)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,523 @@
+use std::{cell::RefCell, io::Read, sync::Arc};
+
+use crate::{
+    middleware::{Chain, Middleware},
+    request::percent_decode,
+    Method, Request, Response, StatusCode,
+};
+
+const METHODS: [Method; 9] = [
+    Method::Get,
+    Method::Head,
+    Method::Post,
+    Method::Put,
+    Method::Delete,
+    Method::Patch,
+    Method::Options,
+    Method::Trace,
+    Method::Connect,
+];
+
+/// Values captured from the path by `:name` and `*name` segments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub type Handler = Box<dyn Fn(&Request, &Params) -> Response + Send + Sync + 'static>;
+
+/// A handler that reads the request body itself, registered with
+/// `Router::streaming`.
+pub type BodyHandler =
+    Box<dyn Fn(&Request, &Params, &mut dyn Read) -> Response + Send + Sync + 'static>;
+
+// What a route runs.
+enum Endpoint {
+    Buffered(Handler),
+    Streaming(BodyHandler),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+struct Route {
+    method: Method,
+    pattern: String,
+    segments: Vec<Segment>,
+    endpoint: Endpoint,
+}
+
+/// Dispatches requests to handlers registered for a method and a path
+/// pattern.
+///
+/// Patterns are split on `/`. A segment written `:id` captures one path
+/// segment and a final `*path` captures the rest of the path. Routes are
+/// tried in the order they were registered.
+///
+/// Middleware added with `middleware` runs around every request, including
+/// ones that end up as `404` or `405`.
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Handler,
+    middleware: Chain,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_, _| Response::new(StatusCode::NotFound).with_body("Not Found")),
+            middleware: Chain::new(),
+        }
+    }
+
+    /// Adds `middleware` to the chain that runs around every request, inside
+    /// the middleware added before it.
+    pub fn middleware<M: Middleware>(&mut self, middleware: M) -> &mut Router {
+        self.middleware.push(middleware);
+        self
+    }
+
+    /// Registers `handler` for requests with `method` whose path matches
+    /// `pattern`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pattern does not start with `/`, names a capture with
+    /// an empty name, or has a `*` segment anywhere but at the end.
+    pub fn route<F>(&mut self, method: Method, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.push(method, pattern, Endpoint::Buffered(Box::new(handler)))
+    }
+
+    /// Registers `handler` like `route`, but leaves the body on the
+    /// connection for the handler to read from its third argument, so an
+    /// upload can be larger than `Limits::max_body_bytes` without being
+    /// held in memory. `Request::body` is empty.
+    ///
+    /// The body must still arrive within `Limits::body_timeout`. A handler
+    /// that stops reading early gets its connection closed after the
+    /// response. Under `Mode::Epoll` the body has been read into memory
+    /// first, within the usual limit, and is read back from there.
+    ///
+    /// # Panics
+    ///
+    /// As for `route`.
+    pub fn streaming<F>(&mut self, method: Method, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params, &mut dyn Read) -> Response + Send + Sync + 'static,
+    {
+        self.push(method, pattern, Endpoint::Streaming(Box::new(handler)))
+    }
+
+    fn push(&mut self, method: Method, pattern: &str, endpoint: Endpoint) -> &mut Router {
+        self.routes.push(Route {
+            method,
+            pattern: pattern.to_string(),
+            segments: parse_pattern(pattern),
+            endpoint,
+        });
+        self
+    }
+
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Registers `handler` for requests with any method whose path matches
+    /// `pattern`.
+    pub fn any<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        for method in METHODS {
+            let handler = Arc::clone(&handler);
+            self.route(method, pattern, move |request, params| {
+                handler(request, params)
+            });
+        }
+        self
+    }
+
+    /// Replaces the handler used when no pattern matches the path.
+    pub fn not_found<F>(&mut self, handler: F) -> &mut Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    /// Runs the handler registered for the request, wrapped in the router's
+    /// middleware.
+    ///
+    /// A `HEAD` request falls back to the `GET` handler with the body
+    /// removed. If the path matches but the method does not, the answer is
+    /// `405 Method Not Allowed` with an `Allow` header listing the methods
+    /// that would have matched.
+    pub fn handle(&self, request: &Request) -> Response {
+        self.handle_with_body(request, &mut &request.body[..])
+    }
+
+    /// Like `handle`, but a route registered with `streaming` reads its body
+    /// from `body` rather than from `Request::body`.
+    pub fn handle_with_body(&self, request: &Request, body: &mut dyn Read) -> Response {
+        // Middleware only sees the request, so the body rides alongside.
+        let body = RefCell::new(body);
+        let dispatch = |request: &Request| self.dispatch(request, *body.borrow_mut());
+        let response = if self.middleware.is_empty() {
+            dispatch(request)
+        } else {
+            self.middleware.run(request, &dispatch)
+        };
+        // Only now, so middleware such as `Compression` sees the body a
+        // `GET` would have had and the headers describe that.
+        if request.method == Method::Head {
+            return response.into_head();
+        }
+        response
+    }
+
+    /// Whether the request goes to a route registered with `streaming`, so
+    /// the server should leave its body unread.
+    pub fn streams_body(&self, request: &Request) -> bool {
+        self.routes
+            .iter()
+            .find(|route| {
+                route.method == request.method
+                    && match_segments(&route.segments, request.raw_path()).is_some()
+            })
+            .is_some_and(|route| matches!(route.endpoint, Endpoint::Streaming(_)))
+    }
+
+    /// Returns the pattern of the first route whose path matches the
+    /// request, whatever its method. Metrics use it to group requests
+    /// without one series per distinct path.
+    pub fn matched_route(&self, request: &Request) -> Option<&str> {
+        self.routes
+            .iter()
+            .find(|route| match_segments(&route.segments, request.raw_path()).is_some())
+            .map(|route| route.pattern.as_str())
+    }
+
+    fn dispatch(&self, request: &Request, body: &mut dyn Read) -> Response {
+        let mut allowed = Vec::new();
+        let mut head_fallback = None;
+
+        for route in &self.routes {
+            let params = match match_segments(&route.segments, request.raw_path()) {
+                Some(params) => params,
+                None => continue,
+            };
+
+            if route.method == request.method {
+                return match &route.endpoint {
+                    Endpoint::Buffered(handler) => handler(request, &params),
+                    Endpoint::Streaming(handler) => handler(request, &params, body),
+                };
+            }
+            if request.method == Method::Head
+                && route.method == Method::Get
+                && head_fallback.is_none()
+            {
+                head_fallback = Some((route, params));
+            }
+            if !allowed.contains(&route.method) {
+                allowed.push(route.method);
+            }
+        }
+
+        if let Some((route, params)) = head_fallback {
+            return match &route.endpoint {
+                Endpoint::Buffered(handler) => handler(request, &params),
+                Endpoint::Streaming(handler) => handler(request, &params, body),
+            };
+        }
+
+        if allowed.is_empty() {
+            return (self.not_found)(request, &Params::default());
+        }
+
+        if allowed.contains(&Method::Get) && !allowed.contains(&Method::Head) {
+            allowed.push(Method::Head);
+        }
+        let allow = allowed
+            .iter()
+            .map(|method| method.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        Response::new(StatusCode::MethodNotAllowed)
+            .with_header("Allow", &allow)
+            .with_body("Method Not Allowed")
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    assert!(
+        pattern.starts_with('/'),
+        "route pattern must start with '/': {pattern}"
+    );
+
+    let parts: Vec<&str> = pattern[1..].split('/').collect();
+    parts
+        .iter()
+        .enumerate()
+        .map(|(i, part)| {
+            if let Some(name) = part.strip_prefix(':') {
+                assert!(!name.is_empty(), "empty parameter name in {pattern}");
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(!name.is_empty(), "empty wildcard name in {pattern}");
+                assert!(
+                    i == parts.len() - 1,
+                    "wildcard must be the last segment in {pattern}"
+                );
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Literal(part.to_string())
+            }
+        })
+        .collect()
+}
+
+// Splits the path as sent before decoding each segment, so an encoded `/`
+// stays inside the segment it was sent in.
+fn match_segments(segments: &[Segment], raw_path: &str) -> Option<Params> {
+    let raw_path = raw_path.strip_prefix('/')?;
+    let mut parts = raw_path.split('/');
+    let mut params = Vec::new();
+
+    for segment in segments {
+        match segment {
+            Segment::Literal(literal) => {
+                if percent_decode(parts.next()?).ok()? != *literal {
+                    return None;
+                }
+            }
+            Segment::Param(name) => {
+                let value = percent_decode(parts.next()?).ok()?;
+                if value.is_empty() {
+                    return None;
+                }
+                params.push((name.clone(), value));
+            }
+            Segment::Wildcard(name) => {
+                let rest = parts.collect::<Vec<_>>().join("/");
+                params.push((name.clone(), percent_decode(&rest).ok()?));
+                return Some(Params(params));
+            }
+        }
+    }
+
+    match parts.next() {
+        None => Some(Params(params)),
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str) -> Request {
+        let raw = format!("{method} {path} HTTP/1.1\r\n\r\n");
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn captures_params_and_wildcards() {
+        let mut router = Router::new();
+        router
+            .get("/users/:id", |_, params| {
+                Response::new(StatusCode::Ok).with_body(params.get("id").unwrap())
+            })
+            .get("/static/*path", |_, params| {
+                Response::new(StatusCode::Ok).with_body(params.get("path").unwrap())
+            });
+
+        assert_eq!(body(router.handle(&request("GET", "/users/42"))), "42");
+        assert_eq!(
+            body(router.handle(&request("GET", "/static/css/site.css"))),
+            "css/site.css"
+        );
+        assert_eq!(
+            router.handle(&request("GET", "/users/42/posts")).status,
+            404
+        );
+        assert_eq!(router.handle(&request("GET", "/users/")).status, 404);
+    }
+
+    #[test]
+    fn decodes_segments_after_splitting() {
+        let mut router = Router::new();
+        router
+            .get("/files/:name", |_, params| {
+                Response::new(StatusCode::Ok).with_body(params.get("name").unwrap())
+            })
+            .get("/files/:dir/:name", |_, _| {
+                Response::new(StatusCode::Conflict)
+            })
+            .get("/my docs/*rest", |_, params| {
+                Response::new(StatusCode::Ok).with_body(params.get("rest").unwrap())
+            });
+
+        assert_eq!(body(router.handle(&request("GET", "/files/a%2Fb"))), "a/b");
+        assert_eq!(
+            body(router.handle(&request("GET", "/files/caf%C3%A9"))),
+            "café"
+        );
+        assert_eq!(
+            body(router.handle(&request("GET", "/my%20docs/x/y%20z"))),
+            "x/y z"
+        );
+        assert_eq!(
+            router.handle(&request("GET", "/files/a/b")).status,
+            StatusCode::Conflict
+        );
+    }
+
+    #[test]
+    fn reports_the_matched_pattern() {
+        let mut router = Router::new();
+        router
+            .get("/users/:id", |_, _| Response::new(StatusCode::Ok))
+            .post("/users", |_, _| Response::new(StatusCode::Created));
+
+        assert_eq!(
+            router.matched_route(&request("GET", "/users/42")),
+            Some("/users/:id")
+        );
+        assert_eq!(
+            router.matched_route(&request("GET", "/users")),
+            Some("/users")
+        );
+        assert_eq!(router.matched_route(&request("GET", "/nope")), None);
+    }
+
+    #[test]
+    fn wrong_method_gives_405_with_allow() {
+        let mut router = Router::new();
+        router
+            .get("/items/:id", |_, _| Response::new(StatusCode::Ok))
+            .delete("/items/:id", |_, _| Response::new(StatusCode::NoContent));
+
+        let response = router.handle(&request("POST", "/items/1"));
+
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("allow"), Some("GET, DELETE, HEAD"));
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let mut router = Router::new();
+        router.get("/", |_, _| Response::new(StatusCode::Ok).with_body("hello"));
+
+        let response = router.handle(&request("HEAD", "/"));
+
+        assert_eq!(response.status, 200);
+        assert!(response.body.is_empty());
+        assert_eq!(response.header("content-length"), Some("5"));
+    }
+
+    #[test]
+    fn head_matches_the_compressed_get() {
+        let mut router = Router::new();
+        router
+            .middleware(crate::Compression::default())
+            .get("/", |_, _| {
+                Response::new(StatusCode::Ok)
+                    .with_header("Content-Type", "text/plain")
+                    .with_body("hello ".repeat(1000))
+            });
+        let send = |method: &str| {
+            let raw = format!("{method} / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+            router.handle(&Request::read_from(&mut raw.as_bytes()).unwrap())
+        };
+
+        let get = send("GET");
+        let head = send("HEAD");
+
+        assert!(head.body.is_empty());
+        assert_eq!(head.header("content-encoding"), Some("gzip"));
+        assert_eq!(
+            head.header("content-length"),
+            Some(get.body.len().unwrap().to_string().as_str())
+        );
+    }
+
+    #[test]
+    fn middleware_wraps_every_request() {
+        let mut router = Router::new();
+        router
+            .middleware(crate::middleware::CatchPanic)
+            .get("/boom", |_, _| panic!("handler failed"))
+            .get(
+                "/wrapped",
+                Chain::new()
+                    .with(crate::middleware::Timing)
+                    .wrap(|_, _| Response::new(StatusCode::Ok)),
+            );
+
+        assert_eq!(router.handle(&request("GET", "/boom")).status, 500);
+        assert!(router
+            .handle(&request("GET", "/wrapped"))
+            .header("server-timing")
+            .is_some());
+        assert_eq!(router.handle(&request("GET", "/missing")).status, 404);
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcard must be the last segment")]
+    fn wildcard_must_be_last() {
+        Router::new().get("/*rest/more", |_, _| Response::new(StatusCode::Ok));
+    }
+}
@@ -0,0 +1,1647 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error, info,
+    logging::{self, AccessEntry},
+    metrics::{self, Counter, Family, Histogram, Registry},
+    pool,
+    request::{self, percent_encode, BodyReader, Framing},
+    warn,
+    websocket::{Socket, Upgrade},
+    Method, ParseError, Request, Response, Router, ShutdownReport, StatusCode, ThreadPool,
+    TryExecuteError, Version, WebSocket,
+};
+
+#[cfg(feature = "tls")]
+use crate::TlsConfig;
+
+#[cfg(target_os = "linux")]
+mod reactor;
+
+/// How long a client turned away with `503` is asked to wait.
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Limits for persistent HTTP/1.1 connections.
+#[derive(Debug, Clone)]
+pub struct KeepAlive {
+    /// How long to wait for the next request before closing the connection.
+    pub idle_timeout: Duration,
+    /// How many requests one connection may send before it is closed, so a
+    /// single client cannot hold a pool worker forever.
+    pub max_requests: usize,
+}
+
+impl Default for KeepAlive {
+    fn default() -> KeepAlive {
+        KeepAlive {
+            idle_timeout: Duration::from_secs(5),
+            max_requests: 100,
+        }
+    }
+}
+
+/// Limits that stop slow or greedy clients from tying up pool workers.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// How long a client has to send the request line and headers. A new
+    /// connection that sends nothing for this long is also answered with
+    /// `408 Request Timeout`.
+    pub header_timeout: Duration,
+    /// How long a client has to send the body once the headers are in,
+    /// answered with `408` when exceeded.
+    pub body_timeout: Duration,
+    /// How long a single write to the client may block before the
+    /// connection is dropped.
+    pub write_timeout: Duration,
+    /// The most bytes the request line and headers may take together,
+    /// answered with `431 Request Header Fields Too Large`.
+    pub max_header_bytes: usize,
+    /// The longest request body read into memory, answered with `413
+    /// Payload Too Large` when exceeded.
+    pub max_body_bytes: usize,
+    /// How many connections one client address may have open at once.
+    /// Further connections are answered with `429` and closed. `None` means
+    /// no limit.
+    pub max_connections_per_ip: Option<usize>,
+    /// How many WebSocket connections may be open at once. Each runs on a
+    /// thread of its own rather than a pool worker; handshakes beyond this
+    /// are answered with `503`.
+    pub max_websockets: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            max_header_bytes: 16 * 1024,
+            max_body_bytes: 10 * 1024 * 1024,
+            max_connections_per_ip: None,
+            max_websockets: 256,
+        }
+    }
+}
+
+/// How a `Server` waits on its connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Each connection holds a pool worker for as long as it is open, so
+    /// the worker count caps how many clients are served at once.
+    #[default]
+    Threads,
+    /// One thread waits on every socket with epoll and hands only complete
+    /// requests to the pool, so idle keep-alive connections cost no worker.
+    /// Responses are built in memory before they are sent. Linux only, and
+    /// HTTPS listeners are not supported.
+    ///
+    /// Pair it with `OverflowPolicy::Reject`: the loop answers a full queue
+    /// with `503`, but a blocking policy would stall every connection.
+    Epoll,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s {
+            "threads" => Ok(Mode::Threads),
+            "epoll" => Ok(Mode::Epoll),
+            _ => Err(format!("expected threads or epoll, got {s:?}")),
+        }
+    }
+}
+
+/// A cloneable handle that stops a running `Server`.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<ShutdownInner>,
+}
+
+#[derive(Default)]
+struct ShutdownInner {
+    triggered: AtomicBool,
+    // Addresses of listeners blocked in accept, which trigger connects to
+    // so the accept call returns.
+    listeners: Mutex<Vec<SocketAddr>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Asks the server to stop accepting connections and drain. Safe to call
+    /// more than once and from any thread.
+    pub fn trigger(&self) {
+        self.inner.triggered.store(true, Ordering::SeqCst);
+
+        for addr in self.inner.listeners.lock().unwrap().iter() {
+            let _ = TcpStream::connect_timeout(addr, Duration::from_secs(1));
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.inner.triggered.load(Ordering::SeqCst)
+    }
+
+    fn register(&self, listener: &TcpListener) -> io::Result<()> {
+        let mut addr = listener.local_addr()?;
+        // A wildcard address cannot be connected to, but loopback reaches it.
+        match addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+            IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+            _ => {}
+        }
+        self.inner.listeners.lock().unwrap().push(addr);
+        // A trigger that came before the listener was registered did not
+        // wake it, so it is woken here instead.
+        if self.is_triggered() {
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+        }
+        Ok(())
+    }
+}
+
+/// A socket the server accepts connections on, and how it talks to them.
+pub struct Listener {
+    socket: TcpListener,
+    kind: ListenerKind,
+}
+
+#[derive(Clone)]
+enum ListenerKind {
+    Plain,
+    // Answers every request with a redirect, ignoring the server's router.
+    Redirect(Arc<Router>),
+    #[cfg(feature = "tls")]
+    Tls(TlsConfig),
+}
+
+impl Listener {
+    /// Plaintext HTTP.
+    pub fn plain(socket: TcpListener) -> Listener {
+        Listener {
+            socket,
+            kind: ListenerKind::Plain,
+        }
+    }
+
+    /// HTTPS, with the handshake done on the pool worker that serves the
+    /// connection.
+    #[cfg(feature = "tls")]
+    pub fn tls(socket: TcpListener, tls: TlsConfig) -> Listener {
+        Listener {
+            socket,
+            kind: ListenerKind::Tls(tls),
+        }
+    }
+
+    /// Plaintext HTTP that sends every request to the same URL over HTTPS
+    /// on `https_port`, with a `308` so the method and body are kept.
+    pub fn redirect_to_https(socket: TcpListener, https_port: u16) -> Listener {
+        let mut router = Router::new();
+        router.not_found(move |request, _| redirect_to_https(request, https_port));
+        Listener {
+            socket,
+            kind: ListenerKind::Redirect(Arc::new(router)),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(socket: TcpListener) -> Listener {
+        Listener::plain(socket)
+    }
+}
+
+/// Accepts connections and hands each one to the `ThreadPool` until its
+/// `Shutdown` handle is triggered.
+pub struct Server {
+    listeners: Vec<Listener>,
+    router: Arc<Router>,
+    pool: ThreadPool,
+    keep_alive: Arc<KeepAlive>,
+    limits: Arc<Limits>,
+    connections: Arc<ConnectionCounts>,
+    websockets: Arc<AtomicUsize>,
+    metrics: Option<Arc<RequestMetrics>>,
+    shutdown: Shutdown,
+    drain_timeout: Duration,
+    mode: Mode,
+}
+
+impl Server {
+    pub fn new(listener: impl Into<Listener>, router: Router, pool: ThreadPool) -> Server {
+        Server {
+            listeners: vec![listener.into()],
+            router: Arc::new(router),
+            pool,
+            keep_alive: Arc::new(KeepAlive::default()),
+            limits: Arc::new(Limits::default()),
+            connections: Arc::default(),
+            websockets: Arc::default(),
+            metrics: None,
+            shutdown: Shutdown::new(),
+            drain_timeout: Duration::from_secs(30),
+            mode: Mode::default(),
+        }
+    }
+
+    /// Also accepts connections from `listener`, sharing the same router
+    /// and pool.
+    pub fn add_listener(mut self, listener: impl Into<Listener>) -> Server {
+        self.listeners.push(listener.into());
+        self
+    }
+
+    pub fn keep_alive(mut self, keep_alive: KeepAlive) -> Server {
+        self.keep_alive = Arc::new(keep_alive);
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Server {
+        self.limits = Arc::new(limits);
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Server {
+        self.mode = mode;
+        self
+    }
+
+    /// Records requests by route and status, their latency, open
+    /// connections and the pool's workers in `registry`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if another server already registered into `registry`.
+    pub fn metrics(mut self, registry: &Registry) -> Server {
+        self.metrics = Some(Arc::new(RequestMetrics {
+            requests: registry.counter_family(
+                "http_requests_total",
+                "Requests answered, by route pattern and status.",
+                &["route", "status"],
+            ),
+            duration: registry.histogram_family(
+                "http_request_duration_seconds",
+                "Time from a request being read to its response being sent.",
+                &["route"],
+                &metrics::DEFAULT_BUCKETS,
+            ),
+        }));
+        let connections = Arc::clone(&self.connections);
+        registry.gauge_fn(
+            "http_open_connections",
+            "Client connections open, including upgraded ones.",
+            move || connections.open.load(Ordering::Relaxed) as f64,
+        );
+        let websockets = Arc::clone(&self.websockets);
+        registry.gauge_fn(
+            "http_open_websockets",
+            "WebSocket connections open.",
+            move || websockets.load(Ordering::Relaxed) as f64,
+        );
+        self.pool.register_metrics(registry, "threadpool");
+        self
+    }
+
+    /// How long in-flight requests get to finish once shutdown starts.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Server {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// The address of the first listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(Listener::local_addr).collect()
+    }
+
+    /// Serves connections in the chosen `Mode`, then drains the pool once
+    /// shutdown is triggered.
+    ///
+    /// # Errors
+    ///
+    /// `Mode::Epoll` fails with `ErrorKind::Unsupported` outside Linux or
+    /// with an HTTPS listener.
+    pub fn run(self) -> io::Result<ShutdownReport> {
+        for listener in &self.listeners {
+            self.shutdown.register(&listener.socket)?;
+        }
+
+        match self.mode {
+            Mode::Threads => thread::scope(|scope| {
+                let (first, rest) = self.listeners.split_first().unwrap();
+                for listener in rest {
+                    scope.spawn(|| self.accept(listener));
+                }
+                self.accept(first);
+            }),
+            #[cfg(target_os = "linux")]
+            Mode::Epoll => reactor::run(&self)?,
+            #[cfg(not(target_os = "linux"))]
+            Mode::Epoll => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "epoll mode needs Linux",
+                ))
+            }
+        }
+
+        info!("Shutting down.");
+        Ok(self.pool.shutdown(self.drain_timeout))
+    }
+
+    fn accept(&self, listener: &Listener) {
+        for stream in listener.socket.incoming() {
+            if self.shutdown.is_triggered() {
+                break;
+            }
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Failed to accept connection: {e}");
+                    continue;
+                }
+            };
+            // Held by the job, so the count drops when the connection ends
+            // or the job is refused.
+            let Some((stream, slot)) = self.admit(listener, stream) else {
+                continue;
+            };
+            // Kept so the acceptor can still answer if the pool is saturated.
+            let overflow = stream.try_clone();
+            let kind = listener.kind.clone();
+            let router = Arc::clone(&self.router);
+            let keep_alive = Arc::clone(&self.keep_alive);
+            let limits = Arc::clone(&self.limits);
+            let websockets = Arc::clone(&self.websockets);
+            let metrics = self.metrics.clone();
+            let shutdown = self.shutdown.clone();
+
+            let job = move || {
+                let shared = Shared {
+                    keep_alive: &keep_alive,
+                    limits: &limits,
+                    websockets: &websockets,
+                    metrics: metrics.as_deref(),
+                    shutdown: &shutdown,
+                };
+                let result = match kind {
+                    ListenerKind::Plain => try_serve_connection(stream, &router, &shared),
+                    ListenerKind::Redirect(redirects) => {
+                        try_serve_connection(stream, &redirects, &shared)
+                    }
+                    #[cfg(feature = "tls")]
+                    ListenerKind::Tls(tls) => try_serve_tls(stream, &tls, &router, &shared),
+                };
+                match result {
+                    // The client keeps its connection slot while the
+                    // WebSocket is open.
+                    Ok(Some(handoff)) => handoff.spawn(slot),
+                    Ok(None) => {}
+                    Err(e) => warn!("Connection error: {e}"),
+                }
+            };
+            if let Err(TryExecuteError::Full(_)) = self.pool.try_execute(job) {
+                if let Ok(stream) = overflow {
+                    listener.turn_away(stream, StatusCode::ServiceUnavailable);
+                }
+            }
+        }
+    }
+
+    // Counts a new connection against its client's limit. `None` means the
+    // client already has too many open and has been turned away.
+    fn admit(
+        &self,
+        listener: &Listener,
+        stream: TcpStream,
+    ) -> Option<(TcpStream, Option<ConnectionSlot>)> {
+        let Ok(peer) = stream.peer_addr() else {
+            return Some((stream, None));
+        };
+        let max = self.limits.max_connections_per_ip;
+        match self.connections.acquire(peer.ip(), max) {
+            Some(slot) => Some((stream, Some(slot))),
+            None => {
+                warn!("Refusing connection from {}: too many open.", peer.ip());
+                listener.turn_away(stream, StatusCode::TooManyRequests);
+                None
+            }
+        }
+    }
+}
+
+impl Listener {
+    // Answers a connection the server will not serve without reading its
+    // request. This runs on the accept thread, so the write is kept short.
+    fn turn_away(&self, mut stream: TcpStream, status: StatusCode) {
+        // A TLS client cannot read a plaintext response, so it is simply
+        // disconnected.
+        #[cfg(feature = "tls")]
+        if let ListenerKind::Tls(_) = self.kind {
+            return;
+        }
+        let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+        let response = Response::new(status)
+            .with_header("Retry-After", &RETRY_AFTER_SECS.to_string())
+            .with_body(status.reason_phrase());
+        if let Err(e) = response.write_to(&mut stream, Version::Http11, false) {
+            warn!("Failed to send {status}: {e}");
+        }
+    }
+}
+
+// Open connections per client address.
+#[derive(Default)]
+struct ConnectionCounts {
+    counts: Mutex<HashMap<IpAddr, usize>>,
+    /// The sum of `counts`, readable without the lock.
+    open: AtomicUsize,
+}
+
+struct ConnectionSlot {
+    counts: Arc<ConnectionCounts>,
+    ip: IpAddr,
+}
+
+impl ConnectionCounts {
+    fn acquire(self: &Arc<Self>, ip: IpAddr, max: Option<usize>) -> Option<ConnectionSlot> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if max.is_some_and(|max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+        self.open.fetch_add(1, Ordering::Relaxed);
+        Some(ConnectionSlot {
+            counts: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.counts.lock().unwrap();
+        self.counts.open.fetch_sub(1, Ordering::Relaxed);
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+// Counts an open WebSocket against `Limits::max_websockets` until dropped.
+struct WebSocketSlot(Arc<AtomicUsize>);
+
+impl WebSocketSlot {
+    fn acquire(open: &Arc<AtomicUsize>, max: usize) -> Option<WebSocketSlot> {
+        open.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+            (n < max).then_some(n + 1)
+        })
+        .ok()?;
+        Some(WebSocketSlot(Arc::clone(open)))
+    }
+}
+
+impl Drop for WebSocketSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// When the current read phase of a connection must be over. Shared between
+// the connection loop, which moves it on, and the stream that enforces it.
+#[derive(Clone, Default)]
+struct Deadline(Arc<Mutex<Option<Instant>>>);
+
+impl Deadline {
+    fn set(&self, timeout: Duration) {
+        *self.0.lock().unwrap() = Some(Instant::now() + timeout);
+    }
+
+    fn clear(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+
+    fn get(&self) -> Option<Instant> {
+        *self.0.lock().unwrap()
+    }
+}
+
+// A socket whose reads time out at a deadline rather than after a quiet
+// spell, so a client trickling in a byte at a time cannot stretch a phase
+// out forever. Without a deadline, as on an upgraded connection, reads
+// wait up to `idle`.
+struct TimedStream {
+    socket: TcpStream,
+    deadline: Deadline,
+    idle: Option<Duration>,
+}
+
+impl TimedStream {
+    fn new(socket: TcpStream, limits: &Limits) -> io::Result<(TimedStream, Deadline)> {
+        socket.set_write_timeout(Some(limits.write_timeout))?;
+        let deadline = Deadline::default();
+        let stream = TimedStream {
+            socket,
+            deadline: deadline.clone(),
+            idle: None,
+        };
+        Ok((stream, deadline))
+    }
+}
+
+impl Read for TimedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline.get() {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"));
+                }
+                Some(remaining)
+            }
+            None => self.idle,
+        };
+        self.socket.set_read_timeout(timeout)?;
+        self.socket
+            .read(buf)
+            .map_err(|e| timed_out(e, "read timed out"))
+    }
+}
+
+impl Write for TimedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket
+            .write(buf)
+            .map_err(|e| timed_out(e, "write timed out"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl Socket for TimedStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.idle = timeout;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Socket for rustls::StreamOwned<rustls::ServerConnection, TimedStream> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+// Sockets report an expired timeout as `WouldBlock` on Unix; name it plainly.
+fn timed_out(e: io::Error, message: &str) -> io::Error {
+    if is_timeout(&e) {
+        io::Error::new(io::ErrorKind::TimedOut, message.to_string())
+    } else {
+        e
+    }
+}
+
+/// Answers requests on `stream` until the client asks to close, goes idle,
+/// reaches `keep_alive.max_requests`, or the server starts shutting down.
+///
+/// Pipelined requests are read one after another from the same buffer, so
+/// their responses go out in the order the requests arrived.
+pub fn serve_connection(
+    stream: TcpStream,
+    router: &Router,
+    keep_alive: &KeepAlive,
+    limits: &Limits,
+    shutdown: &Shutdown,
+) {
+    // Only this connection is counted against `Limits::max_websockets`.
+    let websockets = Arc::default();
+    let shared = Shared {
+        keep_alive,
+        limits,
+        websockets: &websockets,
+        metrics: None,
+        shutdown,
+    };
+    match try_serve_connection(stream, router, &shared) {
+        Ok(Some(handoff)) => handoff.spawn(None),
+        Ok(None) => {}
+        Err(e) => warn!("Connection error: {e}"),
+    }
+}
+
+fn try_serve_connection(
+    stream: TcpStream,
+    router: &Router,
+    shared: &Shared,
+) -> io::Result<Option<Handoff>> {
+    let remote = stream.peer_addr().ok().map(|addr| addr.ip());
+    let (stream, deadline) = TimedStream::new(stream, shared.limits)?;
+    let connection = Connection {
+        remote,
+        deadline,
+        router,
+        shared,
+    };
+    match connection.serve(stream)? {
+        Ending::Closed(_) => Ok(None),
+        Ending::Upgraded(handoff) => Ok(Some(handoff)),
+    }
+}
+
+// The handshake runs on the first read, so it has to finish within the
+// header timeout like any other request.
+#[cfg(feature = "tls")]
+fn try_serve_tls(
+    stream: TcpStream,
+    tls: &TlsConfig,
+    router: &Router,
+    shared: &Shared,
+) -> io::Result<Option<Handoff>> {
+    let remote = stream.peer_addr().ok().map(|addr| addr.ip());
+    let (stream, deadline) = TimedStream::new(stream, shared.limits)?;
+    let tls = tls.accept().map_err(io::Error::other)?;
+    let stream = rustls::StreamOwned::new(tls, stream);
+    let connection = Connection {
+        remote,
+        deadline,
+        router,
+        shared,
+    };
+
+    match connection.serve(stream)? {
+        Ending::Closed(mut stream) => {
+            stream.conn.send_close_notify();
+            stream.flush()?;
+            Ok(None)
+        }
+        Ending::Upgraded(handoff) => Ok(Some(handoff)),
+    }
+}
+
+// Settings and state every connection of a server shares.
+struct Shared<'a> {
+    keep_alive: &'a KeepAlive,
+    limits: &'a Limits,
+    websockets: &'a Arc<AtomicUsize>,
+    metrics: Option<&'a RequestMetrics>,
+    shutdown: &'a Shutdown,
+}
+
+// One client connection, served on a pool worker.
+struct Connection<'a> {
+    remote: Option<IpAddr>,
+    deadline: Deadline,
+    router: &'a Router,
+    shared: &'a Shared<'a>,
+}
+
+// How a connection loop finished with its stream.
+enum Ending<S> {
+    Closed(S),
+    /// A handler switched protocols and now owns the connection.
+    Upgraded(Handoff),
+}
+
+// An upgraded connection on its way to a thread of its own.
+struct Handoff {
+    socket: Box<dyn Socket>,
+    /// Bytes the client sent right after the handshake, already read off
+    /// the socket.
+    buffered: Vec<u8>,
+    upgrade: Upgrade,
+    slot: WebSocketSlot,
+}
+
+impl Handoff {
+    // `connection` is the client's slot under `max_connections_per_ip`,
+    // held until the handler returns.
+    fn spawn(self, connection: Option<ConnectionSlot>) {
+        let spawned = thread::Builder::new()
+            .name(String::from("websocket"))
+            .spawn(move || {
+                let _slots = (self.slot, connection);
+                self.upgrade.run(WebSocket::new(self.socket, self.buffered));
+            });
+        if let Err(e) = spawned {
+            error!("Failed to start WebSocket thread: {e}");
+        }
+    }
+}
+
+impl Connection<'_> {
+    fn serve<S: Socket + 'static>(&self, stream: S) -> io::Result<Ending<S>> {
+        let remote = self.remote;
+        // Responses are written through the reader so bytes of pipelined
+        // requests it has already buffered are not lost.
+        let mut reader = BufReader::new(stream);
+
+        for served in 1.. {
+            let Some((mut request, unread)) = self.read_request(&mut reader, served == 1)? else {
+                break;
+            };
+            let started = Instant::now();
+            let mut body = unread.map(|framing| BodyReader::new(&mut reader, framing));
+            let Prepared {
+                response,
+                request_id,
+                persist,
+                upgrade,
+            } = self
+                .shared
+                .respond(self.router, &mut request, body.as_mut(), served);
+            drop(body);
+            self.deadline.clear();
+            let status = response.status;
+            let bytes = response.write_to(reader.get_mut(), request.version, persist)?;
+            log_access(remote, &request_id, Some(&request), status, bytes, started);
+            self.shared
+                .observe(self.router, Some(&request), status, started);
+
+            if let Some((upgrade, slot)) = upgrade {
+                self.deadline.clear();
+                return Ok(Ending::Upgraded(Handoff {
+                    buffered: reader.buffer().to_vec(),
+                    socket: Box::new(reader.into_inner()),
+                    upgrade,
+                    slot,
+                }));
+            }
+            if !persist {
+                break;
+            }
+        }
+
+        Ok(Ending::Closed(reader.into_inner()))
+    }
+
+    // Reads the next request, answering clients that are too slow or send
+    // too much. `None` means the connection is done. A request for a
+    // streaming route comes with the framing of the body it left unread.
+    fn read_request<S: Read + Write>(
+        &self,
+        reader: &mut BufReader<S>,
+        first: bool,
+    ) -> io::Result<Option<(Request, Option<Framing>)>> {
+        // A new connection has the header timeout to get its first request
+        // in. Between requests the client may idle for longer, and the
+        // header timeout starts once the next request begins.
+        if !first {
+            self.deadline.set(self.shared.keep_alive.idle_timeout);
+            match reader.fill_buf() {
+                Ok([]) => return Ok(None),
+                Ok(_) => {}
+                Err(e) if is_timeout(&e) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+        self.deadline.set(self.shared.limits.header_timeout);
+        let mut request = match Request::read_head(reader, self.shared.limits.max_header_bytes) {
+            Ok(request) => Request {
+                remote: self.remote,
+                ..request
+            },
+            Err(ParseError::ConnectionClosed) => return Ok(None),
+            Err(e) => {
+                self.reject(reader.get_mut(), &e, None)?;
+                return Ok(None);
+            }
+        };
+        self.deadline.set(self.shared.limits.body_timeout);
+        // The handler reads the body itself, still under the body timeout.
+        if self.router.streams_body(&request) {
+            return match request::framing(&request.headers) {
+                Ok(framing) => Ok(Some((request, Some(framing)))),
+                Err(e) => {
+                    self.reject(reader.get_mut(), &e, Some(&request))?;
+                    Ok(None)
+                }
+            };
+        }
+        match request.read_body(reader, self.shared.limits.max_body_bytes) {
+            Ok(body) => request.body = body,
+            Err(e) => {
+                self.reject(reader.get_mut(), &e, Some(&request))?;
+                return Ok(None);
+            }
+        }
+        // Handlers may read nothing more, and writes have their own timeout.
+        self.deadline.clear();
+        Ok(Some((request, None)))
+    }
+
+    // Answers a request that could not be read. The stream cannot be
+    // trusted afterwards, so the connection closes.
+    fn reject<W: Write>(
+        &self,
+        stream: &mut W,
+        error: &ParseError,
+        request: Option<&Request>,
+    ) -> io::Result<()> {
+        let started = Instant::now();
+        let (response, request_id) = rejection(error, self.remote);
+        let status = response.status;
+        let bytes = response.write_to(stream, Version::Http11, false)?;
+        log_access(self.remote, &request_id, request, status, bytes, started);
+        self.shared.observe(self.router, request, status, started);
+        Ok(())
+    }
+}
+
+// A handler's response, with the headers that depend on the connection
+// filled in.
+struct Prepared {
+    response: Response,
+    request_id: String,
+    persist: bool,
+    upgrade: Option<(Upgrade, WebSocketSlot)>,
+}
+
+impl Shared<'_> {
+    // Runs the handler for the `served`th request on a connection and
+    // decides whether the connection stays open after it. `body` is the
+    // unread body of a request for a streaming route.
+    fn respond(
+        &self,
+        router: &Router,
+        request: &mut Request,
+        body: Option<&mut BodyReader>,
+        served: usize,
+    ) -> Prepared {
+        let request_id = request_id(request);
+        let (mut response, unread) = match body {
+            Some(body) => (router.handle_with_body(request, body), !body.is_finished()),
+            None => (router.handle(request), false),
+        };
+        let upgrade = match response.upgrade.take() {
+            Some(upgrade) if response.status == StatusCode::SwitchingProtocols => {
+                match WebSocketSlot::acquire(self.websockets, self.limits.max_websockets) {
+                    Some(slot) => Some((upgrade, slot)),
+                    None => {
+                        warn!(
+                            "Refusing WebSocket: {} already open.",
+                            self.limits.max_websockets
+                        );
+                        response = Response::new(StatusCode::ServiceUnavailable)
+                            .with_header("Retry-After", &RETRY_AFTER_SECS.to_string())
+                            .with_header("Connection", "close")
+                            .with_body("Service Unavailable");
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        // Checked after the handler so a slow request that straddles the
+        // shutdown signal still closes its connection. A handler can also
+        // close it, for example after refusing to read a large body. A
+        // stream sent to an HTTP/1.0 client can only end by closing.
+        let handler_closes = response
+            .header("connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+        let unframed = response.body.is_stream() && request.version == Version::Http10;
+        // What is left of a body the handler stopped reading would be taken
+        // for the next request.
+        let persist = !unread
+            && wants_keep_alive(request)
+            && served < self.keep_alive.max_requests
+            && !self.shutdown.is_triggered()
+            && !handler_closes
+            && !unframed;
+        if request.method == Method::Head {
+            response = response.into_head();
+        }
+        if persist && request.version == Version::Http10 {
+            let remaining = self.keep_alive.max_requests - served;
+            let value = format!(
+                "timeout={}, max={remaining}",
+                self.keep_alive.idle_timeout.as_secs()
+            );
+            response.headers.insert("Keep-Alive", &value);
+        }
+        response.headers.insert("X-Request-Id", &request_id);
+
+        Prepared {
+            response,
+            request_id,
+            persist,
+            upgrade,
+        }
+    }
+
+    // Counts a finished request. Requests that matched no route, or could
+    // not be read at all, share one series so stray paths cannot create
+    // new ones.
+    fn observe(
+        &self,
+        router: &Router,
+        request: Option<&Request>,
+        status: StatusCode,
+        started: Instant,
+    ) {
+        let Some(metrics) = self.metrics else {
+            return;
+        };
+        let route = request
+            .and_then(|request| router.matched_route(request))
+            .unwrap_or("unmatched");
+        metrics
+            .requests
+            .with(&[route, &status.as_u16().to_string()])
+            .inc();
+        metrics
+            .duration
+            .with(&[route])
+            .observe_duration(started.elapsed());
+    }
+}
+
+// The per-request series registered by `Server::metrics`.
+struct RequestMetrics {
+    requests: Arc<Family<Counter>>,
+    duration: Arc<Family<Histogram>>,
+}
+
+// The response to a request that could not be read. The stream cannot be
+// trusted afterwards, so the connection closes.
+fn rejection(error: &ParseError, remote: Option<IpAddr>) -> (Response, String) {
+    let status = match error {
+        ParseError::Io(e) if is_timeout(e) => StatusCode::RequestTimeout,
+        ParseError::HeadersTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+        ParseError::BodyTooLarge => StatusCode::PayloadTooLarge,
+        _ => StatusCode::BadRequest,
+    };
+    match remote {
+        Some(ip) => warn!("{status} for {ip}: {error}"),
+        None => warn!("{status}: {error}"),
+    }
+    let request_id = logging::next_request_id();
+    let response = Response::new(status)
+        .with_header("X-Request-Id", &request_id)
+        .with_body(status.reason_phrase());
+    (response, request_id)
+}
+
+// `request` is `None` when not even the request line could be read.
+fn log_access(
+    remote: Option<IpAddr>,
+    request_id: &str,
+    request: Option<&Request>,
+    status: StatusCode,
+    bytes: u64,
+    started: Instant,
+) {
+    logging::logger().access(&AccessEntry {
+        remote,
+        request_id,
+        method: request.map_or("-", |request| request.method.as_str()),
+        path: request.map_or("-", |request| &request.target),
+        version: request.map_or("-", |request| request.version.as_str()),
+        status: status.as_u16(),
+        bytes,
+        duration: started.elapsed(),
+        worker: pool::current_worker(),
+    });
+}
+
+fn redirect_to_https(request: &Request, https_port: u16) -> Response {
+    let Some(host) = request.header("host").map(strip_port) else {
+        return Response::new(StatusCode::BadRequest).with_body("Missing Host header");
+    };
+    let mut location = match https_port {
+        443 => format!("https://{host}"),
+        port => format!("https://{host}:{port}"),
+    };
+    location.push_str(&percent_encode(&request.path));
+    for (i, (name, value)) in request.query.iter().enumerate() {
+        location.push(if i == 0 { '?' } else { '&' });
+        location.push_str(&percent_encode(name));
+        location.push('=');
+        location.push_str(&percent_encode(value));
+    }
+    Response::new(StatusCode::PermanentRedirect)
+        .with_header("Location", &location)
+        .with_body("Permanent Redirect")
+}
+
+// Removes the port from a Host header, leaving IPv6 literals bracketed.
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    }
+}
+
+// Keeps the id a client or proxy sent, if it is short and printable, so one
+// id can follow a request across services. Otherwise a new one is made and
+// added to the request for handlers to see.
+fn request_id(request: &mut Request) -> String {
+    let valid = |id: &str| {
+        !id.is_empty()
+            && id.len() <= 64
+            && id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+    };
+    match request.header("x-request-id") {
+        Some(id) if valid(id) => id.to_string(),
+        _ => {
+            let id = logging::next_request_id();
+            request.headers.retain(|(name, _)| name != "x-request-id");
+            request
+                .headers
+                .push((String::from("x-request-id"), id.clone()));
+            id
+        }
+    }
+}
+
+// HTTP/1.1 connections persist unless the client says otherwise, while
+// HTTP/1.0 clients have to opt in.
+fn wants_keep_alive(request: &Request) -> bool {
+    let has_token = |token: &str| {
+        request
+            .header("connection")
+            .map(|value| {
+                value
+                    .split(',')
+                    .any(|t| t.trim().eq_ignore_ascii_case(token))
+            })
+            .unwrap_or(false)
+    };
+
+    match request.version {
+        Version::Http11 => !has_token("close"),
+        Version::Http10 => has_token("keep-alive"),
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    fn spawn_server(keep_alive: KeepAlive, limits: Limits) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut router = Router::new();
+            router
+                .get("/:name", |_, params| {
+                    Response::new(StatusCode::Ok).with_body(params.get("name").unwrap())
+                })
+                .streaming(Method::Post, "/peek", |_, _, body| {
+                    let mut start = [0; 2];
+                    body.read_exact(&mut start).unwrap();
+                    Response::new(StatusCode::Ok).with_body(start.to_vec())
+                });
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(stream, &router, &keep_alive, &limits, &Shutdown::new());
+        });
+        addr
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order() {
+        let addr = spawn_server(KeepAlive::default(), Limits::default());
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        stream
+            .write_all(b"GET /one HTTP/1.1\r\n\r\nGET /two HTTP/1.1\r\n\r\nGET /three HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+
+        let one = output.find("\r\n\r\none").unwrap();
+        let two = output.find("\r\n\r\ntwo").unwrap();
+        let three = output.find("\r\n\r\nthree").unwrap();
+        assert!(one < two && two < three);
+        assert_eq!(output.matches("Connection: keep-alive").count(), 2);
+        assert_eq!(output.matches("Connection: close").count(), 1);
+        assert!(output.ends_with("\r\n\r\nthree"));
+        assert!(output.contains("Content-Length: 5\r\n"));
+    }
+
+    #[test]
+    fn closes_after_a_partly_read_body() {
+        let addr = spawn_server(KeepAlive::default(), Limits::default());
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        stream
+            .write_all(b"POST /peek HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\nGET /after HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+
+        assert!(output.contains("Connection: close\r\n"));
+        assert!(output.ends_with("\r\n\r\nhe"));
+    }
+
+    #[test]
+    fn closes_after_max_requests() {
+        let addr = spawn_server(
+            KeepAlive {
+                max_requests: 2,
+                ..KeepAlive::default()
+            },
+            Limits::default(),
+        );
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        stream
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 2);
+        let last = &output[output.rfind("HTTP/1.1").unwrap()..];
+        assert!(last.contains("Connection: close\r\n"));
+        assert!(last.contains("Content-Length: 1\r\n"));
+        assert!(last.ends_with("\r\n\r\nb"));
+    }
+
+    #[test]
+    fn tags_responses_with_a_request_id() {
+        let addr = spawn_server(KeepAlive::default(), Limits::default());
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        stream
+            .write_all(b"GET /a HTTP/1.1\r\nX-Request-Id: upstream-42\r\n\r\nGET /b HTTP/1.1\r\nX-Request-Id: bad id!\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+
+        let ids: Vec<_> = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("X-Request-Id: "))
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(ids[0], "upstream-42");
+        assert_eq!(ids[1].len(), 16);
+    }
+
+    #[test]
+    fn closes_idle_connections() {
+        let addr = spawn_server(
+            KeepAlive {
+                idle_timeout: Duration::from_millis(100),
+                ..KeepAlive::default()
+            },
+            Limits::default(),
+        );
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        stream.write_all(b"GET /a HTTP/1.1\r\n\r\n").unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+
+        // The idle close after a finished request is silent.
+        assert_eq!(output.matches("HTTP/1.1").count(), 1);
+        assert!(output.ends_with("\r\n\r\na"));
+    }
+
+    #[test]
+    fn times_out_slow_clients() {
+        let limits = Limits {
+            header_timeout: Duration::from_millis(200),
+            body_timeout: Duration::from_millis(200),
+            ..Limits::default()
+        };
+        let silent = spawn_server(KeepAlive::default(), limits.clone());
+        let trickling = spawn_server(KeepAlive::default(), limits.clone());
+        let slow_body = spawn_server(KeepAlive::default(), limits);
+
+        let mut output = String::new();
+        TcpStream::connect(silent)
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+
+        // Bytes that keep arriving do not extend the header deadline.
+        let mut stream = TcpStream::connect(trickling).unwrap();
+        for byte in b"GET /a HTTP/1.1\r\nX-Slow: " {
+            if stream.write_all(&[*byte]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        // A byte written after the server gave up may reset the connection
+        // once the response is in.
+        let mut output = String::new();
+        let _ = stream.read_to_string(&mut output);
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(output.contains("Connection: close\r\n"));
+
+        let mut stream = TcpStream::connect(slow_body).unwrap();
+        stream
+            .write_all(b"POST /a HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc")
+            .unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn rejects_oversized_headers() {
+        let addr = spawn_server(
+            KeepAlive::default(),
+            Limits {
+                max_header_bytes: 64,
+                ..Limits::default()
+            },
+        );
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        let request = format!("GET /a HTTP/1.1\r\nCookie: {}\r\n\r\n", "x".repeat(100));
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+
+        assert!(output.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[test]
+    fn rejects_oversized_bodies() {
+        let addr = spawn_server(
+            KeepAlive::default(),
+            Limits {
+                max_body_bytes: 16,
+                ..Limits::default()
+            },
+        );
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        let body = "x".repeat(100);
+        let request = format!("POST /a HTTP/1.1\r\nContent-Length: 100\r\n\r\n{body}");
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+
+        assert!(output.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    }
+
+    #[test]
+    fn caps_connections_per_ip() {
+        let mut router = Router::new();
+        router.get("/", |_, _| Response::new(StatusCode::Ok).with_body("hi"));
+        let server = Server::new(
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            router,
+            ThreadPool::new(2),
+        )
+        .limits(Limits {
+            max_connections_per_ip: Some(1),
+            ..Limits::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run().unwrap());
+
+        let mut held = TcpStream::connect(addr).unwrap();
+        held.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut head = [0; 15];
+        held.read_exact(&mut head).unwrap();
+        assert_eq!(&head, b"HTTP/1.1 200 OK");
+
+        let mut output = String::new();
+        TcpStream::connect(addr)
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        assert!(output.starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+
+        // Closing the first connection frees its slot.
+        held.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        held.read_to_string(&mut String::new()).unwrap();
+        drop(held);
+        let mut output = String::new();
+        for _ in 0..50 {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            output.clear();
+            // A turned-away connection may be reset before the 429 is read.
+            let read = stream.read_to_string(&mut output);
+            if read.is_ok() && !output.contains(" 429 ") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        shutdown.trigger();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_stops_the_accept_loop() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut router = Router::new();
+        router.get("/", |_, _| Response::new(StatusCode::Ok).with_body("hi"));
+        let server = Server::new(listener, router, ThreadPool::new(2));
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run().unwrap());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        shutdown.trigger();
+        let report = running.join().unwrap();
+
+        assert!(output.ends_with("\r\n\r\nhi"));
+        assert_eq!(report.abandoned, 0);
+        assert_eq!(report.completed, 1);
+    }
+
+    #[test]
+    fn serves_every_listener() {
+        let mut router = Router::new();
+        router.get("/", |_, _| Response::new(StatusCode::Ok).with_body("hi"));
+        let server = Server::new(
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            router,
+            ThreadPool::new(2),
+        )
+        .add_listener(TcpListener::bind("127.0.0.1:0").unwrap());
+        let addrs = server.local_addrs().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run().unwrap());
+
+        for addr in &addrs {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut output = String::new();
+            stream.read_to_string(&mut output).unwrap();
+            assert!(output.ends_with("\r\n\r\nhi"));
+        }
+        shutdown.trigger();
+        assert_eq!(running.join().unwrap().completed, 2);
+    }
+
+    #[test]
+    fn redirects_plain_http_to_https() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::new(
+            Listener::redirect_to_https(listener, 8443),
+            Router::new(),
+            ThreadPool::new(1),
+        );
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run().unwrap());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST /a%20b?q=1&r=x%2By HTTP/1.1\r\nHost: example.com:8080\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        shutdown.trigger();
+        running.join().unwrap();
+
+        assert!(output.starts_with("HTTP/1.1 308 Permanent Redirect\r\n"));
+        assert!(output.contains("\r\nLocation: https://example.com:8443/a%20b?q=1&r=x%2By\r\n"));
+        assert_eq!(strip_port("[::1]:80"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn serves_https() {
+        use rustls::pki_types::{pem::PemObject, CertificateDer, ServerName};
+
+        let generated =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let cert = generated.cert.pem();
+        let tls = TlsConfig::from_pem(
+            cert.as_bytes(),
+            generated.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+
+        let mut router = Router::new();
+        router.get("/", |_, _| {
+            Response::new(StatusCode::Ok).with_body("secret")
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::new(Listener::tls(listener, tls), router, ThreadPool::new(1));
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run().unwrap());
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_slice(cert.as_bytes()).unwrap())
+            .unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connection = rustls::ClientConnection::new(
+            Arc::new(client),
+            ServerName::try_from("localhost").unwrap(),
+        )
+        .unwrap();
+        let mut stream = rustls::StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        shutdown.trigger();
+        running.join().unwrap();
+
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("\r\n\r\nsecret"));
+    }
+
+    #[test]
+    fn hands_websockets_their_own_thread() {
+        let mut router = Router::new();
+        router.get("/echo", |request, _| {
+            crate::websocket::upgrade(request, |mut socket| {
+                while let Ok(crate::Message::Text(text)) = socket.recv() {
+                    socket.send_text(&text.to_uppercase()).unwrap();
+                }
+            })
+        });
+        // One worker, which the open WebSocket must not keep busy.
+        let server = Server::new(
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            router,
+            ThreadPool::new(1),
+        )
+        .limits(Limits {
+            max_websockets: 1,
+            ..Limits::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run().unwrap());
+        let handshake = b"GET /echo HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+        // The first frame is sent with the handshake, so it sits in the
+        // HTTP reader's buffer when the connection is handed over.
+        let mut socket = TcpStream::connect(addr).unwrap();
+        let mut request = handshake.to_vec();
+        request.extend([0x81, 0x82, 0, 0, 0, 0, b'h', b'i']);
+        socket.write_all(&request).unwrap();
+        let mut reader = BufReader::new(socket.try_clone().unwrap());
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Connection: Upgrade\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        let mut frame = [0; 4];
+        reader.read_exact(&mut frame).unwrap();
+        assert_eq!(frame, [0x81, 2, b'H', b'I']);
+
+        let mut second = TcpStream::connect(addr).unwrap();
+        second.write_all(handshake).unwrap();
+        let mut output = String::new();
+        second.read_to_string(&mut output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        // A close frame ends the handler, which answers in kind.
+        socket
+            .write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xE8])
+            .unwrap();
+        let mut frame = [0; 4];
+        reader.read_exact(&mut frame).unwrap();
+        assert_eq!(frame, [0x88, 2, 0x03, 0xE8]);
+        shutdown.trigger();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn saturated_pool_answers_503() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pool = ThreadPool::with_config(crate::PoolConfig {
+            min_workers: 1,
+            max_workers: 1,
+            queue_capacity: Some(1),
+            overflow: crate::OverflowPolicy::Reject,
+            ..crate::PoolConfig::default()
+        })
+        .unwrap();
+        let server = Server::new(listener, Router::new(), pool);
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run().unwrap());
+
+        // The first connection holds the worker once it has been answered,
+        // and the second fills the queue.
+        let mut busy = TcpStream::connect(addr).unwrap();
+        busy.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        busy.read_exact(&mut [0; 12]).unwrap();
+        let _queued = TcpStream::connect(addr).unwrap();
+        let mut rejected = TcpStream::connect(addr).unwrap();
+        let mut output = String::new();
+        rejected.read_to_string(&mut output).unwrap();
+
+        assert!(output.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(output.contains("Retry-After: 1\r\n"));
+        drop((busy, _queued));
+        shutdown.trigger();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn records_request_metrics() {
+        let registry = Registry::new();
+        let mut router = Router::new();
+        router.get("/users/:id", |_, _| Response::new(StatusCode::Ok));
+        let server = Server::new(
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            router,
+            ThreadPool::new(2),
+        )
+        .metrics(&registry);
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run().unwrap());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /users/1 HTTP/1.1\r\n\r\n").unwrap();
+        let mut head = [0; 15];
+        stream.read_exact(&mut head).unwrap();
+        assert!(registry.encode().contains("\nhttp_open_connections 1\n"));
+
+        stream
+            .write_all(
+                b"GET /users/2 HTTP/1.1\r\n\r\nGET /missing HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        stream.read_to_string(&mut String::new()).unwrap();
+        // Joining drains the pool, so every request has been counted.
+        shutdown.trigger();
+        running.join().unwrap();
+
+        let text = registry.encode();
+        assert!(text.contains("http_requests_total{route=\"/users/:id\",status=\"200\"} 2\n"));
+        assert!(text.contains("http_requests_total{route=\"unmatched\",status=\"404\"} 1\n"));
+        assert!(text.contains("http_request_duration_seconds_count{route=\"/users/:id\"} 2\n"));
+        assert!(text.contains("\nhttp_open_connections 0\n"));
+        assert!(text.contains("# TYPE threadpool_queued_jobs gauge\n"));
+        assert!(text.contains("\nthreadpool_panicked_jobs_total 0\n"));
+    }
+}
@@ -0,0 +1,899 @@
+//! The epoll event loop behind `Mode::Epoll`.
+//!
+//! One thread owns every socket. It accepts connections, reads requests as
+//! their bytes arrive and writes responses as sockets drain, so a connection
+//! waiting for its next request costs a buffer rather than a worker. Only
+//! complete requests go to the pool. Each job runs the handler, serializes
+//! the response and sends it back over a channel, waking the loop through an
+//! eventfd.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Write},
+    net::{IpAddr, TcpStream},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+use super::{
+    log_access, rejection, ConnectionSlot, Handoff, ListenerKind, Prepared, Server, Shared,
+    TimedStream, WebSocketSlot, RETRY_AFTER_SECS,
+};
+use crate::{
+    error, request::ChunkedDecoder, warn, websocket::Upgrade, ParseError, Request, Response,
+    Router, StatusCode, Version,
+};
+
+/// Listeners use their index as their token, and connections count up from
+/// after the last listener.
+const WAKER: u64 = u64::MAX;
+
+/// How often deadlines are checked, which bounds how late a timeout fires.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Serves every listener of `server` from the current thread until shutdown
+/// is triggered and the open connections have finished or the drain timeout
+/// has passed.
+pub(super) fn run(server: &Server) -> io::Result<()> {
+    Reactor::new(server)?.run()
+}
+
+struct Epoll {
+    fd: OwnedFd,
+}
+
+impl Epoll {
+    fn new() -> io::Result<Epoll> {
+        // SAFETY: epoll_create1 has no preconditions, and the descriptor it
+        // returns is owned by nothing else.
+        let fd = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        Ok(Epoll {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    fn add(&self, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_ADD, fd, token, events)
+    }
+
+    fn modify(&self, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_MOD, fd, token, events)
+    }
+
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    fn control(&self, op: i32, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        let mut event = libc::epoll_event { events, u64: token };
+        // SAFETY: both descriptors are open and `event` outlives the call.
+        cvt(unsafe { libc::epoll_ctl(self.fd.as_raw_fd(), op, fd, &mut event) }).map(drop)
+    }
+
+    // Fills `events` with the ready sockets, waiting at most `timeout`.
+    fn wait(&self, events: &mut Vec<libc::epoll_event>, timeout: Duration) -> io::Result<()> {
+        events.clear();
+        // SAFETY: the kernel writes at most `capacity` events into the
+        // vector's spare capacity, and `set_len` covers only those.
+        let ready = unsafe {
+            libc::epoll_wait(
+                self.fd.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.capacity() as i32,
+                timeout.as_millis() as i32,
+            )
+        };
+        match cvt(ready) {
+            Ok(ready) => {
+                unsafe { events.set_len(ready as usize) };
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn cvt(result: i32) -> io::Result<i32> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+// Wakes the loop from pool threads when a response is ready.
+struct Waker {
+    eventfd: File,
+}
+
+impl Waker {
+    fn new() -> io::Result<Waker> {
+        // SAFETY: as for epoll_create1.
+        let fd = cvt(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })?;
+        Ok(Waker {
+            eventfd: File::from(unsafe { OwnedFd::from_raw_fd(fd) }),
+        })
+    }
+
+    fn wake(&self) {
+        let _ = (&self.eventfd).write(&1u64.to_ne_bytes());
+    }
+
+    fn reset(&self) {
+        let _ = (&self.eventfd).read(&mut [0; 8]);
+    }
+}
+
+// What a pool job sends back to the loop.
+struct Completion {
+    token: u64,
+    /// The serialized response. Empty when the connection should just
+    /// close.
+    output: Vec<u8>,
+    persist: bool,
+    upgrade: Option<(Upgrade, WebSocketSlot)>,
+}
+
+// Delivers a job's completion when dropped, so a handler that panics still
+// tells the loop to close the connection. A job the pool turned away never
+// arms its reply, and the loop answers for it.
+struct Reply {
+    armed: bool,
+    completion: Completion,
+    sender: mpsc::Sender<Completion>,
+    waker: Arc<Waker>,
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let completion = Completion {
+            token: self.completion.token,
+            output: std::mem::take(&mut self.completion.output),
+            persist: self.completion.persist,
+            upgrade: self.completion.upgrade.take(),
+        };
+        let _ = self.sender.send(completion);
+        self.waker.wake();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Between requests. The connection closes quietly at the deadline.
+    Idle,
+    /// Part of a request head has arrived, or a new connection has sent
+    /// nothing yet. The client gets `408` at the deadline.
+    Head,
+    /// The head is in and the body is arriving; also `408` at the deadline.
+    Body,
+    /// A pool worker is running the handler.
+    Handling,
+    /// The response is going out. The connection drops at the deadline.
+    Writing,
+}
+
+struct Conn {
+    stream: TcpStream,
+    remote: Option<IpAddr>,
+    router: Arc<Router>,
+    slot: Option<ConnectionSlot>,
+    input: Vec<u8>,
+    /// A request whose head is in while its body is still arriving, kept
+    /// so that each new packet is not parsed again from the start.
+    pending: Option<Pending>,
+    output: Vec<u8>,
+    written: usize,
+    served: usize,
+    phase: Phase,
+    deadline: Instant,
+    /// Whether the connection closes once `output` is written.
+    closing: bool,
+    upgrade: Option<(Upgrade, WebSocketSlot)>,
+}
+
+impl Conn {
+    fn interest(&self) -> u32 {
+        match self.phase {
+            Phase::Idle | Phase::Head | Phase::Body => (libc::EPOLLIN | libc::EPOLLRDHUP) as u32,
+            Phase::Writing => libc::EPOLLOUT as u32,
+            // Pipelined requests wait in the kernel until this one is done.
+            Phase::Handling => 0,
+        }
+    }
+
+    // Reads everything available. Returns whether the client has stopped
+    // sending.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 8192];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(true),
+                Ok(read) => self.input.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Takes a complete request off the input, moving the deadline on as
+    // the head and then the body start to arrive.
+    fn parse(&mut self, shared: &Shared) -> Result<Option<Request>, ParseError> {
+        let limits = shared.limits;
+        let mut pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => {
+                let mut rest = &self.input[..];
+                let request = match Request::read_head(&mut rest, limits.max_header_bytes) {
+                    Ok(request) => Request {
+                        remote: self.remote,
+                        ..request
+                    },
+                    Err(ParseError::ConnectionClosed | ParseError::UnexpectedEof) => {
+                        let started = self.input.iter().any(|b| !b.is_ascii_whitespace());
+                        if self.phase == Phase::Idle && started {
+                            self.phase = Phase::Head;
+                            self.deadline = Instant::now() + limits.header_timeout;
+                        }
+                        return Ok(None);
+                    }
+                    Err(e) => return Err(e),
+                };
+                let consumed = self.input.len() - rest.len();
+                self.input.drain(..consumed);
+                self.phase = Phase::Body;
+                self.deadline = Instant::now() + limits.body_timeout;
+                Pending::new(request, limits.max_body_bytes)
+            }
+        };
+
+        let body = match &mut pending.chunks {
+            Some(decoder) => {
+                let used = decoder.decode(&self.input)?;
+                self.input.drain(..used);
+                if !decoder.is_done() {
+                    self.pending = Some(pending);
+                    return Ok(None);
+                }
+                pending.chunks.take().unwrap().into_body()
+            }
+            None => {
+                // Checked first so a large announced body is not allocated
+                // again for every packet that brings part of it.
+                let announced = pending
+                    .request
+                    .header("content-length")
+                    .and_then(|length| length.trim().parse::<usize>().ok());
+                match announced {
+                    Some(length) if length > limits.max_body_bytes => {
+                        return Err(ParseError::BodyTooLarge)
+                    }
+                    Some(length) if self.input.len() < length => {
+                        self.pending = Some(pending);
+                        return Ok(None);
+                    }
+                    _ => {}
+                }
+                let mut rest = &self.input[..];
+                match pending.request.read_body(&mut rest, limits.max_body_bytes) {
+                    Ok(body) => {
+                        let consumed = self.input.len() - rest.len();
+                        self.input.drain(..consumed);
+                        body
+                    }
+                    Err(ParseError::UnexpectedEof) => {
+                        self.pending = Some(pending);
+                        return Ok(None);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        Ok(Some(Request {
+            body,
+            ..pending.request
+        }))
+    }
+}
+
+// A request waiting for its body.
+struct Pending {
+    request: Request,
+    /// The decoder for a chunked body, which takes the input as it comes
+    /// rather than once it has all arrived.
+    chunks: Option<ChunkedDecoder>,
+}
+
+impl Pending {
+    fn new(request: Request, max_body_bytes: usize) -> Pending {
+        // Anything else, including a conflicting Content-Length, is left
+        // to `read_body`.
+        let chunked = request.header("content-length").is_none()
+            && request
+                .header("transfer-encoding")
+                .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+        Pending {
+            chunks: chunked.then(|| ChunkedDecoder::new(max_body_bytes)),
+            request,
+        }
+    }
+}
+
+// The loop thread's view of the state every connection shares.
+fn shared(server: &Server) -> Shared<'_> {
+    Shared {
+        keep_alive: &server.keep_alive,
+        limits: &server.limits,
+        websockets: &server.websockets,
+        metrics: server.metrics.as_deref(),
+        shutdown: &server.shutdown,
+    }
+}
+
+// What to do with a connection after an event.
+enum Step {
+    Keep,
+    Close,
+}
+
+struct Reactor<'a> {
+    server: &'a Server,
+    epoll: Epoll,
+    waker: Arc<Waker>,
+    sender: mpsc::Sender<Completion>,
+    receiver: mpsc::Receiver<Completion>,
+    /// The router each listener serves, by token.
+    routers: Vec<Arc<Router>>,
+    connections: HashMap<u64, Conn>,
+    next_token: u64,
+}
+
+impl<'a> Reactor<'a> {
+    fn new(server: &'a Server) -> io::Result<Reactor<'a>> {
+        let epoll = Epoll::new()?;
+        let mut routers = Vec::new();
+        for (token, listener) in server.listeners.iter().enumerate() {
+            let router = match &listener.kind {
+                ListenerKind::Plain => Arc::clone(&server.router),
+                ListenerKind::Redirect(router) => Arc::clone(router),
+                #[cfg(feature = "tls")]
+                ListenerKind::Tls(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "epoll mode does not serve HTTPS",
+                    ))
+                }
+            };
+            routers.push(router);
+            listener.socket.set_nonblocking(true)?;
+            epoll.add(
+                listener.socket.as_raw_fd(),
+                token as u64,
+                libc::EPOLLIN as u32,
+            )?;
+        }
+        let waker = Arc::new(Waker::new()?);
+        epoll.add(waker.eventfd.as_raw_fd(), WAKER, libc::EPOLLIN as u32)?;
+        let (sender, receiver) = mpsc::channel();
+
+        Ok(Reactor {
+            server,
+            epoll,
+            waker,
+            sender,
+            receiver,
+            next_token: routers.len() as u64,
+            routers,
+            connections: HashMap::new(),
+        })
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        let mut events = Vec::with_capacity(1024);
+        let mut next_sweep = Instant::now() + TICK;
+        let mut drain_until = None;
+
+        loop {
+            self.epoll.wait(&mut events, TICK)?;
+            for event in &events {
+                let (token, flags) = (event.u64, event.events);
+                if token == WAKER {
+                    self.waker.reset();
+                    self.complete();
+                } else if token < self.routers.len() as u64 {
+                    if drain_until.is_none() {
+                        self.accept(token as usize);
+                    }
+                } else {
+                    self.ready(token, flags);
+                }
+            }
+
+            let now = Instant::now();
+            if now >= next_sweep {
+                self.expire(now);
+                next_sweep = now + TICK;
+            }
+            if self.server.shutdown.is_triggered() {
+                let until = *drain_until.get_or_insert_with(|| {
+                    for listener in &self.server.listeners {
+                        let _ = self.epoll.delete(listener.socket.as_raw_fd());
+                    }
+                    now + self.server.drain_timeout
+                });
+                // Nobody is waiting on these, so they can go straight away.
+                self.connections.retain(|_, conn| match conn.phase {
+                    Phase::Idle => false,
+                    Phase::Head => !conn.input.is_empty(),
+                    _ => true,
+                });
+                if self.connections.is_empty() || now >= until {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn accept(&mut self, index: usize) {
+        let listener = &self.server.listeners[index];
+        loop {
+            let stream = match listener.socket.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("Failed to accept connection: {e}");
+                    return;
+                }
+            };
+            let Some((stream, slot)) = self.server.admit(listener, stream) else {
+                continue;
+            };
+            let token = self.next_token;
+            self.next_token += 1;
+            let conn = Conn {
+                remote: stream.peer_addr().ok().map(|addr| addr.ip()),
+                stream,
+                router: Arc::clone(&self.routers[index]),
+                slot,
+                input: Vec::new(),
+                pending: None,
+                output: Vec::new(),
+                written: 0,
+                served: 0,
+                // A new connection has the header timeout to send its first
+                // request, as in `Mode::Threads`.
+                phase: Phase::Head,
+                deadline: Instant::now() + self.server.limits.header_timeout,
+                closing: false,
+                upgrade: None,
+            };
+            let registered = conn.stream.set_nonblocking(true).and_then(|_| {
+                self.epoll
+                    .add(conn.stream.as_raw_fd(), token, conn.interest())
+            });
+            match registered {
+                Ok(()) => {
+                    self.connections.insert(token, conn);
+                }
+                Err(e) => warn!("Connection error: {e}"),
+            }
+        }
+    }
+
+    fn ready(&mut self, token: u64, flags: u32) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+        let hung_up = flags & (libc::EPOLLHUP | libc::EPOLLERR) as u32 != 0;
+        let step = match conn.phase {
+            Phase::Idle | Phase::Head | Phase::Body => self.read(token),
+            Phase::Writing => self.write(token),
+            // The client is gone, so the response has nowhere to go.
+            Phase::Handling if hung_up => Step::Close,
+            Phase::Handling => Step::Keep,
+        };
+        self.finish(token, step);
+    }
+
+    fn read(&mut self, token: u64) -> Step {
+        let conn = self.connections.get_mut(&token).unwrap();
+        let ended = match conn.fill() {
+            Ok(ended) => ended,
+            Err(e) => {
+                warn!("Connection error: {e}");
+                return Step::Close;
+            }
+        };
+        // A client that sends its request and then shuts down its side
+        // still gets an answer.
+        if ended {
+            conn.closing = true;
+        }
+        self.next_request(token)
+    }
+
+    // Dispatches the next buffered request, if it has all arrived.
+    fn next_request(&mut self, token: u64) -> Step {
+        let shared = shared(self.server);
+        let conn = self.connections.get_mut(&token).unwrap();
+        let parsed = conn.parse(&shared);
+        match parsed {
+            Ok(Some(request)) => self.dispatch(token, request),
+            Ok(None) if conn.closing => Step::Close,
+            Ok(None) => Step::Keep,
+            Err(e) => self.reject(token, &e),
+        }
+    }
+
+    fn dispatch(&mut self, token: u64, mut request: Request) -> Step {
+        let conn = self.connections.get_mut(&token).unwrap();
+        conn.phase = Phase::Handling;
+        conn.served += 1;
+        let server = self.server;
+        let router = Arc::clone(&conn.router);
+        let keep_alive = Arc::clone(&server.keep_alive);
+        let limits = Arc::clone(&server.limits);
+        let websockets = Arc::clone(&server.websockets);
+        let metrics = server.metrics.clone();
+        let shutdown = server.shutdown.clone();
+        let (remote, served, closing) = (conn.remote, conn.served, conn.closing);
+        let mut reply = Reply {
+            armed: false,
+            completion: Completion {
+                token,
+                output: Vec::new(),
+                persist: false,
+                upgrade: None,
+            },
+            sender: self.sender.clone(),
+            waker: Arc::clone(&self.waker),
+        };
+
+        let job = move || {
+            reply.armed = true;
+            let shared = Shared {
+                keep_alive: &keep_alive,
+                limits: &limits,
+                websockets: &websockets,
+                metrics: metrics.as_deref(),
+                shutdown: &shutdown,
+            };
+            let started = Instant::now();
+            let Prepared {
+                response,
+                request_id,
+                persist,
+                upgrade,
+            } = shared.respond(&router, &mut request, None, served);
+            let persist = persist && !closing;
+            let status = response.status;
+            match response.write_to(&mut reply.completion.output, request.version, persist) {
+                Ok(bytes) => {
+                    log_access(remote, &request_id, Some(&request), status, bytes, started);
+                    shared.observe(&router, Some(&request), status, started);
+                    reply.completion.persist = persist;
+                    reply.completion.upgrade = upgrade;
+                }
+                Err(e) => {
+                    warn!("Connection error: {e}");
+                    reply.completion.output.clear();
+                }
+            }
+        };
+        if server.pool.try_execute(job).is_err() {
+            let response = Response::new(StatusCode::ServiceUnavailable)
+                .with_header("Retry-After", &RETRY_AFTER_SECS.to_string())
+                .with_body("Service Unavailable");
+            return self.respond_directly(token, response).0;
+        }
+        self.update(token)
+    }
+
+    // Answers a request that could not be read, then closes.
+    fn reject(&mut self, token: u64, error: &ParseError) -> Step {
+        let started = Instant::now();
+        let conn = &self.connections[&token];
+        let (remote, router) = (conn.remote, Arc::clone(&conn.router));
+        let (response, request_id) = rejection(error, remote);
+        let status = response.status;
+        let (step, bytes) = self.respond_directly(token, response);
+        log_access(remote, &request_id, None, status, bytes, started);
+        shared(self.server).observe(&router, None, status, started);
+        step
+    }
+
+    // Sends a response from the loop itself, then closes. Also returns the
+    // body size for the access log.
+    fn respond_directly(&mut self, token: u64, response: Response) -> (Step, u64) {
+        let mut output = Vec::new();
+        let Ok(bytes) = response.write_to(&mut output, Version::Http11, false) else {
+            return (Step::Close, 0);
+        };
+        self.connections.get_mut(&token).unwrap().input.clear();
+        (self.start_writing(token, output, false, None), bytes)
+    }
+
+    // Picks up responses finished by the pool.
+    fn complete(&mut self) {
+        while let Ok(completion) = self.receiver.try_recv() {
+            let Completion {
+                token,
+                output,
+                persist,
+                upgrade,
+            } = completion;
+            if !self.connections.contains_key(&token) {
+                continue;
+            }
+            let step = if output.is_empty() {
+                Step::Close
+            } else {
+                self.start_writing(token, output, persist, upgrade)
+            };
+            self.finish(token, step);
+        }
+    }
+
+    fn start_writing(
+        &mut self,
+        token: u64,
+        output: Vec<u8>,
+        persist: bool,
+        upgrade: Option<(Upgrade, WebSocketSlot)>,
+    ) -> Step {
+        let write_timeout = self.server.limits.write_timeout;
+        let conn = self.connections.get_mut(&token).unwrap();
+        conn.output = output;
+        conn.written = 0;
+        conn.closing |= !persist;
+        conn.upgrade = upgrade;
+        conn.phase = Phase::Writing;
+        conn.deadline = Instant::now() + write_timeout;
+        // Most responses fit in the socket buffer, so try before waiting.
+        match self.write(token) {
+            Step::Keep => self.update(token),
+            Step::Close => Step::Close,
+        }
+    }
+
+    fn write(&mut self, token: u64) -> Step {
+        let write_timeout = self.server.limits.write_timeout;
+        let conn = self.connections.get_mut(&token).unwrap();
+        while conn.written < conn.output.len() {
+            match conn.stream.write(&conn.output[conn.written..]) {
+                Ok(0) => return Step::Close,
+                Ok(written) => {
+                    conn.written += written;
+                    conn.deadline = Instant::now() + write_timeout;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Step::Keep,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!("Connection error: {e}");
+                    return Step::Close;
+                }
+            }
+        }
+        conn.output = Vec::new();
+
+        if conn.upgrade.is_some() {
+            self.hand_off(token);
+            return Step::Keep;
+        }
+        if conn.closing {
+            return Step::Close;
+        }
+        conn.phase = Phase::Idle;
+        conn.deadline = Instant::now() + self.server.keep_alive.idle_timeout;
+        match self.next_request(token) {
+            Step::Keep => self.update(token),
+            Step::Close => Step::Close,
+        }
+    }
+
+    // Gives an upgraded connection a blocking socket and a thread of its
+    // own, like `Mode::Threads` does.
+    fn hand_off(&mut self, token: u64) {
+        let mut conn = self.connections.remove(&token).unwrap();
+        let _ = self.epoll.delete(conn.stream.as_raw_fd());
+        let (upgrade, slot) = conn.upgrade.take().unwrap();
+        let stream = conn
+            .stream
+            .set_nonblocking(false)
+            .and_then(|_| TimedStream::new(conn.stream, &self.server.limits));
+        match stream {
+            Ok((stream, _)) => Handoff {
+                socket: Box::new(stream),
+                buffered: conn.input,
+                upgrade,
+                slot,
+            }
+            .spawn(conn.slot),
+            Err(e) => warn!("Connection error: {e}"),
+        }
+    }
+
+    // Brings the epoll registration in line with the connection's phase.
+    fn update(&mut self, token: u64) -> Step {
+        let Some(conn) = self.connections.get(&token) else {
+            return Step::Keep;
+        };
+        match self
+            .epoll
+            .modify(conn.stream.as_raw_fd(), token, conn.interest())
+        {
+            Ok(()) => Step::Keep,
+            Err(e) => {
+                warn!("Connection error: {e}");
+                Step::Close
+            }
+        }
+    }
+
+    fn finish(&mut self, token: u64, step: Step) {
+        if let Step::Close = step {
+            // Dropping the socket also removes it from the epoll set.
+            self.connections.remove(&token);
+        }
+    }
+
+    // Acts on connections whose deadline has passed.
+    fn expire(&mut self, now: Instant) {
+        let expired: Vec<u64> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| conn.phase != Phase::Handling && conn.deadline <= now)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            let step = match self.connections[&token].phase {
+                Phase::Idle => Step::Close,
+                Phase::Head | Phase::Body => {
+                    let timeout = io::Error::new(io::ErrorKind::TimedOut, "read timed out");
+                    self.reject(token, &ParseError::Io(timeout))
+                }
+                Phase::Writing => {
+                    warn!("Connection error: write timed out");
+                    Step::Close
+                }
+                Phase::Handling => Step::Keep,
+            };
+            self.finish(token, step);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{server::Limits, Mode, Shutdown, ThreadPool};
+    use std::{net::TcpListener, thread};
+
+    fn spawn_server(workers: usize, limits: Limits) -> (std::net::SocketAddr, Shutdown) {
+        let mut router = Router::new();
+        router.get("/:name", |_, params| {
+            Response::new(StatusCode::Ok).with_body(params.get("name").unwrap())
+        });
+        router.post("/echo", |request, _| {
+            Response::new(StatusCode::Ok).with_body(request.body.clone())
+        });
+        let server = Server::new(
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            router,
+            ThreadPool::new(workers),
+        )
+        .limits(limits)
+        .mode(Mode::Epoll);
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        thread::spawn(move || server.run().unwrap());
+        (addr, shutdown)
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order() {
+        let (addr, _) = spawn_server(2, Limits::default());
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        stream
+            .write_all(b"GET /one HTTP/1.1\r\n\r\nGET /two HTTP/1.1\r\n\r\nGET /three HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+
+        let one = output.find("\r\n\r\none").unwrap();
+        let two = output.find("\r\n\r\ntwo").unwrap();
+        let three = output.find("\r\n\r\nthree").unwrap();
+        assert!(one < two && two < three);
+        assert_eq!(output.matches("Connection: keep-alive").count(), 2);
+        assert!(output.ends_with("\r\n\r\nthree"));
+    }
+
+    #[test]
+    fn assembles_chunked_bodies_across_packets() {
+        let (addr, _) = spawn_server(1, Limits::default());
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        let pieces: [&[u8]; 6] = [
+            b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"5\r\nhel",
+            b"lo\r",
+            b"\n6;ext\r\n worl",
+            b"d\r\n0\r\nX-Trailer: yes\r\n",
+            b"\r\nGET /after HTTP/1.1\r\nConnection: close\r\n\r\n",
+        ];
+        for piece in pieces {
+            stream.write_all(piece).unwrap();
+            stream.flush().unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+
+        let echo = output.find("\r\n\r\nhello world").unwrap();
+        assert!(output.ends_with("\r\n\r\nafter"));
+        assert!(echo < output.len() - "after".len());
+    }
+
+    #[test]
+    fn holds_idle_connections_without_workers() {
+        let (addr, _) = spawn_server(1, Limits::default());
+
+        // With one worker, thread mode could serve only one of these.
+        let mut idle: Vec<_> = (0..200)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect();
+        for stream in &mut idle {
+            stream.write_all(b"GET /a HTTP/1.1\r\n\r\n").unwrap();
+            let mut head = [0; 15];
+            stream.read_exact(&mut head).unwrap();
+            assert_eq!(&head, b"HTTP/1.1 200 OK");
+        }
+
+        let mut output = String::new();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /b HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        stream.read_to_string(&mut output).unwrap();
+        assert!(output.ends_with("\r\n\r\nb"));
+    }
+
+    #[test]
+    fn times_out_silent_clients() {
+        let (addr, _) = spawn_server(
+            1,
+            Limits {
+                header_timeout: Duration::from_millis(200),
+                ..Limits::default()
+            },
+        );
+
+        let mut output = String::new();
+        TcpStream::connect(addr)
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn closes_idle_connections_on_shutdown() {
+        let (addr, shutdown) = spawn_server(1, Limits::default());
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /a HTTP/1.1\r\n\r\n").unwrap();
+        let mut head = [0; 15];
+        stream.read_exact(&mut head).unwrap();
+
+        shutdown.trigger();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        assert!(output.ends_with("\r\n\r\na"));
+    }
+}
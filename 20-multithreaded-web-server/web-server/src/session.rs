@@ -0,0 +1,637 @@
+//! Server-side sessions, identified by a signed cookie.
+//!
+//! A handler loads the session for a request with `Sessions::load`, reads
+//! and changes it, and hands it back with the response to `Sessions::save`,
+//! which stores it and sets the cookie. Only the session id travels to the
+//! client; the values stay in a `SessionStore`. Every save pushes the
+//! expiry back by the session's time to live, and a sweeper started with
+//! `Sessions::sweeper` deletes the sessions nobody came back for.
+//!
+//! ```
+//! use web_server::session::{MemoryStore, Sessions};
+//! use web_server::{Key, Response, Router, StatusCode};
+//!
+//! let sessions = Sessions::new(MemoryStore::default(), Key::generate());
+//! let mut router = Router::new();
+//! let login = sessions.clone();
+//! router.post("/login", move |request, _| {
+//!     let mut session = login.load(request);
+//!     // A new id on login stops a planted one from being reused.
+//!     session.renew();
+//!     session.insert("user", "ferris");
+//!     login.save(session, Response::new(StatusCode::NoContent))
+//! });
+//! router.get("/me", move |request, _| {
+//!     match sessions.load(request).get("user") {
+//!         Some(user) => Response::new(StatusCode::Ok).with_body(user.to_string()),
+//!         None => Response::new(StatusCode::Unauthorized),
+//!     }
+//! });
+//! ```
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde_json::{json, Value};
+use tempfile::NamedTempFile;
+
+use crate::{
+    debug, error, warn, Cookie, Key, Request, Response, SameSite, ScheduledHandle, StatusCode,
+    ThreadPool,
+};
+
+/// Random bytes in a session id.
+const ID_BYTES: usize = 32;
+
+/// The longest `Sessions::ttl`.
+const MAX_TTL: Duration = Duration::from_secs(400 * 24 * 60 * 60);
+
+/// What a store keeps for one session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionData {
+    pub values: HashMap<String, String>,
+    /// When the session ends unless saved again first.
+    pub expires: SystemTime,
+}
+
+/// Where sessions are kept between requests.
+///
+/// Ids passed to a store are made by `Sessions` from letters, digits, `-`
+/// and `_`, so they are safe to use in file names and keys.
+pub trait SessionStore: Send + Sync + 'static {
+    /// Returns the session `id`, whether or not it has expired.
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>>;
+
+    fn save(&self, id: &str, data: &SessionData) -> io::Result<()>;
+
+    /// Deletes the session `id`. Deleting one that is not there succeeds.
+    fn remove(&self, id: &str) -> io::Result<()>;
+
+    /// Deletes every session that expired before `now`, returning how many
+    /// there were.
+    fn sweep(&self, now: SystemTime) -> io::Result<usize>;
+}
+
+// A store shared with the rest of the application.
+impl<S: SessionStore + ?Sized> SessionStore for Arc<S> {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>> {
+        (**self).load(id)
+    }
+
+    fn save(&self, id: &str, data: &SessionData) -> io::Result<()> {
+        (**self).save(id, data)
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        (**self).remove(id)
+    }
+
+    fn sweep(&self, now: SystemTime) -> io::Result<usize> {
+        (**self).sweep(now)
+    }
+}
+
+/// Keeps sessions in memory, so they are lost when the process exits.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, SessionData>>,
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>> {
+        Ok(self.sessions.lock().unwrap().get(id).cloned())
+    }
+
+    fn save(&self, id: &str, data: &SessionData) -> io::Result<()> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), data.clone());
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn sweep(&self, now: SystemTime) -> io::Result<usize> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, data| data.expires > now);
+        Ok(before - sessions.len())
+    }
+}
+
+/// Keeps each session in a JSON file of its own, so sessions survive a
+/// restart and can be shared by processes on one machine.
+///
+/// Files are replaced in one step when saved, so a reader never sees one
+/// half written. Expiry is stored to the second.
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// A store in `dir`, which is created if missing. Nothing else should
+    /// write `.json` files there.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `dir` cannot be created.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<FileStore> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileStore { dir })
+    }
+
+    fn path(&self, id: &str) -> io::Result<PathBuf> {
+        if !is_valid_id(id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid session id {id:?}"),
+            ));
+        }
+        Ok(self.dir.join(format!("{id}.json")))
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>> {
+        match fs::read(self.path(id)?) {
+            Ok(bytes) => decode(&bytes).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self, id: &str, data: &SessionData) -> io::Result<()> {
+        let path = self.path(id)?;
+        let mut file = NamedTempFile::new_in(&self.dir)?;
+        file.write_all(&encode(data))?;
+        file.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(id)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn sweep(&self, now: SystemTime) -> io::Result<usize> {
+        let mut swept = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            // A file that cannot be read back is no use to anyone either.
+            let expired = match fs::read(&path) {
+                Ok(bytes) => decode(&bytes).map_or(true, |data| data.expires <= now),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if expired {
+                match fs::remove_file(&path) {
+                    Ok(()) => swept += 1,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(swept)
+    }
+}
+
+fn encode(data: &SessionData) -> Vec<u8> {
+    let expires = data
+        .expires
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    json!({ "expires": expires, "values": data.values })
+        .to_string()
+        .into_bytes()
+}
+
+fn decode(bytes: &[u8]) -> io::Result<SessionData> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed session file");
+    let value: Value = serde_json::from_slice(bytes).map_err(|_| invalid())?;
+    let expires = value["expires"].as_u64().ok_or_else(invalid)?;
+    let values = value["values"]
+        .as_object()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+        .collect::<Option<_>>()
+        .ok_or_else(invalid)?;
+    Ok(SessionData {
+        values,
+        expires: UNIX_EPOCH
+            .checked_add(Duration::from_secs(expires))
+            .ok_or_else(invalid)?,
+    })
+}
+
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+// A new unguessable id, 43 characters of URL-safe base64.
+fn new_id() -> String {
+    let mut bytes = [0; ID_BYTES];
+    getrandom::getrandom(&mut bytes).expect("Failed to read random bytes");
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// The session of one request, read and changed by a handler.
+#[derive(Debug, Default)]
+pub struct Session {
+    /// None until the session is first saved.
+    id: Option<String>,
+    values: HashMap<String, String>,
+    renew: bool,
+    destroyed: bool,
+}
+
+impl Session {
+    /// Whether the client had no session yet, or one that had expired.
+    pub fn is_new(&self) -> bool {
+        self.id.is_none()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.values.remove(key)
+    }
+
+    /// Gives the session a new id when it is saved, keeping its values.
+    /// Call it when a user logs in.
+    pub fn renew(&mut self) {
+        self.renew = true;
+    }
+
+    /// Deletes the session when it is saved and tells the client to forget
+    /// the cookie. Call it when a user logs out.
+    pub fn destroy(&mut self) {
+        self.values.clear();
+        self.destroyed = true;
+    }
+}
+
+/// Loads and saves sessions in a store, and sets the cookie that names
+/// them.
+///
+/// The cookie is `HttpOnly` and signed with a `Key`, so ids a client makes
+/// up are turned away before they reach the store. Cloning is cheap, and
+/// clones share the store.
+#[derive(Clone)]
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    key: Key,
+    cookie_name: String,
+    path: String,
+    ttl: Duration,
+    secure: bool,
+    same_site: SameSite,
+}
+
+impl Sessions {
+    /// Sessions in `store` that last 24 hours from the last save, named by a
+    /// `session` cookie on every path with `SameSite=Lax`.
+    pub fn new(store: impl SessionStore, key: Key) -> Sessions {
+        Sessions {
+            store: Arc::new(store),
+            key,
+            cookie_name: String::from("session"),
+            path: String::from("/"),
+            ttl: Duration::from_secs(24 * 60 * 60),
+            secure: false,
+            same_site: SameSite::Lax,
+        }
+    }
+
+    pub fn cookie_name(mut self, name: &str) -> Sessions {
+        self.cookie_name = name.to_string();
+        self
+    }
+
+    /// Limits the cookie to paths under `path`.
+    pub fn path(mut self, path: &str) -> Sessions {
+        self.path = path.to_string();
+        self
+    }
+
+    /// How long a session lasts after it was last saved, at most 400 days,
+    /// which is as long as browsers keep a cookie.
+    pub fn ttl(mut self, ttl: Duration) -> Sessions {
+        self.ttl = ttl.min(MAX_TTL);
+        self
+    }
+
+    /// Only sends the cookie over HTTPS. Turn it on whenever the site is
+    /// served over TLS.
+    pub fn secure(mut self, secure: bool) -> Sessions {
+        self.secure = secure;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Sessions {
+        self.same_site = same_site;
+        self
+    }
+
+    /// Returns the session named by the request's cookie, or a new empty
+    /// one if there is none, it has expired or the cookie is not valid.
+    ///
+    /// A store that fails is logged and treated as having no session.
+    pub fn load(&self, request: &Request) -> Session {
+        let Some(id) = self
+            .key
+            .signed_cookie(request, &self.cookie_name)
+            .filter(|id| is_valid_id(id))
+        else {
+            return Session::default();
+        };
+        match self.store.load(id) {
+            Ok(Some(data)) if data.expires > SystemTime::now() => Session {
+                id: Some(id.to_string()),
+                values: data.values,
+                ..Session::default()
+            },
+            Ok(_) => Session::default(),
+            Err(e) => {
+                error!("Failed to load a session: {e}");
+                Session::default()
+            }
+        }
+    }
+
+    /// Stores `session` and adds the cookie for it to `response`.
+    ///
+    /// A new session with nothing in it is not stored, so clients that
+    /// never log in get no cookie. If the store fails, the error is logged
+    /// and the client gets `500 Internal Server Error` instead.
+    pub fn save(&self, session: Session, response: Response) -> Response {
+        match self.try_save(session) {
+            Ok(Some(cookie)) => response.with_cookie(&cookie),
+            Ok(None) => response,
+            Err(e) => {
+                error!("Failed to save a session: {e}");
+                Response::new(StatusCode::InternalServerError)
+            }
+        }
+    }
+
+    fn try_save(&self, session: Session) -> io::Result<Option<Cookie>> {
+        let old = session.id.as_deref();
+        if session.destroyed {
+            return match old {
+                Some(id) => {
+                    self.store.remove(id)?;
+                    let removal = Cookie::removal(&self.cookie_name).path(&self.path);
+                    Ok(Some(removal))
+                }
+                None => Ok(None),
+            };
+        }
+        if old.is_none() && session.values.is_empty() {
+            return Ok(None);
+        }
+
+        let id = match old {
+            Some(id) if !session.renew => id.to_string(),
+            _ => new_id(),
+        };
+        let data = SessionData {
+            values: session.values,
+            expires: SystemTime::now() + self.ttl,
+        };
+        self.store.save(&id, &data)?;
+        if let Some(old) = old.filter(|old| *old != id) {
+            self.store.remove(old)?;
+        }
+
+        let cookie = Cookie::new(&self.cookie_name, &id)
+            .path(&self.path)
+            .max_age(self.ttl)
+            .secure(self.secure)
+            .http_only(true)
+            .same_site(self.same_site);
+        Ok(Some(self.key.sign(cookie)))
+    }
+
+    /// Deletes expired sessions from the store on a worker of `pool` every
+    /// `interval`, until the handle is cancelled or the pool shuts down.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn sweeper(&self, pool: &ThreadPool, interval: Duration) -> ScheduledHandle {
+        let store = Arc::clone(&self.store);
+        pool.execute_every(interval, move || match store.sweep(SystemTime::now()) {
+            Ok(0) => {}
+            Ok(swept) => debug!("Swept {swept} expired sessions"),
+            Err(e) => warn!("Failed to sweep expired sessions: {e}"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(cookie: Option<&str>) -> Request {
+        let header = cookie.map_or(String::new(), |cookie| format!("Cookie: {cookie}\r\n"));
+        let raw = format!("GET / HTTP/1.1\r\n{header}\r\n");
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    // Sends back the cookie a response set, as a browser would.
+    fn follow(response: &Response) -> Request {
+        let set = response.header("set-cookie").unwrap();
+        request(Some(set.split(';').next().unwrap()))
+    }
+
+    fn ok() -> Response {
+        Response::new(StatusCode::Ok)
+    }
+
+    fn data(expires: SystemTime) -> SessionData {
+        SessionData {
+            values: HashMap::from([(String::from("user"), String::from("ann"))]),
+            expires,
+        }
+    }
+
+    #[test]
+    fn keeps_values_between_requests() {
+        let sessions = Sessions::new(MemoryStore::default(), Key::generate()).secure(true);
+
+        let session = sessions.load(&request(None));
+        assert!(session.is_new());
+        assert!(sessions.save(session, ok()).header("set-cookie").is_none());
+
+        let mut session = sessions.load(&request(None));
+        session.insert("user", "ann");
+        let response = sessions.save(session, ok());
+        let set = response.header("set-cookie").unwrap();
+        assert!(set.starts_with("session="));
+        assert!(set.ends_with("; Path=/; Max-Age=86400; Secure; HttpOnly; SameSite=Lax"));
+
+        let session = sessions.load(&follow(&response));
+        assert!(!session.is_new());
+        assert_eq!(session.get("user"), Some("ann"));
+
+        let forever = sessions.ttl(Duration::MAX);
+        let mut session = forever.load(&request(None));
+        session.insert("user", "ann");
+        let set = forever
+            .save(session, ok())
+            .header("set-cookie")
+            .unwrap()
+            .to_string();
+        assert!(set.contains("; Max-Age=34560000;"), "{set}");
+    }
+
+    #[test]
+    fn turns_away_forged_and_expired_cookies() {
+        let store = Arc::new(MemoryStore::default());
+        let key = Key::new(&[1; 32]);
+        let sessions = Sessions::new(Arc::clone(&store), key.clone());
+        store
+            .save(
+                "guessed",
+                &data(SystemTime::now() + Duration::from_secs(60)),
+            )
+            .unwrap();
+        store.save("stale", &data(UNIX_EPOCH)).unwrap();
+
+        assert!(sessions.load(&request(Some("session=guessed"))).is_new());
+        let stale = key.sign(Cookie::new("session", "stale"));
+        assert!(sessions
+            .load(&request(Some(&format!("session={}", stale.value()))))
+            .is_new());
+        let signed = key.sign(Cookie::new("session", "guessed"));
+        let session = sessions.load(&request(Some(&format!("session={}", signed.value()))));
+        assert_eq!(session.get("user"), Some("ann"));
+    }
+
+    #[test]
+    fn renews_and_destroys_sessions() {
+        let store = Arc::new(MemoryStore::default());
+        let sessions = Sessions::new(Arc::clone(&store), Key::generate()).path("/app");
+        let mut session = sessions.load(&request(None));
+        session.insert("cart", "3");
+        let first = follow(&sessions.save(session, ok()));
+
+        let mut session = sessions.load(&first);
+        session.renew();
+        session.insert("user", "ann");
+        let second = follow(&sessions.save(session, ok()));
+        assert_ne!(first.cookie("session"), second.cookie("session"));
+        assert!(sessions.load(&first).is_new());
+        let session = sessions.load(&second);
+        assert_eq!(session.get("cart"), Some("3"));
+        assert_eq!(session.get("user"), Some("ann"));
+
+        let mut session = session;
+        session.destroy();
+        let response = sessions.save(session, ok());
+        assert_eq!(
+            response.header("set-cookie"),
+            Some("session=; Path=/app; Max-Age=0")
+        );
+        assert!(sessions.load(&second).is_new());
+        assert_eq!(store.sweep(SystemTime::now()).unwrap(), 0);
+        assert!(store.sessions.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn file_store_survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let later = UNIX_EPOCH + Duration::from_secs(4_000_000_000);
+        FileStore::new(dir.path())
+            .unwrap()
+            .save("abc-_1", &data(later))
+            .unwrap();
+
+        let store = FileStore::new(dir.path()).unwrap();
+        assert_eq!(store.load("abc-_1").unwrap(), Some(data(later)));
+        assert_eq!(store.load("missing").unwrap(), None);
+        assert!(store.load("../escape").is_err());
+        let huge = format!(r#"{{"expires": {}, "values": {{}}}}"#, u64::MAX);
+        fs::write(dir.path().join("huge.json"), huge).unwrap();
+        assert_eq!(
+            store.load("huge").unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(store.sweep(SystemTime::now()).unwrap(), 1);
+        store.remove("abc-_1").unwrap();
+        store.remove("abc-_1").unwrap();
+        assert_eq!(store.load("abc-_1").unwrap(), None);
+    }
+
+    #[test]
+    fn sweeps_expired_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+        let stores: [Box<dyn SessionStore>; 2] = [
+            Box::new(MemoryStore::default()),
+            Box::new(FileStore::new(dir.path()).unwrap()),
+        ];
+        for store in &stores {
+            store
+                .save("old", &data(now - Duration::from_secs(5)))
+                .unwrap();
+            store
+                .save("new", &data(now + Duration::from_secs(5)))
+                .unwrap();
+
+            assert_eq!(store.sweep(now).unwrap(), 1);
+            assert_eq!(store.load("old").unwrap(), None);
+            assert!(store.load("new").unwrap().is_some());
+        }
+        fs::write(dir.path().join("junk.json"), "{").unwrap();
+        fs::write(dir.path().join("notes.txt"), "kept").unwrap();
+        assert_eq!(stores[1].sweep(now).unwrap(), 1);
+        assert!(dir.path().join("notes.txt").exists());
+    }
+
+    #[test]
+    fn sweeper_runs_on_the_pool() {
+        let store = Arc::new(MemoryStore::default());
+        store.save("old", &data(UNIX_EPOCH)).unwrap();
+        let sessions = Sessions::new(Arc::clone(&store), Key::generate());
+        let pool = ThreadPool::new(1);
+
+        let sweeper = sessions.sweeper(&pool, Duration::from_millis(10));
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while store.load("old").unwrap().is_some() {
+            assert!(std::time::Instant::now() < deadline, "sweeper never ran");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        sweeper.cancel();
+    }
+}
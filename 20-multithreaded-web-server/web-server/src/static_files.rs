@@ -0,0 +1,515 @@
+use std::{
+    fs::{self, File, Metadata},
+    io::{self, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    compression::{self, negotiate},
+    date::{format_http_date, parse_http_date},
+    error,
+    request::percent_encode,
+    Body, Encoding, Request, Response, StatusCode,
+};
+
+/// Compressed siblings looked for next to each file, most preferred first.
+const PRECOMPRESSED: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+/// Serves files from a directory on disk.
+///
+/// Mount it on a wildcard route and pass the captured path to `serve`:
+///
+/// ```no_run
+/// use web_server::{Router, StaticFiles};
+///
+/// let files = StaticFiles::new("./front-end");
+/// let mut router = Router::new();
+/// router.get("/static/*path", move |request, params| {
+///     files.serve(request, params.get("path").unwrap_or(""))
+/// });
+/// ```
+///
+/// A file with a compressed copy beside it, such as `app.js.br` or
+/// `app.js.gz`, is served from that copy to clients that accept its
+/// encoding.
+pub struct StaticFiles {
+    root: PathBuf,
+    index: String,
+    precompressed: bool,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles {
+            root: root.into(),
+            index: String::from("index.html"),
+            precompressed: true,
+        }
+    }
+
+    /// Changes the file served for a directory, `index.html` by default.
+    pub fn index(mut self, index: &str) -> StaticFiles {
+        self.index = index.to_string();
+        self
+    }
+
+    /// Turns off serving `.br` and `.gz` siblings, which is on by default.
+    pub fn precompressed(mut self, enabled: bool) -> StaticFiles {
+        self.precompressed = enabled;
+        self
+    }
+
+    /// Answers `request` with the file at `relative` under the root.
+    ///
+    /// Paths containing `..` are refused with `403`, and so is anything that
+    /// resolves outside the root through a symlink.
+    pub fn serve(&self, request: &Request, relative: &str) -> Response {
+        let mut path = match self.resolve(relative) {
+            Some(path) => path,
+            None => return Response::new(StatusCode::Forbidden).with_body("Forbidden"),
+        };
+
+        let mut metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Response::new(StatusCode::NotFound).with_body("Not Found"),
+        };
+
+        if metadata.is_dir() {
+            // Relative links in the index only work from a URL ending in '/'.
+            if !request.path.ends_with('/') {
+                let mut location = format!("{}/", percent_encode(&request.path));
+                if let Some((_, query)) = request.target.split_once('?') {
+                    location.push('?');
+                    location.push_str(query);
+                }
+                return Response::new(StatusCode::MovedPermanently)
+                    .with_header("Location", &location);
+            }
+            path.push(&self.index);
+            metadata = match fs::metadata(&path) {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => return Response::new(StatusCode::NotFound).with_body("Not Found"),
+            };
+        }
+
+        let siblings = if self.precompressed {
+            self.precompressed_siblings(&path)
+        } else {
+            Vec::new()
+        };
+        let offered: Vec<Encoding> = siblings.iter().map(|(encoding, ..)| *encoding).collect();
+        let chosen = negotiate(request.header("accept-encoding"), &offered)
+            .and_then(|chosen| siblings.iter().find(|(encoding, ..)| *encoding == chosen));
+        let content_type = content_type(&path);
+        let result = match chosen {
+            Some((encoding, sibling, sibling_metadata)) => self.respond(
+                request,
+                sibling,
+                sibling_metadata,
+                content_type,
+                Some(*encoding),
+            ),
+            None => self.respond(request, &path, &metadata, content_type, None),
+        };
+
+        match result {
+            // Which file is sent depends on Accept-Encoding.
+            Ok(mut response) if !offered.is_empty() => {
+                compression::add_vary(&mut response.headers, "Accept-Encoding");
+                response
+            }
+            Ok(response) => response,
+            Err(e) => {
+                error!("Failed to serve {}: {e}", path.display());
+                Response::new(StatusCode::InternalServerError)
+            }
+        }
+    }
+
+    fn resolve(&self, relative: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        for component in Path::new(relative).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+            }
+        }
+
+        // A symlink inside the root may still point somewhere else.
+        if let (Ok(root), Ok(resolved)) = (self.root.canonicalize(), path.canonicalize()) {
+            if !resolved.starts_with(root) {
+                return None;
+            }
+        }
+
+        Some(path)
+    }
+
+    // The compressed copies of `path` that exist, with their metadata. Like
+    // `path` itself, they may not lead outside the root.
+    fn precompressed_siblings(&self, path: &Path) -> Vec<(Encoding, PathBuf, Metadata)> {
+        let root = self.root.canonicalize();
+        PRECOMPRESSED
+            .iter()
+            .filter_map(|encoding| {
+                let mut sibling = path.as_os_str().to_owned();
+                sibling.push(".");
+                sibling.push(encoding.extension()?);
+                let sibling = PathBuf::from(sibling);
+                let inside = match (&root, sibling.canonicalize()) {
+                    (Ok(root), Ok(resolved)) => resolved.starts_with(root),
+                    _ => false,
+                };
+                match fs::metadata(&sibling) {
+                    Ok(metadata) if inside && metadata.is_file() => {
+                        Some((*encoding, sibling, metadata))
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    fn respond(
+        &self,
+        request: &Request,
+        path: &Path,
+        metadata: &Metadata,
+        content_type: &str,
+        encoding: Option<Encoding>,
+    ) -> io::Result<Response> {
+        let length = metadata.len();
+        let modified = metadata.modified()?;
+        let etag = format!(
+            "\"{:x}-{:x}\"",
+            length,
+            modified
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0)
+        );
+        let last_modified = format_http_date(modified);
+
+        let response = Response::new(StatusCode::Ok)
+            .with_header("ETag", &etag)
+            .with_header("Last-Modified", &last_modified)
+            .with_header("Accept-Ranges", "bytes");
+
+        if not_modified(request, &etag, modified) {
+            return Ok(response.with_status(StatusCode::NotModified));
+        }
+
+        let mut response = response.with_header("Content-Type", content_type);
+        if let Some(encoding) = encoding {
+            response = response.with_header("Content-Encoding", encoding.as_str());
+        }
+        let mut file = File::open(path)?;
+
+        // A Range is only honoured if If-Range still names the current file.
+        let range = request
+            .header("range")
+            .filter(|_| match request.header("if-range") {
+                None => true,
+                Some(if_range) => if_range == etag || if_range == last_modified,
+            });
+
+        match range.map(|range| parse_range(range, length)) {
+            Some(Some(RangeSpec::Satisfiable(start, end))) => {
+                file.seek(SeekFrom::Start(start))?;
+                let body = Body::File {
+                    file,
+                    len: end - start + 1,
+                };
+                Ok(response
+                    .with_status(StatusCode::PartialContent)
+                    .with_header("Content-Range", &format!("bytes {start}-{end}/{length}"))
+                    .with_body(body))
+            }
+            Some(Some(RangeSpec::Unsatisfiable)) => {
+                Ok(Response::new(StatusCode::RangeNotSatisfiable)
+                    .with_header("Content-Range", &format!("bytes */{length}")))
+            }
+            // Malformed or multi-part ranges fall back to the whole file.
+            Some(None) | None => Ok(response.with_body(Body::File { file, len: length })),
+        }
+    }
+}
+
+fn not_modified(request: &Request, etag: &str, modified: std::time::SystemTime) -> bool {
+    // If-None-Match takes precedence over If-Modified-Since when both are sent.
+    if let Some(if_none_match) = request.header("if-none-match") {
+        return if_none_match.trim() == "*"
+            || if_none_match
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag);
+    }
+
+    match request
+        .header("if-modified-since")
+        .and_then(parse_http_date)
+    {
+        // HTTP dates only have whole seconds.
+        Some(since) => modified
+            .duration_since(since)
+            .map(|newer_by| newer_by.as_secs() == 0)
+            .unwrap_or(true),
+        None => false,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RangeSpec {
+    /// An inclusive byte range.
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+}
+
+// Only a single byte range is supported. None means the header should be
+// ignored.
+fn parse_range(header: &str, length: u64) -> Option<RangeSpec> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.trim().split_once('-')?;
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            if suffix == 0 || length == 0 {
+                return Some(RangeSpec::Unsatisfiable);
+            }
+            (length.saturating_sub(suffix), length - 1)
+        }
+        (start, "") => (start.parse().ok()?, length.saturating_sub(1)),
+        (start, end) => {
+            let start: u64 = start.parse().ok()?;
+            let end: u64 = end.parse().ok()?;
+            if end < start {
+                return None;
+            }
+            (start, end.min(length.saturating_sub(1)))
+        }
+    };
+
+    if start >= length {
+        return Some(RangeSpec::Unsatisfiable);
+    }
+    Some(RangeSpec::Satisfiable(start, end))
+}
+
+/// Guesses a `Content-Type` from the file extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json" | "map") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("avif") => "image/avif",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("mp3") => "audio/mpeg",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("web-server-static-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join("my docs #1")).unwrap();
+        fs::write(root.join("data.bin"), b"0123456789").unwrap();
+        fs::write(root.join("docs/index.html"), b"<h1>docs</h1>").unwrap();
+        root
+    }
+
+    fn get(path: &str, headers: &str) -> Request {
+        let raw = format!("GET {path} HTTP/1.1\r\n{headers}\r\n");
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn serves_files_and_directory_indexes() {
+        let files = StaticFiles::new(root("serve"));
+
+        let response = files.serve(&get("/data.bin", ""), "data.bin");
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.header("content-type"),
+            Some("application/octet-stream")
+        );
+        assert_eq!(response.body.into_bytes().unwrap(), b"0123456789");
+
+        let response = files.serve(&get("/docs/", ""), "docs/");
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(response.body.into_bytes().unwrap(), b"<h1>docs</h1>");
+
+        let response = files.serve(&get("/docs", ""), "docs");
+        assert_eq!(response.status, 301);
+        assert_eq!(response.header("location"), Some("/docs/"));
+
+        let response = files.serve(&get("/my%20docs%20%231?sort=name", ""), "my docs #1");
+        assert_eq!(response.status, 301);
+        assert_eq!(
+            response.header("location"),
+            Some("/my%20docs%20%231/?sort=name")
+        );
+    }
+
+    #[test]
+    fn blocks_traversal() {
+        let files = StaticFiles::new(root("traversal"));
+
+        assert_eq!(files.serve(&get("/x", ""), "../Cargo.toml").status, 403);
+        assert_eq!(
+            files.serve(&get("/x", ""), "docs/../../etc/passwd").status,
+            403
+        );
+        assert_eq!(files.serve(&get("/x", ""), "missing.txt").status, 404);
+    }
+
+    #[test]
+    fn conditional_get_returns_304() {
+        let files = StaticFiles::new(root("conditional"));
+        let first = files.serve(&get("/data.bin", ""), "data.bin");
+        let etag = first.header("etag").unwrap();
+        let last_modified = first.header("last-modified").unwrap();
+
+        let response = files.serve(
+            &get("/data.bin", &format!("If-None-Match: {etag}\r\n")),
+            "data.bin",
+        );
+        assert_eq!(response.status, 304);
+        assert!(response.body.is_empty());
+
+        let response = files.serve(
+            &get(
+                "/data.bin",
+                &format!("If-Modified-Since: {last_modified}\r\n"),
+            ),
+            "data.bin",
+        );
+        assert_eq!(response.status, 304);
+
+        let response = files.serve(
+            &get("/data.bin", "If-None-Match: \"other\"\r\n"),
+            "data.bin",
+        );
+        assert_eq!(response.status, 200);
+        // A date past any clock is ignored rather than overflowing.
+        let response = files.serve(
+            &get(
+                "/data.bin",
+                "If-Modified-Since: Sun, 06 Nov 300000000000 08:49:37 GMT\r\n",
+            ),
+            "data.bin",
+        );
+        assert_eq!(response.status, 200);
+    }
+
+    #[test]
+    fn range_requests_return_206() {
+        let files = StaticFiles::new(root("range"));
+
+        let response = files.serve(&get("/data.bin", "Range: bytes=2-4\r\n"), "data.bin");
+        assert_eq!(response.status, 206);
+        assert_eq!(response.header("content-range"), Some("bytes 2-4/10"));
+        assert_eq!(response.body.into_bytes().unwrap(), b"234");
+
+        let response = files.serve(&get("/data.bin", "Range: bytes=-3\r\n"), "data.bin");
+        assert_eq!(response.body.into_bytes().unwrap(), b"789");
+
+        let response = files.serve(&get("/data.bin", "Range: bytes=20-\r\n"), "data.bin");
+        assert_eq!(response.status, 416);
+        assert_eq!(response.header("content-range"), Some("bytes */10"));
+
+        let response = files.serve(
+            &get("/data.bin", "Range: bytes=0-1\r\nIf-Range: \"stale\"\r\n"),
+            "data.bin",
+        );
+        assert_eq!(response.status, 200);
+    }
+
+    #[test]
+    fn serves_precompressed_siblings() {
+        let root = root("precompressed");
+        fs::write(root.join("app.js"), b"let x = 1;").unwrap();
+        fs::write(root.join("app.js.br"), b"brotli bytes").unwrap();
+        fs::write(root.join("app.js.gz"), b"gzip bytes").unwrap();
+        let files = StaticFiles::new(&root);
+
+        let response = files.serve(&get("/app.js", "Accept-Encoding: gzip, br\r\n"), "app.js");
+        assert_eq!(response.header("content-encoding"), Some("br"));
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/javascript; charset=utf-8")
+        );
+        assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body.into_bytes().unwrap(), b"brotli bytes");
+
+        let response = files.serve(&get("/app.js", "Accept-Encoding: gzip\r\n"), "app.js");
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        assert_eq!(response.body.into_bytes().unwrap(), b"gzip bytes");
+
+        let response = files.serve(&get("/app.js", ""), "app.js");
+        assert_eq!(response.header("content-encoding"), None);
+        assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body.into_bytes().unwrap(), b"let x = 1;");
+
+        // Files without siblings do not vary.
+        let response = files.serve(&get("/data.bin", "Accept-Encoding: br\r\n"), "data.bin");
+        assert_eq!(response.header("vary"), None);
+
+        let files = StaticFiles::new(&root).precompressed(false);
+        let response = files.serve(&get("/app.js", "Accept-Encoding: br\r\n"), "app.js");
+        assert_eq!(response.body.into_bytes().unwrap(), b"let x = 1;");
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-", 5),
+            Some(RangeSpec::Satisfiable(0, 4))
+        );
+        assert_eq!(
+            parse_range("bytes=3-100", 5),
+            Some(RangeSpec::Satisfiable(3, 4))
+        );
+        assert_eq!(
+            parse_range("bytes=-10", 5),
+            Some(RangeSpec::Satisfiable(0, 4))
+        );
+        assert_eq!(parse_range("bytes=0-1,3-4", 5), None);
+        assert_eq!(parse_range("items=0-1", 5), None);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{error::Error, fmt};
+
+/// An HTTP status code the server knows the reason phrase for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[repr(u16)]
+pub enum StatusCode {
+    Continue = 100,
+    SwitchingProtocols = 101,
+    Ok = 200,
+    Created = 201,
+    Accepted = 202,
+    NoContent = 204,
+    PartialContent = 206,
+    MovedPermanently = 301,
+    Found = 302,
+    SeeOther = 303,
+    NotModified = 304,
+    TemporaryRedirect = 307,
+    PermanentRedirect = 308,
+    BadRequest = 400,
+    Unauthorized = 401,
+    Forbidden = 403,
+    NotFound = 404,
+    MethodNotAllowed = 405,
+    RequestTimeout = 408,
+    Conflict = 409,
+    Gone = 410,
+    LengthRequired = 411,
+    PayloadTooLarge = 413,
+    UriTooLong = 414,
+    UnsupportedMediaType = 415,
+    RangeNotSatisfiable = 416,
+    UpgradeRequired = 426,
+    TooManyRequests = 429,
+    RequestHeaderFieldsTooLarge = 431,
+    InternalServerError = 500,
+    NotImplemented = 501,
+    BadGateway = 502,
+    ServiceUnavailable = 503,
+    GatewayTimeout = 504,
+    HttpVersionNotSupported = 505,
+}
+
+/// A number that is not one of the `StatusCode` variants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownStatus(pub u16);
+
+impl fmt::Display for UnknownStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown HTTP status code: {}", self.0)
+    }
+}
+
+impl Error for UnknownStatus {}
+
+impl StatusCode {
+    pub fn as_u16(self) -> u16 {
+        self as u16
+    }
+
+    pub fn reason_phrase(self) -> &'static str {
+        match self {
+            StatusCode::Continue => "Continue",
+            StatusCode::SwitchingProtocols => "Switching Protocols",
+            StatusCode::Ok => "OK",
+            StatusCode::Created => "Created",
+            StatusCode::Accepted => "Accepted",
+            StatusCode::NoContent => "No Content",
+            StatusCode::PartialContent => "Partial Content",
+            StatusCode::MovedPermanently => "Moved Permanently",
+            StatusCode::Found => "Found",
+            StatusCode::SeeOther => "See Other",
+            StatusCode::NotModified => "Not Modified",
+            StatusCode::TemporaryRedirect => "Temporary Redirect",
+            StatusCode::PermanentRedirect => "Permanent Redirect",
+            StatusCode::BadRequest => "Bad Request",
+            StatusCode::Unauthorized => "Unauthorized",
+            StatusCode::Forbidden => "Forbidden",
+            StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::RequestTimeout => "Request Timeout",
+            StatusCode::Conflict => "Conflict",
+            StatusCode::Gone => "Gone",
+            StatusCode::LengthRequired => "Length Required",
+            StatusCode::PayloadTooLarge => "Payload Too Large",
+            StatusCode::UriTooLong => "URI Too Long",
+            StatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            StatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            StatusCode::UpgradeRequired => "Upgrade Required",
+            StatusCode::TooManyRequests => "Too Many Requests",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            StatusCode::InternalServerError => "Internal Server Error",
+            StatusCode::NotImplemented => "Not Implemented",
+            StatusCode::BadGateway => "Bad Gateway",
+            StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::GatewayTimeout => "Gateway Timeout",
+            StatusCode::HttpVersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
+
+    pub fn is_informational(self) -> bool {
+        (100..200).contains(&self.as_u16())
+    }
+
+    pub fn is_success(self) -> bool {
+        (200..300).contains(&self.as_u16())
+    }
+
+    pub fn is_redirection(self) -> bool {
+        (300..400).contains(&self.as_u16())
+    }
+
+    pub fn is_client_error(self) -> bool {
+        (400..500).contains(&self.as_u16())
+    }
+
+    pub fn is_server_error(self) -> bool {
+        (500..600).contains(&self.as_u16())
+    }
+
+    /// Returns true for statuses whose responses never have a body.
+    pub fn is_bodiless(self) -> bool {
+        self.is_informational() || self == StatusCode::NoContent || self == StatusCode::NotModified
+    }
+}
+
+impl TryFrom<u16> for StatusCode {
+    type Error = UnknownStatus;
+
+    fn try_from(code: u16) -> Result<StatusCode, UnknownStatus> {
+        const ALL: [StatusCode; 35] = [
+            StatusCode::Continue,
+            StatusCode::SwitchingProtocols,
+            StatusCode::Ok,
+            StatusCode::Created,
+            StatusCode::Accepted,
+            StatusCode::NoContent,
+            StatusCode::PartialContent,
+            StatusCode::MovedPermanently,
+            StatusCode::Found,
+            StatusCode::SeeOther,
+            StatusCode::NotModified,
+            StatusCode::TemporaryRedirect,
+            StatusCode::PermanentRedirect,
+            StatusCode::BadRequest,
+            StatusCode::Unauthorized,
+            StatusCode::Forbidden,
+            StatusCode::NotFound,
+            StatusCode::MethodNotAllowed,
+            StatusCode::RequestTimeout,
+            StatusCode::Conflict,
+            StatusCode::Gone,
+            StatusCode::LengthRequired,
+            StatusCode::PayloadTooLarge,
+            StatusCode::UriTooLong,
+            StatusCode::UnsupportedMediaType,
+            StatusCode::RangeNotSatisfiable,
+            StatusCode::UpgradeRequired,
+            StatusCode::TooManyRequests,
+            StatusCode::RequestHeaderFieldsTooLarge,
+            StatusCode::InternalServerError,
+            StatusCode::NotImplemented,
+            StatusCode::BadGateway,
+            StatusCode::ServiceUnavailable,
+            StatusCode::GatewayTimeout,
+            StatusCode::HttpVersionNotSupported,
+        ];
+
+        ALL.into_iter()
+            .find(|status| status.as_u16() == code)
+            .ok_or(UnknownStatus(code))
+    }
+}
+
+// Lets tests and logs compare with plain numbers.
+impl PartialEq<u16> for StatusCode {
+    fn eq(&self, other: &u16) -> bool {
+        self.as_u16() == *other
+    }
+}
+
+/// Formats as the code and reason phrase, e.g. `404 Not Found`.
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.as_u16(), self.reason_phrase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_to_and_from_numbers() {
+        assert_eq!(StatusCode::try_from(404), Ok(StatusCode::NotFound));
+        assert_eq!(StatusCode::try_from(299), Err(UnknownStatus(299)));
+        assert_eq!(StatusCode::RequestHeaderFieldsTooLarge.as_u16(), 431);
+        assert_eq!(StatusCode::NotFound.to_string(), "404 Not Found");
+        assert!(StatusCode::NotModified.is_bodiless());
+        assert!(StatusCode::BadGateway.is_server_error());
+    }
+}
@@ -0,0 +1,452 @@
+//! A server on an ephemeral port and a blocking client to test routes with.
+//!
+//! ```
+//! use web_server::{testing::TestServer, Response, Router, StatusCode};
+//!
+//! let mut router = Router::new();
+//! router.get("/hello/:name", |_, params| {
+//!     Response::new(StatusCode::Ok).with_body(format!("Hello, {}!", params.get("name").unwrap()))
+//! });
+//!
+//! let server = TestServer::start(router);
+//! let response = server.client().get("/hello/ferris").unwrap();
+//! assert_eq!(response.status, StatusCode::Ok);
+//! assert_eq!(response.text(), "Hello, ferris!");
+//! server.shutdown();
+//! ```
+
+use std::{
+    collections::HashMap,
+    io::{self, BufReader, Read, Write},
+    net::{Shutdown as Half, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    request,
+    response::{read_response_head, ResponseHead},
+    HeaderMap, Method, Router, Server, Shutdown, ShutdownReport, StatusCode, ThreadPool,
+};
+
+/// How long a client waits on the server before a test fails instead of
+/// hanging.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A `Server` running on a background thread, listening on a loopback
+/// port picked by the OS.
+///
+/// `shutdown` stops it and waits for it, so everything a handler does has
+/// happened by the time it returns. Dropping the server does the same.
+pub struct TestServer {
+    addr: SocketAddr,
+    shutdown: Shutdown,
+    /// Connections clients hold open, by their local address, closed at
+    /// shutdown so idle keep-alive ones do not hold it up.
+    connections: Connections,
+    running: Option<JoinHandle<io::Result<ShutdownReport>>>,
+}
+
+impl TestServer {
+    /// Serves `router` with a pool of four workers and default settings.
+    pub fn start(router: Router) -> TestServer {
+        TestServer::with(|listener| Server::new(listener, router, ThreadPool::new(4)))
+    }
+
+    /// Runs the server `build` makes around a loopback listener, for tests
+    /// that need other settings:
+    ///
+    /// ```
+    /// use web_server::{testing::TestServer, KeepAlive, Router, Server, ThreadPool};
+    ///
+    /// let server = TestServer::with(|listener| {
+    ///     Server::new(listener, Router::new(), ThreadPool::new(1)).keep_alive(KeepAlive {
+    ///         max_requests: 1,
+    ///         ..KeepAlive::default()
+    ///     })
+    /// });
+    /// # server.shutdown();
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if no loopback port can be bound.
+    pub fn with<F>(build: F) -> TestServer
+    where
+        F: FnOnce(TcpListener) -> Server,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind a loopback port");
+        let server = build(listener);
+        let addr = server
+            .local_addr()
+            .expect("Failed to read the server address");
+        let shutdown = server.shutdown_handle();
+        let running = thread::Builder::new()
+            .name(String::from("test-server"))
+            .spawn(move || server.run())
+            .expect("Failed to start the server thread");
+        TestServer {
+            addr,
+            shutdown,
+            connections: Arc::default(),
+            running: Some(running),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// A client that keeps one connection to the server open between
+    /// requests.
+    pub fn client(&self) -> TestClient {
+        TestClient {
+            addr: self.addr,
+            stream: None,
+            connections: Arc::clone(&self.connections),
+        }
+    }
+
+    /// Stops the server and waits for every request in flight to finish.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server failed or panicked, so the test does too.
+    pub fn shutdown(mut self) -> ShutdownReport {
+        match self.stop() {
+            Some(Ok(Ok(report))) => report,
+            Some(Ok(Err(e))) => panic!("test server failed: {e}"),
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            None => unreachable!("test server stopped twice"),
+        }
+    }
+
+    fn stop(&mut self) -> Option<thread::Result<io::Result<ShutdownReport>>> {
+        let running = self.running.take()?;
+        self.shutdown.trigger();
+        // Half-closing tells a connection waiting for its next request that
+        // none is coming, while one mid-request can still send its answer.
+        for (_, stream) in self.connections.lock().unwrap().drain() {
+            let _ = stream.shutdown(Half::Write);
+        }
+        Some(running.join())
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// A blocking HTTP/1.1 client for a `TestServer`.
+///
+/// The connection is reused for as long as the server keeps it open.
+pub struct TestClient {
+    addr: SocketAddr,
+    stream: Option<BufReader<TcpStream>>,
+    connections: Connections,
+}
+
+type Connections = Arc<Mutex<HashMap<SocketAddr, TcpStream>>>;
+
+impl TestClient {
+    pub fn get(&mut self, target: &str) -> io::Result<TestResponse> {
+        self.send(&TestRequest::new(Method::Get, target))
+    }
+
+    pub fn post(&mut self, target: &str, body: impl Into<Vec<u8>>) -> io::Result<TestResponse> {
+        self.send(&TestRequest::new(Method::Post, target).body(body))
+    }
+
+    /// Sends `request` and reads the whole response.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the server cannot be reached, closes the connection early,
+    /// or sends something that is not an HTTP response.
+    pub fn send(&mut self, request: &TestRequest) -> io::Result<TestResponse> {
+        let mut reader = match self.stream.take() {
+            Some(reader) => reader,
+            None => self.connect()?,
+        };
+        match self.exchange(&mut reader, request) {
+            Ok((response, true)) => {
+                self.stream = Some(reader);
+                Ok(response)
+            }
+            Ok((response, false)) => {
+                self.forget(reader.get_ref());
+                Ok(response)
+            }
+            Err(e) => {
+                self.forget(reader.get_ref());
+                Err(e)
+            }
+        }
+    }
+
+    // Sends `request` and reads the response, and whether the connection
+    // can be used again.
+    fn exchange(
+        &self,
+        reader: &mut BufReader<TcpStream>,
+        request: &TestRequest,
+    ) -> io::Result<(TestResponse, bool)> {
+        reader.get_mut().write_all(&request.to_bytes(self.addr))?;
+
+        let head = read_response_head(reader).map_err(invalid)?;
+        let status = StatusCode::try_from(head.status).map_err(invalid)?;
+        let framed =
+            head.header("content-length").is_some() || head.header("transfer-encoding").is_some();
+        let bodiless = request.method == Method::Head || status.is_bodiless();
+        let body = if bodiless {
+            Vec::new()
+        } else if framed {
+            request::read_body(&head.headers, reader, usize::MAX).map_err(invalid)?
+        } else {
+            // Without framing the body runs to the end of the connection.
+            let mut body = Vec::new();
+            reader.read_to_end(&mut body)?;
+            body
+        };
+
+        let reusable = head.keeps_alive() && (framed || bodiless);
+        Ok((TestResponse::new(status, head, body), reusable))
+    }
+
+    fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+        let stream = TcpStream::connect(self.addr)?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(stream.local_addr()?, stream.try_clone()?);
+        Ok(BufReader::new(stream))
+    }
+
+    // Stops tracking a connection that is about to be closed.
+    fn forget(&self, stream: &TcpStream) {
+        if let Ok(addr) = stream.local_addr() {
+            self.connections.lock().unwrap().remove(&addr);
+        }
+    }
+}
+
+impl Drop for TestClient {
+    fn drop(&mut self) {
+        if let Some(reader) = self.stream.take() {
+            self.forget(reader.get_ref());
+        }
+    }
+}
+
+fn invalid<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// A request for `TestClient::send`.
+#[derive(Debug, Clone)]
+pub struct TestRequest {
+    method: Method,
+    target: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl TestRequest {
+    /// A request for `target`, a path with an optional query string sent as
+    /// written.
+    pub fn new(method: Method, target: &str) -> TestRequest {
+        TestRequest {
+            method,
+            target: target.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Adds a header. `Host` and `Content-Length` are filled in unless set
+    /// here.
+    pub fn header(mut self, name: &str, value: &str) -> TestRequest {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> TestRequest {
+        self.body = body.into();
+        self
+    }
+
+    fn to_bytes(&self, addr: SocketAddr) -> Vec<u8> {
+        let has = |name: &str| {
+            self.headers
+                .iter()
+                .any(|(key, _)| key.eq_ignore_ascii_case(name))
+        };
+        let mut head = format!("{} {} HTTP/1.1\r\n", self.method, self.target);
+        if !has("host") {
+            head.push_str(&format!("Host: {addr}\r\n"));
+        }
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !has("content-length") && !has("transfer-encoding") && !self.body.is_empty() {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+/// A response read by a `TestClient`.
+#[derive(Debug, Clone)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    /// The body with any chunked framing removed.
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    fn new(status: StatusCode, head: ResponseHead, body: Vec<u8>) -> TestResponse {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &head.headers {
+            headers.append(name, value);
+        }
+        TestResponse {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    /// Returns the first value of the header `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// The body as text, with invalid UTF-8 replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KeepAlive, Response};
+    use std::time::Instant;
+
+    fn echo_router() -> Router {
+        let mut router = Router::new();
+        router
+            .post("/echo", |request, _| {
+                Response::new(StatusCode::Created)
+                    .with_header("X-Method", request.method.as_str())
+                    .with_body(request.body.clone())
+            })
+            .get("/stream", |_, _| {
+                Response::new(StatusCode::Ok).with_body(crate::Body::stream(
+                    ["one ", "two"].map(|chunk| Ok(chunk.as_bytes().to_vec())),
+                ))
+            });
+        router
+    }
+
+    #[test]
+    fn sends_requests_and_reads_responses() {
+        let server = TestServer::start(echo_router());
+        let mut client = server.client();
+
+        let response = client.post("/echo", "ping").unwrap();
+        assert_eq!(response.status, StatusCode::Created);
+        assert_eq!(response.header("x-method"), Some("POST"));
+        assert_eq!(response.text(), "ping");
+
+        let response = client.get("/stream").unwrap();
+        assert_eq!(response.header("transfer-encoding"), Some("chunked"));
+        assert_eq!(response.text(), "one two");
+
+        let response = client
+            .send(&TestRequest::new(Method::Head, "/missing").header("Accept", "text/plain"))
+            .unwrap();
+        assert_eq!(response.status, StatusCode::NotFound);
+        assert!(response.body.is_empty());
+        server.shutdown();
+    }
+
+    #[test]
+    fn reuses_the_connection_until_the_server_closes_it() {
+        let server = TestServer::with(|listener| {
+            Server::new(listener, echo_router(), ThreadPool::new(1)).keep_alive(KeepAlive {
+                max_requests: 2,
+                ..KeepAlive::default()
+            })
+        });
+        let mut client = server.client();
+        let port = |client: &TestClient| {
+            let stream = client.stream.as_ref().map(|reader| reader.get_ref());
+            stream.map(|stream| stream.local_addr().unwrap().port())
+        };
+
+        client.post("/echo", "1").unwrap();
+        let first = port(&client);
+        assert!(first.is_some());
+        client.post("/echo", "2").unwrap();
+        assert_eq!(port(&client), None);
+        client.post("/echo", "3").unwrap();
+        assert_ne!(port(&client), first);
+        server.shutdown();
+    }
+
+    #[test]
+    fn forgets_closed_connections() {
+        let server = TestServer::with(|listener| {
+            Server::new(listener, echo_router(), ThreadPool::new(1)).keep_alive(KeepAlive {
+                max_requests: 1,
+                ..KeepAlive::default()
+            })
+        });
+        let mut client = server.client();
+        for _ in 0..3 {
+            client.post("/echo", "hi").unwrap();
+        }
+        assert!(server.connections.lock().unwrap().is_empty());
+
+        let server = TestServer::start(echo_router());
+        let mut kept = server.client();
+        kept.post("/echo", "hi").unwrap();
+        assert_eq!(server.connections.lock().unwrap().len(), 1);
+        drop(kept);
+        assert!(server.connections.lock().unwrap().is_empty());
+        server.shutdown();
+    }
+
+    #[test]
+    fn shutdown_does_not_wait_for_idle_clients() {
+        let server = TestServer::start(echo_router());
+        let mut idle = server.client();
+        idle.post("/echo", "hi").unwrap();
+
+        let started = Instant::now();
+        let report = server.shutdown();
+
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(report.completed, 1);
+        assert_eq!(report.abandoned, 0);
+    }
+
+    #[test]
+    fn shutdown_right_after_start() {
+        for _ in 0..20 {
+            let report = TestServer::start(Router::new()).shutdown();
+            assert_eq!(report.completed, 0);
+        }
+    }
+}
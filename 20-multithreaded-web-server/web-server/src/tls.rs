@@ -0,0 +1,150 @@
+use std::{
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig, ServerConnection,
+};
+
+/// A certificate chain and private key for serving HTTPS.
+///
+/// Cloning is cheap; every clone shares the same rustls configuration.
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: Arc<ServerConfig>,
+}
+
+/// Why a `TlsConfig` could not be built.
+#[derive(Debug)]
+pub enum TlsError {
+    /// A PEM file could not be read.
+    Read { path: PathBuf, error: io::Error },
+    /// The certificate PEM holds no `CERTIFICATE` sections.
+    NoCertificates,
+    /// The key PEM holds no private key.
+    NoPrivateKey,
+    /// A PEM section is malformed.
+    Pem(String),
+    /// rustls rejected the certificate or key, for example because they do
+    /// not match.
+    Rustls(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Read { path, error } => write!(f, "cannot read {}: {error}", path.display()),
+            TlsError::NoCertificates => f.write_str("no certificates found in PEM"),
+            TlsError::NoPrivateKey => f.write_str("no private key found in PEM"),
+            TlsError::Pem(message) => write!(f, "invalid PEM: {message}"),
+            TlsError::Rustls(e) => write!(f, "invalid certificate or key: {e}"),
+        }
+    }
+}
+
+impl Error for TlsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TlsError::Read { error, .. } => Some(error),
+            TlsError::Rustls(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Loads a PEM certificate chain, leaf first, and a PEM private key in
+    /// PKCS#8, PKCS#1 or SEC1 form.
+    pub fn from_pem_files(cert: &Path, key: &Path) -> Result<TlsConfig, TlsError> {
+        let read = |path: &Path| {
+            fs::read(path).map_err(|error| TlsError::Read {
+                path: path.to_path_buf(),
+                error,
+            })
+        };
+        TlsConfig::from_pem(&read(cert)?, &read(key)?)
+    }
+
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> Result<TlsConfig, TlsError> {
+        let chain = CertificateDer::pem_slice_iter(cert)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| TlsError::Pem(e.to_string()))?;
+        if chain.is_empty() {
+            return Err(TlsError::NoCertificates);
+        }
+        let key = PrivateKeyDer::from_pem_slice(key).map_err(|e| match e {
+            rustls::pki_types::pem::Error::NoItemsFound => TlsError::NoPrivateKey,
+            e => TlsError::Pem(e.to_string()),
+        })?;
+
+        // The provider is named rather than taken from the process default,
+        // so another crate enabling a second provider cannot make this panic.
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(TlsError::Rustls)?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .map_err(TlsError::Rustls)?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(TlsConfig {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Starts the server side of a handshake. The handshake itself runs on
+    /// the first read or write.
+    pub(crate) fn accept(&self) -> Result<ServerConnection, rustls::Error> {
+        ServerConnection::new(Arc::clone(&self.config))
+    }
+
+    /// The rustls configuration, for building clients in tests.
+    #[cfg(test)]
+    pub(crate) fn server_config(&self) -> &ServerConfig {
+        &self.config
+    }
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("alpn", &self.config.alpn_protocols)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_a_self_signed_certificate() {
+        let generated =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let cert = generated.cert.pem();
+        let key = generated.key_pair.serialize_pem();
+
+        let tls = TlsConfig::from_pem(cert.as_bytes(), key.as_bytes()).unwrap();
+        assert_eq!(tls.server_config().alpn_protocols, [b"http/1.1".to_vec()]);
+
+        assert!(matches!(
+            TlsConfig::from_pem(b"", key.as_bytes()),
+            Err(TlsError::NoCertificates)
+        ));
+        assert!(matches!(
+            TlsConfig::from_pem(cert.as_bytes(), cert.as_bytes()),
+            Err(TlsError::NoPrivateKey)
+        ));
+
+        let other = rcgen::KeyPair::generate().unwrap().serialize_pem();
+        assert!(matches!(
+            TlsConfig::from_pem(cert.as_bytes(), other.as_bytes()),
+            Err(TlsError::Rustls(_))
+        ));
+    }
+}
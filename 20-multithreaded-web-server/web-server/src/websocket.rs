@@ -0,0 +1,790 @@
+//! WebSocket connections, as in RFC 6455.
+//!
+//! A handler accepts the opening handshake with `upgrade`, passing the code
+//! that takes over the connection once the `101 Switching Protocols`
+//! response has been sent. That code runs on a thread of its own rather
+//! than a pool worker, so long-lived sockets cannot starve normal requests;
+//! `Limits::max_websockets` caps how many may be open at once.
+//!
+//! ```
+//! use web_server::websocket::{self, Message};
+//! use web_server::Router;
+//!
+//! let mut router = Router::new();
+//! router.get("/echo", |request, _| {
+//!     websocket::upgrade(request, |mut socket| {
+//!         while let Ok(message) = socket.recv() {
+//!             match message {
+//!                 Message::Text(text) => socket.send_text(&text).unwrap(),
+//!                 Message::Binary(data) => socket.send_binary(&data).unwrap(),
+//!                 _ => {}
+//!             }
+//!         }
+//!     })
+//! });
+//! ```
+
+use std::{
+    error::Error,
+    fmt,
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+
+use crate::{Method, Request, Response, StatusCode, Version};
+
+/// Appended to the client's key before hashing, as fixed by the RFC.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message `recv` accepts unless `set_max_message_size` says
+/// otherwise.
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 16 * 1024 * 1024;
+
+/// Answers a WebSocket opening handshake.
+///
+/// On success the response is `101 Switching Protocols` and `handler` is
+/// given the connection once the response is sent. A request that is not a
+/// valid handshake gets `400 Bad Request`, or `426 Upgrade Required` when it
+/// asks for no WebSocket or an unsupported version of the protocol.
+pub fn upgrade<F>(request: &Request, handler: F) -> Response
+where
+    F: FnOnce(WebSocket) + Send + 'static,
+{
+    let key = match handshake_key(request) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let mut response = Response::new(StatusCode::SwitchingProtocols)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", &accept_key(key));
+    response.upgrade = Some(Upgrade(Box::new(handler)));
+    response
+}
+
+// Checks the handshake headers and returns the client's key.
+fn handshake_key(request: &Request) -> Result<&str, Response> {
+    let upgrade_required = || {
+        Response::new(StatusCode::UpgradeRequired)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Version", "13")
+            .with_body("Upgrade Required")
+    };
+    let bad_request = |message: &str| Response::new(StatusCode::BadRequest).with_body(message);
+
+    if !has_token(request.header("upgrade"), "websocket") {
+        return Err(upgrade_required());
+    }
+    if request.method != Method::Get || request.version != Version::Http11 {
+        return Err(bad_request(
+            "WebSocket handshakes must be HTTP/1.1 GET requests",
+        ));
+    }
+    if !has_token(request.header("connection"), "upgrade") {
+        return Err(bad_request("Connection must include \"upgrade\""));
+    }
+    if request.header("sec-websocket-version") != Some("13") {
+        return Err(upgrade_required());
+    }
+    match request.header("sec-websocket-key") {
+        Some(key) if STANDARD.decode(key).is_ok_and(|nonce| nonce.len() == 16) => Ok(key),
+        _ => Err(bad_request("Invalid Sec-WebSocket-Key")),
+    }
+}
+
+// Whether a comma-separated header value lists `token`, ignoring case.
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|value| {
+        value
+            .split(',')
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// The code that takes over a connection after a successful handshake.
+pub(crate) struct Upgrade(Box<dyn FnOnce(WebSocket) + Send>);
+
+impl Upgrade {
+    pub(crate) fn run(self, socket: WebSocket) {
+        (self.0)(socket)
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Upgrade")
+    }
+}
+
+/// A connection a `WebSocket` can run over.
+pub(crate) trait Socket: Read + Write + Send {
+    /// Makes reads fail with `TimedOut` once `timeout` passes without data,
+    /// or never with `None`.
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+/// A close status code, from RFC 6455 section 7.4. Applications may use
+/// their own codes from 3000 to 4999.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CloseCode(pub u16);
+
+impl CloseCode {
+    pub const NORMAL: CloseCode = CloseCode(1000);
+    pub const GOING_AWAY: CloseCode = CloseCode(1001);
+    pub const PROTOCOL_ERROR: CloseCode = CloseCode(1002);
+    pub const UNSUPPORTED_DATA: CloseCode = CloseCode(1003);
+    /// Reported when a close frame carried no code. It is never sent.
+    pub const NO_STATUS: CloseCode = CloseCode(1005);
+    pub const INVALID_PAYLOAD: CloseCode = CloseCode(1007);
+    pub const POLICY_VIOLATION: CloseCode = CloseCode(1008);
+    pub const MESSAGE_TOO_BIG: CloseCode = CloseCode(1009);
+    pub const INTERNAL_ERROR: CloseCode = CloseCode(1011);
+
+    // Codes that may appear in a close frame. The rest are reserved or only
+    // describe a connection locally.
+    fn is_sendable(self) -> bool {
+        matches!(self.0, 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+/// A message received from the peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// A ping, which `recv` has already answered with a pong.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The peer closed the connection. `recv` has already replied, so
+    /// nothing more can be sent.
+    Close {
+        code: CloseCode,
+        reason: String,
+    },
+}
+
+/// Why a `WebSocket` could not receive or send.
+#[derive(Debug)]
+pub enum WebSocketError {
+    /// The connection failed. A `TimedOut` error after
+    /// `WebSocket::set_read_timeout` leaves the socket usable.
+    Io(io::Error),
+    /// The peer broke the protocol, and the connection was closed with
+    /// `CloseCode::PROTOCOL_ERROR`.
+    Protocol(&'static str),
+    /// A text message was not UTF-8, and the connection was closed with
+    /// `CloseCode::INVALID_PAYLOAD`.
+    InvalidUtf8,
+    /// A message was over the size limit, and the connection was closed
+    /// with `CloseCode::MESSAGE_TOO_BIG`.
+    MessageTooBig,
+    /// The connection has already been closed.
+    Closed,
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebSocketError::Io(e) => write!(f, "i/o error: {e}"),
+            WebSocketError::Protocol(message) => write!(f, "protocol error: {message}"),
+            WebSocketError::InvalidUtf8 => f.write_str("text message is not valid UTF-8"),
+            WebSocketError::MessageTooBig => f.write_str("message too big"),
+            WebSocketError::Closed => f.write_str("connection closed"),
+        }
+    }
+}
+
+impl Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WebSocketError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(e: io::Error) -> WebSocketError {
+        WebSocketError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_u8(value: u8) -> Option<Opcode> {
+        match value {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: Opcode,
+    payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Open,
+    /// A close frame has been sent and the peer's is awaited.
+    Closing,
+    Closed,
+}
+
+/// The server end of a WebSocket connection.
+///
+/// Reads and writes are blocking. A handler that pushes updates while also
+/// listening can set a read timeout and send whenever `recv` times out.
+/// Dropping an open socket closes it with `CloseCode::NORMAL`.
+pub struct WebSocket {
+    socket: Box<dyn Socket>,
+    /// Bytes read but not yet parsed, so a timeout in the middle of a frame
+    /// loses nothing.
+    buffer: Vec<u8>,
+    /// The opcode and data so far of a fragmented message.
+    partial: Option<(Opcode, Vec<u8>)>,
+    max_message_bytes: usize,
+    state: State,
+}
+
+impl WebSocket {
+    /// Wraps an upgraded connection. `buffered` holds bytes the HTTP reader
+    /// had already taken off the socket.
+    pub(crate) fn new(socket: Box<dyn Socket>, buffered: Vec<u8>) -> WebSocket {
+        WebSocket {
+            socket,
+            buffer: buffered,
+            partial: None,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            state: State::Open,
+        }
+    }
+
+    /// Makes `recv` fail with a `TimedOut` I/O error when nothing arrives
+    /// for `timeout`. The socket stays usable afterwards.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    /// Sets the largest message, after reassembling fragments, that `recv`
+    /// accepts.
+    pub fn set_max_message_size(&mut self, bytes: usize) {
+        self.max_message_bytes = bytes;
+    }
+
+    /// Waits for the next message.
+    ///
+    /// Pings are answered and fragmented messages reassembled before they
+    /// are returned. When the peer breaks the protocol the connection is
+    /// closed with a matching code and the error returned.
+    pub fn recv(&mut self) -> Result<Message, WebSocketError> {
+        loop {
+            if self.state == State::Closed {
+                return Err(WebSocketError::Closed);
+            }
+            let result = self.read_frame().and_then(|frame| self.handle(frame));
+            match result {
+                Ok(Some(message)) => return Ok(message),
+                Ok(None) => {}
+                Err(e) => return Err(self.fail(e)),
+            }
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<(), WebSocketError> {
+        self.write_frame(Opcode::Text, text.as_bytes())
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> Result<(), WebSocketError> {
+        self.write_frame(Opcode::Binary, data)
+    }
+
+    /// Sends a ping. Its payload may be at most 125 bytes.
+    pub fn ping(&mut self, payload: &[u8]) -> Result<(), WebSocketError> {
+        if payload.len() > 125 {
+            return Err(invalid_input("ping payload over 125 bytes"));
+        }
+        self.write_frame(Opcode::Ping, payload)
+    }
+
+    /// Sends a close frame and waits for the peer's, discarding any messages
+    /// that arrive first. `reason` may be at most 123 bytes.
+    pub fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), WebSocketError> {
+        if !code.is_sendable() {
+            return Err(invalid_input("close code cannot be sent"));
+        }
+        if reason.len() > 123 {
+            return Err(invalid_input("close reason over 123 bytes"));
+        }
+        self.write_close(code, reason)?;
+        loop {
+            match self.recv() {
+                Ok(Message::Close { .. }) | Err(WebSocketError::Closed) => return Ok(()),
+                Ok(_) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Reads until the buffer holds a whole frame.
+    fn read_frame(&mut self) -> Result<Frame, WebSocketError> {
+        loop {
+            if let Some(frame) = self.parse_frame()? {
+                return Ok(frame);
+            }
+            let mut chunk = [0; 8192];
+            let read = match self.socket.read(&mut chunk) {
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if read == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed without a close frame",
+                )
+                .into());
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    // Takes the first frame off the buffer if it has all arrived. Limits are
+    // checked as soon as the header is in, before the payload is waited for.
+    fn parse_frame(&mut self) -> Result<Option<Frame>, WebSocketError> {
+        let buffer = &self.buffer;
+        if buffer.len() < 2 {
+            return Ok(None);
+        }
+        let fin = buffer[0] & 0x80 != 0;
+        if buffer[0] & 0x70 != 0 {
+            return Err(WebSocketError::Protocol("reserved bits set"));
+        }
+        let opcode =
+            Opcode::from_u8(buffer[0] & 0x0F).ok_or(WebSocketError::Protocol("unknown opcode"))?;
+        if buffer[1] & 0x80 == 0 {
+            return Err(WebSocketError::Protocol("client frames must be masked"));
+        }
+        let (len, header) = match buffer[1] & 0x7F {
+            126 if buffer.len() < 4 => return Ok(None),
+            126 => (u64::from(u16::from_be_bytes([buffer[2], buffer[3]])), 4),
+            127 if buffer.len() < 10 => return Ok(None),
+            127 => {
+                let len = u64::from_be_bytes(buffer[2..10].try_into().unwrap());
+                if len >> 63 != 0 {
+                    return Err(WebSocketError::Protocol("frame length out of range"));
+                }
+                (len, 10)
+            }
+            len => (u64::from(len), 2),
+        };
+        if opcode.is_control() {
+            if !fin {
+                return Err(WebSocketError::Protocol("fragmented control frame"));
+            }
+            if len > 125 {
+                return Err(WebSocketError::Protocol("control frame over 125 bytes"));
+            }
+        } else {
+            let so_far = self.partial.as_ref().map_or(0, |(_, data)| data.len());
+            if len > self.max_message_bytes.saturating_sub(so_far) as u64 {
+                return Err(WebSocketError::MessageTooBig);
+            }
+        }
+
+        let len = len as usize;
+        if buffer.len() < header + 4 + len {
+            return Ok(None);
+        }
+        let mask = [
+            buffer[header],
+            buffer[header + 1],
+            buffer[header + 2],
+            buffer[header + 3],
+        ];
+        let mut payload: Vec<u8> = self
+            .buffer
+            .drain(..header + 4 + len)
+            .skip(header + 4)
+            .collect();
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+
+    // Acts on one frame, returning a message once one is complete.
+    fn handle(&mut self, frame: Frame) -> Result<Option<Message>, WebSocketError> {
+        let Frame {
+            fin,
+            opcode,
+            payload,
+        } = frame;
+        match opcode {
+            Opcode::Ping => {
+                if self.state == State::Open {
+                    self.write_frame(Opcode::Pong, &payload)?;
+                }
+                Ok(Some(Message::Ping(payload)))
+            }
+            Opcode::Pong => Ok(Some(Message::Pong(payload))),
+            Opcode::Close => {
+                let (code, reason) = parse_close(&payload)?;
+                // Echo the code, unless this close answers our own. The peer
+                // is going away either way, so a failed reply is ignored.
+                if self.state == State::Open {
+                    let _ = if code == CloseCode::NO_STATUS {
+                        self.write_frame(Opcode::Close, &[])
+                    } else {
+                        self.write_close(code, "")
+                    };
+                }
+                self.state = State::Closed;
+                Ok(Some(Message::Close { code, reason }))
+            }
+            Opcode::Text | Opcode::Binary => {
+                if self.partial.is_some() {
+                    return Err(WebSocketError::Protocol(
+                        "new message before the last one finished",
+                    ));
+                }
+                if fin {
+                    return message(opcode, payload).map(Some);
+                }
+                self.partial = Some((opcode, payload));
+                Ok(None)
+            }
+            Opcode::Continuation => {
+                let Some((opcode, mut data)) = self.partial.take() else {
+                    return Err(WebSocketError::Protocol("continuation without a message"));
+                };
+                data.extend_from_slice(&payload);
+                if fin {
+                    return message(opcode, data).map(Some);
+                }
+                self.partial = Some((opcode, data));
+                Ok(None)
+            }
+        }
+    }
+
+    // Closes the connection after a protocol error, telling the peer why when
+    // there is still a connection to tell.
+    fn fail(&mut self, e: WebSocketError) -> WebSocketError {
+        let code = match &e {
+            WebSocketError::Protocol(_) => CloseCode::PROTOCOL_ERROR,
+            WebSocketError::InvalidUtf8 => CloseCode::INVALID_PAYLOAD,
+            WebSocketError::MessageTooBig => CloseCode::MESSAGE_TOO_BIG,
+            WebSocketError::Io(io) if io.kind() == io::ErrorKind::TimedOut => return e,
+            WebSocketError::Io(_) | WebSocketError::Closed => {
+                self.state = State::Closed;
+                return e;
+            }
+        };
+        if self.state == State::Open {
+            let _ = self.write_close(code, "");
+        }
+        self.state = State::Closed;
+        e
+    }
+
+    fn write_close(&mut self, code: CloseCode, reason: &str) -> Result<(), WebSocketError> {
+        let mut payload = code.0.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.write_frame(Opcode::Close, &payload)?;
+        self.state = State::Closing;
+        Ok(())
+    }
+
+    // Server frames are never masked and always sent whole.
+    fn write_frame(&mut self, opcode: Opcode, payload: &[u8]) -> Result<(), WebSocketError> {
+        if self.state != State::Open {
+            return Err(WebSocketError::Closed);
+        }
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode.as_u8());
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        if let Err(e) = self
+            .socket
+            .write_all(&frame)
+            .and_then(|_| self.socket.flush())
+        {
+            self.state = State::Closed;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        if self.state == State::Open {
+            let _ = self.write_close(CloseCode::NORMAL, "");
+        }
+    }
+}
+
+fn message(opcode: Opcode, data: Vec<u8>) -> Result<Message, WebSocketError> {
+    match opcode {
+        Opcode::Text => String::from_utf8(data)
+            .map(Message::Text)
+            .map_err(|_| WebSocketError::InvalidUtf8),
+        _ => Ok(Message::Binary(data)),
+    }
+}
+
+fn parse_close(payload: &[u8]) -> Result<(CloseCode, String), WebSocketError> {
+    match payload {
+        [] => Ok((CloseCode::NO_STATUS, String::new())),
+        [_] => Err(WebSocketError::Protocol("close frame with a one-byte code")),
+        [high, low, reason @ ..] => {
+            let code = CloseCode(u16::from_be_bytes([*high, *low]));
+            if !code.is_sendable() {
+                return Err(WebSocketError::Protocol("invalid close code"));
+            }
+            let reason =
+                String::from_utf8(reason.to_vec()).map_err(|_| WebSocketError::InvalidUtf8)?;
+            Ok((code, reason))
+        }
+    }
+}
+
+fn invalid_input(message: &str) -> WebSocketError {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    // Feeds canned client bytes in and collects what the server writes.
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Socket for Pipe {
+        fn set_read_timeout(&mut self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn socket(input: Vec<u8>) -> (WebSocket, Arc<Mutex<Vec<u8>>>) {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let pipe = Pipe {
+            input: Cursor::new(input),
+            output: Arc::clone(&output),
+        };
+        (WebSocket::new(Box::new(pipe), Vec::new()), output)
+    }
+
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![u8::from(fin) << 7 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    // Splits short unmasked server frames into opcodes and payloads.
+    fn server_frames(mut bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while let [first, len, rest @ ..] = bytes {
+            let len = usize::from(*len);
+            frames.push((first & 0x0F, rest[..len].to_vec()));
+            bytes = &rest[len..];
+        }
+        frames
+    }
+
+    fn handshake(headers: &str) -> Response {
+        let text = format!("GET /live HTTP/1.1\r\nHost: example.com\r\n{headers}\r\n");
+        let request = Request::read_from(&mut text.as_bytes()).unwrap();
+        upgrade(&request, |_| {})
+    }
+
+    #[test]
+    fn answers_the_opening_handshake() {
+        let response = handshake(
+            "Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+        );
+        assert_eq!(response.status, StatusCode::SwitchingProtocols);
+        assert_eq!(
+            response.header("sec-websocket-accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert!(response.upgrade.is_some());
+
+        let response = handshake("Connection: Upgrade\r\n");
+        assert_eq!(response.status, StatusCode::UpgradeRequired);
+        let response = handshake(
+            "Upgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Version: 8\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+        );
+        assert_eq!(response.status, StatusCode::UpgradeRequired);
+        assert_eq!(response.header("sec-websocket-version"), Some("13"));
+        let response = handshake(
+            "Upgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: c2hvcnQ=\r\n",
+        );
+        assert_eq!(response.status, StatusCode::BadRequest);
+        assert!(response.upgrade.is_none());
+    }
+
+    #[test]
+    fn reassembles_fragments_and_answers_pings() {
+        let mut input = client_frame(false, 0x1, b"Hel");
+        input.extend(client_frame(true, 0x9, b"are you there"));
+        input.extend(client_frame(false, 0x0, b"lo, "));
+        input.extend(client_frame(true, 0x0, "wörld".as_bytes()));
+        input.extend(client_frame(true, 0x2, &[0; 300]));
+        input.extend(client_frame(true, 0x8, b"\x03\xe8bye"));
+        let (mut socket, output) = socket(input);
+
+        assert_eq!(
+            socket.recv().unwrap(),
+            Message::Ping(b"are you there".to_vec())
+        );
+        assert_eq!(
+            socket.recv().unwrap(),
+            Message::Text(String::from("Hello, wörld"))
+        );
+        assert_eq!(socket.recv().unwrap(), Message::Binary(vec![0; 300]));
+        assert_eq!(
+            socket.recv().unwrap(),
+            Message::Close {
+                code: CloseCode::NORMAL,
+                reason: String::from("bye")
+            }
+        );
+        assert!(matches!(socket.recv(), Err(WebSocketError::Closed)));
+        assert!(matches!(
+            socket.send_text("late"),
+            Err(WebSocketError::Closed)
+        ));
+
+        let output = output.lock().unwrap();
+        assert_eq!(
+            server_frames(&output),
+            [
+                (0xA, b"are you there".to_vec()),
+                (0x8, b"\x03\xe8".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn closes_on_protocol_errors() {
+        let cases: [(Vec<u8>, u16); 5] = [
+            // Unmasked.
+            (vec![0x81, 0x02, b'h', b'i'], 1002),
+            (client_frame(false, 0x9, b""), 1002),
+            (client_frame(true, 0x0, b"orphan"), 1002),
+            (client_frame(true, 0x1, b"\xff\xfe"), 1007),
+            (client_frame(true, 0x8, b"\x03\xed"), 1002),
+        ];
+        for (input, code) in cases {
+            let (mut socket, output) = socket(input);
+            assert!(socket.recv().is_err());
+            let output = output.lock().unwrap();
+            assert_eq!(
+                server_frames(&output),
+                [(0x8, code.to_be_bytes().to_vec())],
+                "expected close {code}"
+            );
+        }
+
+        let (mut socket, output) = socket(client_frame(true, 0x2, &[0; 64]));
+        socket.set_max_message_size(32);
+        assert!(matches!(socket.recv(), Err(WebSocketError::MessageTooBig)));
+        assert_eq!(
+            server_frames(&output.lock().unwrap()),
+            [(0x8, 1009u16.to_be_bytes().to_vec())]
+        );
+    }
+}